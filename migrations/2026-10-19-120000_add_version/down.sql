@@ -0,0 +1 @@
+ALTER TABLE key_values DROP COLUMN version;
@@ -0,0 +1,2 @@
+-- Bumped on every write to a key, transactions use it for check_version
+ALTER TABLE key_values ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
@@ -0,0 +1,12 @@
+-- Every committed change in order, listeners replay it to catch up on what they missed.
+-- AUTOINCREMENT so sequence numbers are never reused after old changes are pruned.
+CREATE TABLE change_log (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    key TEXT NOT NULL,
+    -- NULL for deletes
+    value TEXT,
+    -- Unix time in seconds
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX change_log_created_at ON change_log (created_at);
@@ -0,0 +1,26 @@
+-- HTTP endpoints told about changes to keys, see `webhooks`
+CREATE TABLE webhooks (
+    id TEXT PRIMARY KEY NOT NULL,
+    url TEXT NOT NULL,
+    -- Only keys starting with this
+    prefix TEXT NOT NULL DEFAULT '',
+    -- Only keys matching this glob, NULL for every key under the prefix
+    pattern TEXT,
+    -- Deliveries are signed with HMAC-SHA256 when set
+    secret TEXT,
+    -- Unix time in seconds
+    created_at BIGINT NOT NULL
+);
+
+-- Deliveries that failed every attempt, kept until they are retried or deleted
+CREATE TABLE webhook_dead_letters (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    webhook_id TEXT NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    error TEXT NOT NULL,
+    -- Unix time in seconds
+    failed_at BIGINT NOT NULL
+);
+
+CREATE INDEX webhook_dead_letters_webhook_id ON webhook_dead_letters (webhook_id);
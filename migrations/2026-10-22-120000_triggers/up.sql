@@ -0,0 +1,14 @@
+-- Scripts run around writes, see `triggers`. They run in id order.
+CREATE TABLE triggers (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- 'before' or 'after'
+    phase TEXT NOT NULL,
+    -- 'set' or 'delete'
+    event TEXT NOT NULL,
+    -- Only keys starting with this
+    prefix TEXT NOT NULL DEFAULT '',
+    -- Rhai source
+    script TEXT NOT NULL,
+    -- Unix time in seconds
+    created_at BIGINT NOT NULL
+);
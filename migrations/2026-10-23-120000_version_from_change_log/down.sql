@@ -0,0 +1,2 @@
+-- Nothing to undo, the numbering only skipped ahead
+SELECT 1;
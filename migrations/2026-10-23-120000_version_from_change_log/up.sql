@@ -0,0 +1,9 @@
+-- Versions are now the sequence number of the change that last wrote the key, so they never
+-- repeat. Number changes from above every version handed out before.
+INSERT INTO sqlite_sequence (name, seq)
+SELECT 'change_log', 0
+WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = 'change_log');
+
+UPDATE sqlite_sequence
+SET seq = MAX(seq, (SELECT IFNULL(MAX(version), 0) FROM key_values))
+WHERE name = 'change_log';
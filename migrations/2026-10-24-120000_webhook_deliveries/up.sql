@@ -0,0 +1,10 @@
+-- Deliveries waiting to be sent, each webhook sends its own in id order, see `webhooks`
+CREATE TABLE webhook_deliveries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    webhook_id TEXT NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    payload TEXT NOT NULL,
+    -- Tries that failed so far
+    attempts INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id, id);
@@ -0,0 +1,161 @@
+//! Key commands sent over the WebSocket, so a client holding a `/ws` connection
+//! doesn't need separate HTTP requests to read and write keys.
+//!
+//! A command is a JSON object with an `op` and an optional `id`, the response
+//! carries the same `id` so the client can match them up when several are in
+//! flight. Commands go through the same limits and storage calls as the REST
+//! handlers and fail with the status those would have answered. Auth already
+//! happened when the socket was opened under `/v0/{secret}`.
+
+use crate::controllers::key_controller::db_error_status;
+use crate::limits::Limits;
+use crate::storage::{KeyExists, Storage, StorageError, WriteOp, WriteOutcome};
+use actix_web::http::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Command {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Delete {
+        key: String,
+    },
+    /// Keys starting with `prefix`, every key without one
+    List {
+        #[serde(default)]
+        prefix: String,
+    },
+    /// Writes that all commit or none do, see `Storage::batch`
+    Batch {
+        ops: Vec<WriteOp>,
+    },
+}
+
+/// Why a command failed, with the status the REST API would have answered
+#[derive(Debug)]
+pub struct CommandError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl CommandError {
+    fn new(status: StatusCode, message: impl Into<String>) -> CommandError {
+        CommandError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn storage(error: StorageError) -> CommandError {
+        if let Some(exists) = error.downcast_ref::<KeyExists>() {
+            return CommandError::new(StatusCode::CONFLICT, exists.to_string());
+        }
+        let status = db_error_status(&error);
+        if status.is_server_error() {
+            log::error!("Storage error: {error}");
+        }
+        CommandError::new(status, error.to_string())
+    }
+
+    pub fn internal(message: impl Into<String>) -> CommandError {
+        CommandError::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+/// Reads a command and its `id`, the id is null if there is none or the message is not JSON
+pub fn parse(text: &str) -> (Value, Result<Command, CommandError>) {
+    let message: Value = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(error) => {
+            let error =
+                CommandError::new(StatusCode::BAD_REQUEST, format!("Invalid JSON: {error}"));
+            return (Value::Null, Err(error));
+        }
+    };
+    let id = message.get("id").cloned().unwrap_or(Value::Null);
+    let command = serde_json::from_value(message).map_err(|error| {
+        CommandError::new(StatusCode::BAD_REQUEST, format!("Invalid command: {error}"))
+    });
+    (id, command)
+}
+
+/// The response to send back for the command with `id`
+pub fn response(id: Value, result: Result<Value, CommandError>) -> String {
+    match result {
+        Ok(result) => json!({ "id": id, "ok": true, "result": result }),
+        Err(error) => json!({
+            "id": id,
+            "ok": false,
+            "status": error.status.as_u16(),
+            "error": error.message,
+        }),
+    }
+    .to_string()
+}
+
+impl Command {
+    /// Checks keys and values against the limits like the REST handlers do
+    pub fn validate(&self, limits: &Limits) -> Result<(), CommandError> {
+        let checked = match self {
+            Command::Get { key } | Command::Delete { key } => limits.check_key(key),
+            Command::Set { key, value } => limits.check(key, value),
+            Command::List { prefix } => limits.check_key(prefix),
+            Command::Batch { ops } => {
+                if ops.is_empty() {
+                    return Err(CommandError::new(
+                        StatusCode::BAD_REQUEST,
+                        "A batch needs at least one operation",
+                    ));
+                }
+                ops.iter().try_for_each(|op| match op {
+                    WriteOp::Set { key, value }
+                    | WriteOp::Insert { key, value }
+                    | WriteOp::Create { key, value } => limits.check(key, value),
+                    WriteOp::Delete { key } => limits.check_key(key),
+                })
+            }
+        };
+        checked.map_err(|error| CommandError::new(error.status(), error.to_string()))
+    }
+
+    /// Runs the command, blocks like every storage call
+    pub fn run(self, storage: &dyn Storage) -> Result<Value, CommandError> {
+        match self {
+            Command::Get { key } => {
+                let entry = storage.get_versioned(&key).map_err(CommandError::storage)?;
+                Ok(match entry {
+                    Some(entry) => json!({ "value": entry.value, "version": entry.version }),
+                    None => json!({ "value": null, "version": 0 }),
+                })
+            }
+            Command::Set { key, value } => {
+                let outcomes = storage
+                    .batch(vec![WriteOp::Set { key, value }])
+                    .map_err(CommandError::storage)?;
+                Ok(json!(outcomes.first()))
+            }
+            Command::Delete { key } => match storage.delete(&key).map_err(CommandError::storage)? {
+                true => Ok(json!(WriteOutcome::Deleted)),
+                false => Err(CommandError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("Key {key} does not exist"),
+                )),
+            },
+            Command::List { prefix } => {
+                let keys = storage.scan(&prefix).map_err(CommandError::storage)?;
+                Ok(json!(keys))
+            }
+            Command::Batch { ops } => {
+                let outcomes = storage.batch(ops).map_err(CommandError::storage)?;
+                Ok(json!(outcomes))
+            }
+        }
+    }
+}
@@ -1,2 +1,5 @@
+pub mod commands;
+pub mod outbox;
+pub mod peer;
 pub mod session;
 pub mod ws_actor;
@@ -0,0 +1,153 @@
+//! Bounded queue of messages waiting to be sent to a WebSocket session.
+//!
+//! A session only runs when its socket has room to write, so a slow client
+//! used to pile messages up in its mailbox without limit. Now the chat server
+//! puts them in the session's outbox and only wakes the session when the outbox
+//! goes from empty to not empty. Once the outbox holds `queue_size` messages the
+//! `slow_consumer` policy decides what gives.
+
+use crate::config::{SlowConsumer, WebSocketConfig};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[cfg(test)]
+mod tests;
+
+/// Counters for `GET /admin/{secret}/websocket`
+#[derive(Debug, Default)]
+pub struct WsMetrics {
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WsStats {
+    /// Messages dropped from full queues
+    pub dropped: u64,
+    /// Changes replaced by a newer change to the same key
+    pub coalesced: u64,
+    /// Sessions closed for falling behind
+    pub disconnected: u64,
+}
+
+impl WsMetrics {
+    pub fn stats(&self) -> WsStats {
+        WsStats {
+            dropped: self.dropped.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A message waiting to be sent
+#[derive(Debug)]
+pub enum Outgoing {
+    Text(String),
+    /// A change to `key`, a newer change to the same key can replace it
+    Change {
+        key: String,
+        text: String,
+    },
+    /// Messages that are sent together and count as one, like a snapshot
+    Batch(Vec<String>),
+}
+
+/// What `push` did
+#[derive(Debug, PartialEq, Eq)]
+pub enum Pushed {
+    /// The outbox was empty, the session has to be woken up
+    Wake,
+    /// Queued behind messages the session already knows about
+    Queued,
+    /// The outbox is full and the policy is to disconnect, the session was told to close
+    Closed,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<Outgoing>,
+    /// Set when the session has to close, with the reason
+    closed: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Outbox {
+    state: Mutex<State>,
+    config: WebSocketConfig,
+    metrics: Arc<WsMetrics>,
+}
+
+impl Outbox {
+    pub fn new(config: WebSocketConfig, metrics: Arc<WsMetrics>) -> Outbox {
+        Outbox {
+            state: Mutex::new(State::default()),
+            config,
+            metrics,
+        }
+    }
+
+    pub fn push(&self, message: Outgoing) -> Pushed {
+        let mut state = self.state.lock().unwrap();
+        if state.closed.is_some() {
+            return Pushed::Closed;
+        }
+        let was_empty = state.queue.is_empty();
+
+        if state.queue.len() >= self.config.queue_size {
+            match self.config.slow_consumer {
+                SlowConsumer::DropOldest => {
+                    state.queue.pop_front();
+                    self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                SlowConsumer::Coalesce => {
+                    let waiting = match &message {
+                        Outgoing::Change { key, .. } => state.queue.iter().position(|queued| {
+                            matches!(queued, Outgoing::Change { key: queued, .. } if queued == key)
+                        }),
+                        _ => None,
+                    };
+                    match waiting {
+                        // Taken out and queued again at the back, so changes stay in order
+                        Some(index) => {
+                            state.queue.remove(index);
+                            self.metrics.coalesced.fetch_add(1, Ordering::Relaxed);
+                        }
+                        None => {
+                            state.queue.pop_front();
+                            self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                SlowConsumer::Disconnect => {
+                    self.metrics
+                        .dropped
+                        .fetch_add(state.queue.len() as u64 + 1, Ordering::Relaxed);
+                    self.metrics.disconnected.fetch_add(1, Ordering::Relaxed);
+                    state.queue.clear();
+                    state.closed = Some(format!(
+                        "more than {} messages waiting, reading too slowly",
+                        self.config.queue_size
+                    ));
+                    return Pushed::Closed;
+                }
+            }
+        }
+
+        state.queue.push_back(message);
+        match was_empty {
+            true => Pushed::Wake,
+            false => Pushed::Queued,
+        }
+    }
+
+    /// Takes everything waiting, and the reason to close if the session fell too far behind
+    pub fn drain(&self) -> (VecDeque<Outgoing>, Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        let queue = std::mem::take(&mut state.queue);
+        (queue, state.closed.clone())
+    }
+}
@@ -0,0 +1,110 @@
+//! What each slow consumer policy does to a full outbox
+
+use super::*;
+
+fn outbox(slow_consumer: SlowConsumer) -> (Outbox, Arc<WsMetrics>) {
+    let metrics = Arc::new(WsMetrics::default());
+    let config = WebSocketConfig {
+        queue_size: 3,
+        slow_consumer,
+    };
+    (Outbox::new(config, metrics.clone()), metrics)
+}
+
+fn text(text: &str) -> Outgoing {
+    Outgoing::Text(text.to_string())
+}
+
+fn change(key: &str, text: &str) -> Outgoing {
+    Outgoing::Change {
+        key: key.to_string(),
+        text: text.to_string(),
+    }
+}
+
+/// The text of everything waiting, a batch as its messages joined with `+`
+fn drain(outbox: &Outbox) -> (Vec<String>, Option<String>) {
+    let (queue, closed) = outbox.drain();
+    let texts = queue
+        .into_iter()
+        .map(|message| match message {
+            Outgoing::Text(text) | Outgoing::Change { text, .. } => text,
+            Outgoing::Batch(texts) => texts.join("+"),
+        })
+        .collect();
+    (texts, closed)
+}
+
+fn counters(metrics: &WsMetrics) -> (u64, u64, u64) {
+    let stats = metrics.stats();
+    (stats.dropped, stats.coalesced, stats.disconnected)
+}
+
+#[test]
+fn wakes_only_when_it_was_empty() {
+    let (outbox, metrics) = outbox(SlowConsumer::DropOldest);
+    assert_eq!(outbox.push(text("1")), Pushed::Wake);
+    assert_eq!(
+        outbox.push(Outgoing::Batch(vec!["2".into(), "3".into()])),
+        Pushed::Queued
+    );
+    assert_eq!(outbox.push(change("a", "4")), Pushed::Queued);
+    assert_eq!(
+        drain(&outbox),
+        (vec!["1".into(), "2+3".into(), "4".into()], None)
+    );
+
+    // Drained, so the next message has to wake the session again
+    assert_eq!(outbox.push(text("5")), Pushed::Wake);
+    assert_eq!(outbox.push(text("6")), Pushed::Queued);
+    assert_eq!(counters(&metrics), (0, 0, 0));
+}
+
+#[test]
+fn drop_oldest() {
+    let (outbox, metrics) = outbox(SlowConsumer::DropOldest);
+    for message in ["1", "2", "3", "4", "5"] {
+        outbox.push(change("a", message));
+    }
+    assert_eq!(drain(&outbox).0, vec!["3", "4", "5"]);
+    assert_eq!(counters(&metrics), (2, 0, 0));
+}
+
+#[test]
+fn coalesce_replaces_the_waiting_change_to_the_same_key() {
+    let (outbox, metrics) = outbox(SlowConsumer::Coalesce);
+    outbox.push(change("a", "a1"));
+    outbox.push(change("b", "b1"));
+    outbox.push(text("t"));
+    // a1 is taken out and a2 goes to the back, behind b1
+    assert_eq!(outbox.push(change("a", "a2")), Pushed::Queued);
+    assert_eq!(drain(&outbox).0, vec!["b1", "t", "a2"]);
+    assert_eq!(counters(&metrics), (0, 1, 0));
+
+    // Without a change to the same key waiting the oldest message goes
+    outbox.push(change("a", "a1"));
+    outbox.push(change("b", "b1"));
+    outbox.push(change("c", "c1"));
+    outbox.push(change("d", "d1"));
+    outbox.push(text("t"));
+    assert_eq!(drain(&outbox).0, vec!["c1", "d1", "t"]);
+    assert_eq!(counters(&metrics), (2, 1, 0));
+}
+
+#[test]
+fn disconnect_clears_the_queue_and_closes() {
+    let (outbox, metrics) = outbox(SlowConsumer::Disconnect);
+    for message in ["1", "2", "3"] {
+        assert_ne!(outbox.push(text(message)), Pushed::Closed);
+    }
+    assert_eq!(outbox.push(text("4")), Pushed::Closed);
+    assert_eq!(counters(&metrics), (4, 0, 1));
+
+    // Everything after is refused and the session is told why
+    assert_eq!(outbox.push(text("5")), Pushed::Closed);
+    let (queue, closed) = drain(&outbox);
+    assert!(queue.is_empty());
+    assert!(closed.unwrap().contains("more than 3 messages waiting"));
+    assert_eq!(outbox.push(text("6")), Pushed::Closed);
+    assert_eq!(counters(&metrics), (4, 0, 1));
+}
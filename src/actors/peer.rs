@@ -0,0 +1,112 @@
+//! Streams this instance's committed changes to a peer instance.
+//!
+//! A peer connects to `/cluster/{secret}/changes`. The first message is a hello
+//! with our node id, so an instance that lists itself as a peer can tell. After
+//! it every change committed here is sent as a JSON event, one per message.
+//! Changes that came from other peers are never sent on, so peers can connect
+//! both ways without changes going round in circles.
+
+use crate::cluster::Hello;
+use crate::storage::Changes;
+use actix::prelude::*;
+use actix_web_actors::ws::{self, CloseCode, CloseReason};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use uuid::Uuid;
+
+/// How often heartbeat pings are sent
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long before lack of peer response causes a timeout
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct PeerSession {
+    /// Our node id, sent in the hello
+    pub node: Uuid,
+
+    /// Last ping or pong from the peer
+    pub hb: Instant,
+
+    /// Committed changes from storage, turned into a stream once the session starts
+    pub changes: Option<broadcast::Receiver<Changes>>,
+}
+
+impl PeerSession {
+    fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > PEER_TIMEOUT {
+                log::warn!("cluster peer stopped answering pings, disconnecting");
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+}
+
+impl Actor for PeerSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.hb(ctx);
+        match serde_json::to_string(&Hello { node: self.node }) {
+            Ok(hello) => ctx.text(hello),
+            Err(_) => ctx.stop(),
+        }
+        if let Some(changes) = self.changes.take() {
+            ctx.add_stream(BroadcastStream::new(changes));
+        }
+    }
+}
+
+/// Sends every committed change to the peer
+impl StreamHandler<Result<Changes, BroadcastStreamRecvError>> for PeerSession {
+    fn handle(&mut self, msg: Result<Changes, BroadcastStreamRecvError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(changes) => {
+                for event in changes.0 {
+                    match serde_json::to_string(&event) {
+                        Ok(text) => ctx.text(text),
+                        Err(error) => {
+                            log::error!("could not send a change to a cluster peer: {error}")
+                        }
+                    }
+                }
+            }
+            // The peer reconnects and its listeners carry on from the changes after
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                log::warn!("cluster peer fell behind and missed {missed} change groups");
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Again,
+                    description: Some(format!("missed {missed} change groups")),
+                }));
+                ctx.stop();
+            }
+        }
+    }
+
+    // Storage went away, nothing more to send
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.stop();
+    }
+}
+
+/// Peers only answer pings and close, anything else they send is ignored
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for PeerSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.hb = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => self.hb = Instant::now(),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => {}
+            Err(_) => ctx.stop(),
+        }
+    }
+}
@@ -1,23 +1,30 @@
-use crate::actors::ws_actor;
+use crate::actors::outbox::{Outbox, Outgoing};
+use crate::actors::{commands, ws_actor};
+use crate::channels::{Access, ChannelsConfig, Scope};
+use crate::controllers::key_controller::parse_duration;
+use crate::filter::Filter;
+use crate::limits::Limits;
+use crate::storage::Storage;
 use actix::prelude::*;
-use actix_web_actors::ws;
+use actix_web::web;
+use actix_web_actors::ws::{self, CloseCode, CloseReason};
+use std::collections::VecDeque;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+#[cfg(test)]
+mod tests;
+
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
-// Session is disconnected
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct Disconnect {
-    pub id: Uuid,
-}
+/// Largest presence metadata a session can set, in bytes
+const MAX_META_SIZE: usize = 1024;
 
-#[derive(Debug)]
 pub struct WsChatSession {
     /// unique session id
     pub id: Uuid,
@@ -34,6 +41,25 @@ pub struct WsChatSession {
 
     /// Chat server
     pub addr: Addr<ws_actor::ClientWebSocketConnection>,
+
+    /// Storage for key commands
+    pub storage: Arc<dyn Storage>,
+
+    pub limits: Limits,
+
+    /// Key commands waiting for the one in flight, they run in the order they were sent
+    pub commands: VecDeque<(serde_json::Value, commands::Command)>,
+
+    /// A key command is running
+    pub running: bool,
+
+    /// Messages from the chat server waiting to be sent
+    pub outbox: Arc<Outbox>,
+
+    /// Connected with the secret, or a channel token that only allows channel commands
+    pub access: Access,
+
+    pub channels: Arc<ChannelsConfig>,
 }
 
 impl WsChatSession {
@@ -47,8 +73,11 @@ impl WsChatSession {
                 // heartbeat timed out
                 println!("Websocket Client heartbeat failed, disconnecting!");
 
-                // notify chat server
-                act.addr.do_send(ws_actor::Disconnect { id: act.id });
+                // notify chat server, the session's room sees it leave
+                act.addr.do_send(ws_actor::Disconnect {
+                    id: act.id,
+                    reason: ws_actor::LeaveReason::Timeout,
+                });
 
                 // stop actor
                 ctx.stop();
@@ -60,6 +89,50 @@ impl WsChatSession {
             ctx.ping(b"");
         });
     }
+
+    /// Queues a JSON key command, invalid ones are answered right away
+    fn command(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let (id, command) = commands::parse(text);
+        let command = match command.and_then(|command| {
+            command.validate(&self.limits)?;
+            Ok(command)
+        }) {
+            Ok(command) => command,
+            Err(error) => {
+                ctx.text(commands::response(id, Err(error)));
+                return;
+            }
+        };
+
+        self.commands.push_back((id, command));
+        self.run_next_command(ctx);
+    }
+
+    /// Runs the next queued key command off the session's thread and answers with its result.
+    /// One at a time, so a read sees the writes sent before it.
+    fn run_next_command(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.running {
+            return;
+        }
+        let (id, command) = match self.commands.pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+        self.running = true;
+
+        let storage = self.storage.clone();
+        web::block(move || command.run(storage.as_ref()))
+            .into_actor(self)
+            .map(move |result, act, ctx| {
+                let result = result.unwrap_or_else(|error| {
+                    Err(commands::CommandError::internal(error.to_string()))
+                });
+                ctx.text(commands::response(id, result));
+                act.running = false;
+                act.run_next_command(ctx);
+            })
+            .spawn(ctx);
+    }
 }
 
 impl Actor for WsChatSession {
@@ -80,6 +153,11 @@ impl Actor for WsChatSession {
         self.addr
             .send(ws_actor::Connect {
                 addr: addr.recipient(),
+                outbox: self.outbox.clone(),
+                room: match self.access {
+                    Access::Secret => Some(self.room.clone()),
+                    Access::Token(_) => None,
+                },
             })
             .into_actor(self)
             .then(|res, act, ctx| {
@@ -95,17 +173,34 @@ impl Actor for WsChatSession {
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         // notify chat server
-        self.addr.do_send(ws_actor::Disconnect { id: self.id });
+        self.addr.do_send(ws_actor::Disconnect {
+            id: self.id,
+            reason: ws_actor::LeaveReason::Closed,
+        });
         Running::Stop
     }
 }
 
-/// Handle messages from chat server, we simply send it to peer websocket
-impl Handler<ws_actor::Message> for WsChatSession {
+/// Handle messages from chat server, we simply send what waits in the outbox to peer websocket
+impl Handler<ws_actor::Flush> for WsChatSession {
     type Result = ();
 
-    fn handle(&mut self, msg: ws_actor::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+    fn handle(&mut self, _: ws_actor::Flush, ctx: &mut Self::Context) {
+        let (messages, closed) = self.outbox.drain();
+        for message in messages {
+            match message {
+                Outgoing::Text(text) | Outgoing::Change { text, .. } => ctx.text(text),
+                Outgoing::Batch(texts) => texts.into_iter().for_each(|text| ctx.text(text)),
+            }
+        }
+        // fell too far behind, the chat server already let go of the session
+        if let Some(reason) = closed {
+            ctx.close(Some(CloseReason {
+                code: CloseCode::Policy,
+                description: Some(reason),
+            }));
+            ctx.stop();
+        }
     }
 }
 
@@ -131,8 +226,18 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             }
             ws::Message::Text(text) => {
                 let m = text.trim();
+                if let Access::Token(_) = self.access {
+                    let command = m.split(' ').next().unwrap_or_default();
+                    if !["/sub", "/unsub", "/pub"].contains(&command) {
+                        ctx.text("!!! a channel token can only use /sub, /unsub and /pub");
+                        return;
+                    }
+                }
+                // JSON objects are key commands
+                if m.starts_with('{') {
+                    self.command(m, ctx);
                 // we check for /sss type of messages
-                if m.starts_with('/') {
+                } else if m.starts_with('/') {
                     let v: Vec<&str> = m.splitn(2, ' ').collect();
                     match v[0] {
                         "/list" => {
@@ -174,23 +279,120 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                         "/name" => {
                             if v.len() == 2 {
                                 self.name = Some(v[1].to_owned());
+                                self.addr.do_send(ws_actor::SetPresence {
+                                    id: self.id,
+                                    name: self.name.clone(),
+                                    meta: None,
+                                });
                             } else {
                                 ctx.text("!!! name is required");
                             }
                         }
-                        "/listen" => {
-                            if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                self.addr.do_send(ws_actor::Listen {
+                        // `/meta <json>` sets what the room sees about us besides the name
+                        "/meta" => match v.get(1).map(|meta| serde_json::from_str(meta)) {
+                            Some(Ok(meta)) if v[1].len() <= MAX_META_SIZE => {
+                                self.addr.do_send(ws_actor::SetPresence {
                                     id: self.id,
-                                    key_prefix: self.room.clone(),
+                                    name: None,
+                                    meta: Some(meta),
                                 });
-
-                                ctx.text(format!("listening to the prefix {}", v[1].to_string()));
-                            } else {
-                                ctx.text("!!! room name is required");
+                            }
+                            Some(Ok(_)) => ctx
+                                .text(format!("!!! metadata can be at most {MAX_META_SIZE} bytes")),
+                            _ => ctx.text("!!! usage: /meta <json>"),
+                        },
+                        // `/who [room]` lists who is in a room, ours without one
+                        "/who" => self
+                            .addr
+                            .send(ws_actor::Who {
+                                id: Some(self.id),
+                                room: v.get(1).map(|room| room.to_string()),
+                            })
+                            .into_actor(self)
+                            .then(|res, _, ctx| {
+                                match res {
+                                    Ok(Some((room, members))) => ctx.text(
+                                        serde_json::json!({
+                                            "event": "presence",
+                                            "room": room,
+                                            "members": members,
+                                        })
+                                        .to_string(),
+                                    ),
+                                    Ok(None) => ctx.text("!!! not in a room"),
+                                    Err(error) => {
+                                        log::error!("Could not list who is in the room: {error}");
+                                        ctx.text("!!! could not list who is in the room");
+                                    }
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx),
+                        // `/sub <channel>` gets the messages published to the channel from now on
+                        "/sub" | "/unsub" => match v.get(1) {
+                            Some(channel) => {
+                                match self.channels.check(&self.access, channel, Scope::Subscribe) {
+                                    Ok(()) if v[0] == "/sub" => {
+                                        self.addr.do_send(ws_actor::Subscribe {
+                                            id: self.id,
+                                            channel: channel.to_string(),
+                                        });
+                                        ctx.text(format!("subscribed to {channel}"));
+                                    }
+                                    Ok(()) => {
+                                        self.addr.do_send(ws_actor::Unsubscribe {
+                                            id: self.id,
+                                            channel: channel.to_string(),
+                                        });
+                                        ctx.text(format!("unsubscribed from {channel}"));
+                                    }
+                                    Err(error) => ctx.text(format!("!!! {error}")),
+                                }
+                            }
+                            None => ctx.text("!!! channel is required"),
+                        },
+                        // `/pub <channel> <message>` sends the message to the channel's other subscribers
+                        "/pub" => {
+                            let args: Vec<&str> = match v.get(1) {
+                                Some(args) => args.splitn(2, ' ').collect(),
+                                None => Vec::new(),
+                            };
+                            match (args.first(), args.get(1)) {
+                                (Some(channel), Some(message)) => {
+                                    let checked = self
+                                        .channels
+                                        .check(&self.access, channel, Scope::Publish)
+                                        .and_then(|_| {
+                                            self.channels.check_message(channel, message)
+                                        });
+                                    match checked {
+                                        Ok(()) => self.addr.do_send(ws_actor::Publish {
+                                            from: Some(self.id),
+                                            channel: channel.to_string(),
+                                            message: message.to_string(),
+                                        }),
+                                        Err(error) => ctx.text(format!("!!! {error}")),
+                                    }
+                                }
+                                _ => ctx.text("!!! usage: /pub <channel> <message>"),
                             }
                         }
+                        // `/listen <prefix> [options]`, the same options SSE `/watch` takes
+                        // as query parameters, see `parse_listen`
+                        "/listen" => match parse_listen(v.get(1).copied().unwrap_or_default()) {
+                            Ok(listen) => {
+                                self.room = listen.prefix.clone();
+                                ctx.text(format!("listening to the prefix {}", listen.prefix));
+                                self.addr.do_send(ws_actor::Listen {
+                                    id: self.id,
+                                    key_prefix: listen.prefix,
+                                    start: listen.start,
+                                    coalesce: listen.coalesce,
+                                    filter: listen.filter,
+                                });
+                            }
+                            Err(error) => ctx.text(format!("!!! {error}")),
+                        },
                         _ => ctx.text(format!("!!! unknown command: {m:?}")),
                     }
                 } else {
@@ -221,3 +423,60 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
         }
     }
 }
+
+const LISTEN_USAGE: &str =
+    "usage: /listen <prefix> [snapshot] [after=<seq>] [coalesce=<window>] [filter=<filter>]";
+
+/// What `/listen` asked for
+struct ListenArgs {
+    prefix: String,
+    start: ws_actor::Start,
+    coalesce: Option<Duration>,
+    filter: Option<Filter>,
+}
+
+/// Parses the arguments of `/listen`. The prefix is the first word, the filter takes the rest
+/// of the line so it has to come last.
+fn parse_listen(args: &str) -> Result<ListenArgs, String> {
+    let (prefix, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let mut rest = rest.trim_start();
+    if prefix.is_empty() {
+        return Err(LISTEN_USAGE.to_string());
+    }
+    let mut listen = ListenArgs {
+        prefix: prefix.to_string(),
+        start: ws_actor::Start::Live,
+        coalesce: None,
+        filter: None,
+    };
+    while !rest.is_empty() {
+        if let Some(filter) = rest.strip_prefix("filter=") {
+            listen.filter = Some(Filter::parse(filter).map_err(|error| error.to_string())?);
+            break;
+        }
+        let (option, next) = rest.split_once(' ').unwrap_or((rest, ""));
+        rest = next.trim_start();
+        let start = match (option, option.split_once('=')) {
+            ("snapshot" | "snapshot=true", _) => Some(ws_actor::Start::Snapshot),
+            (_, Some(("after", seq))) => match seq.parse() {
+                Ok(seq) => Some(ws_actor::Start::After(seq)),
+                Err(_) => return Err("after must be a sequence number".to_string()),
+            },
+            (_, Some(("coalesce", window))) => match parse_duration(window) {
+                Some(window) if !window.is_zero() && window <= ws_actor::MAX_COALESCE => {
+                    listen.coalesce = Some(window);
+                    None
+                }
+                _ => return Err("coalesce must be a duration like 100ms or 1s, at most 60s".into()),
+            },
+            _ => return Err(format!("unknown option {option:?}, {LISTEN_USAGE}")),
+        };
+        if let Some(start) = start {
+            if listen.start != ws_actor::Start::Live {
+                return Err("snapshot and after can't be used together".to_string());
+            }
+            listen.start = start;
+        }
+    }
+    Ok(listen)
+}
@@ -0,0 +1,39 @@
+//! Parsing the arguments of `/listen`
+
+use super::*;
+use serde_json::json;
+
+#[test]
+fn listen_options() {
+    let listen = parse_listen("users/ snapshot coalesce=100ms").unwrap();
+    assert_eq!(listen.prefix, "users/");
+    assert_eq!(listen.start, ws_actor::Start::Snapshot);
+    assert_eq!(listen.coalesce, Some(Duration::from_millis(100)));
+    assert!(listen.filter.is_none());
+
+    let listen = parse_listen("users/ after=12").unwrap();
+    assert_eq!(listen.start, ws_actor::Start::After(12));
+    assert_eq!(parse_listen("users/").unwrap().start, ws_actor::Start::Live);
+
+    assert!(parse_listen("").is_err());
+    assert!(parse_listen("users/ snapshot after=1").is_err());
+    assert!(parse_listen("users/ after=soon").is_err());
+    assert!(parse_listen("users/ coalesce=0ms").is_err());
+    assert!(parse_listen("users/ coalesce=2m").is_err());
+    assert!(parse_listen("users/ sometimes").is_err());
+}
+
+#[test]
+fn listen_filter_takes_the_rest_of_the_line() {
+    let listen = parse_listen(r#"users/ after=3 filter=status == "open" && n > 2"#).unwrap();
+    assert_eq!(listen.start, ws_actor::Start::After(3));
+    let filter = listen.filter.unwrap();
+    assert!(filter.matches_json(&json!({"status": "open", "n": 3})));
+    assert!(!filter.matches_json(&json!({"status": "open", "n": 1})));
+
+    // Words after the filter are part of it, not options
+    let error = parse_listen("users/ filter=a snapshot").err().unwrap();
+    assert!(error.contains("Invalid filter"), "{error}");
+    let error = parse_listen(r#"users/ filter=a == "open"#).err().unwrap();
+    assert!(error.contains("closing quote"), "{error}");
+}
@@ -2,29 +2,40 @@
 //! And manages available rooms. Peers send messages to other peers in same
 //! room through `ClientWebSocketConnection`.
 
+use crate::actors::outbox::{Outbox, Outgoing, Pushed};
+use crate::filter::{self, Filter};
+use crate::storage::change_log::unix_time;
+use crate::storage::{Change, Changes, ChangesPruned, Entry, Event, Storage, StorageError};
 use actix::prelude::*;
-use rand::{self, rngs::ThreadRng, Rng};
+use actix_web::web;
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::{
     collections::{HashMap, HashSet},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use uuid::Uuid;
 
-/// Chat server sends this messages to session
+/// Chat server sends this to a session when its outbox has messages to send
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
-
-/// Message for chat server communications
+pub struct Flush;
 
 /// New chat session is created
 #[derive(Message)]
 #[rtype(result = "Result<Uuid, std::io::Error>")]
 pub struct Connect {
-    pub addr: Recipient<Message>,
+    pub addr: Recipient<Flush>,
+    /// Where messages for the session wait until it sends them
+    pub outbox: Arc<Outbox>,
+    /// Chat room to join, sessions that only use channels aren't in one
+    pub room: Option<String>,
 }
 
 /// Session is disconnected
@@ -32,6 +43,76 @@ pub struct Connect {
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: Uuid,
+    pub reason: LeaveReason,
+}
+
+/// Why a member left a room, sent with `leave` events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LeaveReason {
+    /// Joined another room
+    Left,
+    /// Closed the connection
+    Closed,
+    /// Stopped answering pings
+    Timeout,
+    /// Was disconnected for reading too slowly
+    SlowConsumer,
+}
+
+/// Someone in a room, as `presence`, `join`, `leave` and `update` events show them
+#[derive(Debug, Clone, Serialize)]
+pub struct Member {
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub meta: Value,
+    /// Unix time they joined the room
+    pub since: u64,
+}
+
+/// Set the name or metadata other members see, `None` keeps what was there
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetPresence {
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub meta: Option<Value>,
+}
+
+/// Start getting the messages published to a channel
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe {
+    pub id: Uuid,
+    pub channel: String,
+}
+
+/// Stop getting a channel's messages
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unsubscribe {
+    pub id: Uuid,
+    pub channel: String,
+}
+
+/// Send a message to a channel's subscribers, except the session that published it.
+/// Answers how many sessions it was sent to.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct Publish {
+    pub from: Option<Uuid>,
+    pub channel: String,
+    pub message: String,
+}
+
+/// Who is in a room, the room of session `id` if there is no `room`
+pub struct Who {
+    pub id: Option<Uuid>,
+    pub room: Option<String>,
+}
+
+impl actix::Message for Who {
+    type Result = Option<(String, Vec<Member>)>;
 }
 
 /// Send message to specific room
@@ -72,6 +153,14 @@ pub struct Listen {
 
     ///Prefix
     pub key_prefix: String,
+
+    pub start: Start,
+
+    /// Send at most one change per key per window, the latest
+    pub coalesce: Option<Duration>,
+
+    /// Only send changes whose new value matches
+    pub filter: Option<Filter>,
 }
 
 /// New chat session is created
@@ -79,47 +168,773 @@ pub struct Listen {
 #[rtype(String)]
 pub struct Test {}
 
+/// Events a `/watch` stream can have queued, a stream that falls further behind is dropped
+pub const STREAM_BUFFER: usize = 1024;
+
+/// Changes read from the change log at a time while catching a listener up
+const CATCH_UP_PAGE: usize = 256;
+
+/// Longest window a listener can have changes coalesced over
+pub const MAX_COALESCE: Duration = Duration::from_secs(60);
+
+/// What a new listener is sent before the live changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Start {
+    /// Nothing, only changes from now on
+    Live,
+    /// The changes after this sequence number
+    After(u64),
+    /// Every key and value under the prefix
+    Snapshot,
+}
+
+/// What listeners are sent
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    Change(Event),
+    /// A change committed on a peer, see `cluster`. It has no place in our change log, so it
+    /// goes out without a sequence number and can't be resumed from.
+    Remote(Change),
+    /// Every entry under the prefix as of `seq`, changes go on after it
+    Snapshot {
+        entries: Vec<Entry>,
+        seq: u64,
+    },
+    /// Changes after the requested sequence number are no longer kept, the client has to reload.
+    /// Changes go on from `seq`.
+    Resync {
+        seq: u64,
+    },
+}
+
+impl WatchEvent {
+    /// The event as queued for WebSocket sessions, a snapshot is a message per entry and a `synced` marker sent together
+    fn to_outgoing(&self) -> Outgoing {
+        match self {
+            // Serializing a change can not fail
+            WatchEvent::Change(event) => Outgoing::Change {
+                key: event.change.key().to_string(),
+                text: serde_json::to_string(event).unwrap(),
+            },
+            WatchEvent::Remote(change) => Outgoing::Change {
+                key: change.key().to_string(),
+                text: serde_json::to_string(change).unwrap(),
+            },
+            WatchEvent::Snapshot { entries, seq } => Outgoing::Batch(
+                entries
+                    .iter()
+                    .map(|entry| {
+                        serde_json::json!({
+                            "event": "snapshot",
+                            "key": entry.key,
+                            "value": entry.value,
+                        })
+                        .to_string()
+                    })
+                    .chain([serde_json::json!({ "event": "synced", "seq": seq }).to_string()])
+                    .collect(),
+            ),
+            WatchEvent::Resync { seq } => {
+                Outgoing::Text(serde_json::json!({ "event": "resync", "seq": seq }).to_string())
+            }
+        }
+    }
+}
+
+/// Where a listener that asked for a replay or snapshot is at
+#[derive(Debug)]
+enum CatchUp {
+    /// Reading the change log or snapshot, live changes are held back until it's done
+    Replaying(Vec<Event>),
+    /// Replayed up to this sequence number, live changes up to it were already sent
+    Replayed(u64),
+}
+
+/// Live changes to a listener that only wants the latest change per key every `window`.
+/// A key's first change in a while goes out right away, changes within the window after it
+/// wait and replace each other until the window ends.
+#[derive(Debug)]
+struct Coalesce {
+    window: Duration,
+    /// When keys were last sent, until their window ends
+    sent: HashMap<String, Instant>,
+    /// Latest change to each key that is waiting for its window to end, after the order it came in
+    pending: HashMap<String, (u64, WatchEvent)>,
+    /// Order the next held change gets
+    next: u64,
+    /// A flush is scheduled, there always is one while `sent` isn't empty
+    scheduled: bool,
+}
+
+impl Coalesce {
+    fn new(window: Duration) -> Coalesce {
+        Coalesce {
+            window,
+            sent: HashMap::new(),
+            pending: HashMap::new(),
+            next: 0,
+            scheduled: false,
+        }
+    }
+}
+
+/// Start streaming changes under a prefix
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Watch {
+    pub prefix: String,
+    pub start: Start,
+    pub coalesce: Option<Duration>,
+    pub filter: Option<Filter>,
+    pub sender: mpsc::Sender<WatchEvent>,
+}
+
+/// Changes committed on a peer instance, see `cluster`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Remote(pub Vec<Change>);
+
+/// A connected WebSocket session
+struct Session {
+    addr: Recipient<Flush>,
+    outbox: Arc<Outbox>,
+    name: Option<String>,
+    meta: Value,
+    /// Unix time it joined its room
+    since: u64,
+}
+
 /// `ClientWebSocketConnection` manages chat rooms and responsible for coordinating chat session.
 ///
 /// Implementation is very naïve.
-#[derive(Debug)]
 pub struct ClientWebSocketConnection {
-    sessions: HashMap<Uuid, Recipient<Message>>,
+    sessions: HashMap<Uuid, Session>,
     pub rooms: HashMap<String, HashSet<Uuid>>,
-    rng: ThreadRng,
     visitor_count: Arc<AtomicUsize>,
+    /// Sessions and `/watch` streams listening to each prefix
     pub prefix_listners: HashMap<String, HashSet<Uuid>>,
+    storage: Arc<dyn Storage>,
+    /// Committed changes from storage, turned into a stream once the actor starts
+    changes: Option<broadcast::Receiver<Changes>>,
+    /// `/watch` streams, they listen to a prefix like sessions do
+    streams: HashMap<Uuid, mpsc::Sender<WatchEvent>>,
+    /// Listeners replaying the change log for a prefix
+    catching_up: HashMap<(Uuid, String), CatchUp>,
+    /// Listeners of a prefix that get changes coalesced per key
+    coalescing: HashMap<(Uuid, String), Coalesce>,
+    /// Listeners of a prefix that only get values matching a filter
+    filters: HashMap<(Uuid, String), Filter>,
+    /// Sessions subscribed to each pub/sub channel
+    channels: HashMap<String, HashSet<Uuid>>,
+    /// Sequence number of the last local change sent to listeners
+    last_seq: Option<u64>,
+    /// Live changes held back while listeners catch up on changes the actor missed,
+    /// `None` unless it is catching up
+    lag_held: Option<Vec<Changes>>,
+    /// Fell behind again while catching up, another round is needed before the held changes go out
+    lagged_again: bool,
 }
 
 impl ClientWebSocketConnection {
-    pub fn new(visitor_count: Arc<AtomicUsize>) -> ClientWebSocketConnection {
+    pub fn new(
+        visitor_count: Arc<AtomicUsize>,
+        storage: Arc<dyn Storage>,
+    ) -> ClientWebSocketConnection {
         // default room
         let mut rooms = HashMap::new();
         rooms.insert("main".to_owned(), HashSet::new());
 
+        // Subscribed first so nothing after this number is missed
+        let changes = storage.watch();
+        let last_seq = match storage.last_seq() {
+            Ok(last_seq) => Some(last_seq),
+            Err(error) => {
+                log::error!("could not read the last change, listeners can't catch up until there is one: {error}");
+                None
+            }
+        };
+
         ClientWebSocketConnection {
             sessions: HashMap::new(),
             rooms,
-            rng: rand::thread_rng(),
             visitor_count,
             prefix_listners: HashMap::new(),
+            changes: Some(changes),
+            storage,
+            streams: HashMap::new(),
+            catching_up: HashMap::new(),
+            coalescing: HashMap::new(),
+            filters: HashMap::new(),
+            channels: HashMap::new(),
+            last_seq,
+            lag_held: None,
+            lagged_again: false,
         }
     }
 }
 
 impl ClientWebSocketConnection {
-    /// Send message to all users in the room
-    fn send_message(&self, room: &str, message: &str, skip_id: Uuid) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
-                if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        addr.do_send(Message(message.to_owned()));
+    /// Sends committed changes to their listeners, skipping any that were already sent
+    fn publish_changes(&mut self, changes: Changes, ctx: &mut Context<Self>) {
+        let events: Vec<Event> = match self.last_seq {
+            Some(last) => changes
+                .0
+                .into_iter()
+                .filter(|event| event.seq > last)
+                .collect(),
+            None => changes.0,
+        };
+        // Changes only move forward, replay marks behind them have nothing left to skip
+        if let Some(first) = events.first() {
+            self.catching_up.retain(|_, catch_up| match catch_up {
+                CatchUp::Replayed(seq) => *seq >= first.seq,
+                CatchUp::Replaying(_) => true,
+            });
+        }
+        for event in events {
+            self.last_seq = Some(event.seq);
+            self.publish(WatchEvent::Change(event), ctx);
+        }
+    }
+
+    /// Reads the next page of changes the actor missed after falling behind the change stream,
+    /// once there are none left the live changes held back meanwhile go out
+    fn catch_up_lag(&self, after: u64, ctx: &mut Context<Self>) {
+        let storage = self.storage.clone();
+        web::block(move || storage.changes_since("", after, CATCH_UP_PAGE))
+            .into_actor(self)
+            .map(move |result, act, ctx| {
+                let result = result
+                    .map_err(|error| error.to_string().into())
+                    .and_then(|page| page);
+                match result {
+                    Ok(events) => {
+                        let more = events.len() == CATCH_UP_PAGE;
+                        act.publish_changes(Changes(events), ctx);
+                        if more || std::mem::take(&mut act.lagged_again) {
+                            act.catch_up_lag(act.last_seq.unwrap_or(after), ctx);
+                            return;
+                        }
+                    }
+                    Err(error) => {
+                        // Nobody knows what they missed, everyone reloads and goes on
+                        // from the held changes
+                        let seq = match error.downcast::<ChangesPruned>() {
+                            Ok(pruned) => {
+                                log::warn!(
+                                    "websocket listeners missed pruned changes, they have to reload"
+                                );
+                                pruned.last_seq
+                            }
+                            Err(error) => {
+                                log::error!(
+                                    "websocket listeners could not catch up and reload: {error}"
+                                );
+                                act.lag_held
+                                    .iter()
+                                    .flatten()
+                                    .flat_map(|changes| changes.0.first())
+                                    .map(|event| event.seq - 1)
+                                    .next()
+                                    .unwrap_or(after)
+                            }
+                        };
+                        act.resync_all(seq);
+                        act.last_seq = Some(seq);
+                        act.lagged_again = false;
+                    }
+                }
+                for changes in act.lag_held.take().unwrap_or_default() {
+                    act.publish_changes(changes, ctx);
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Tells every listener to reload, changes go on after `seq`
+    fn resync_all(&mut self, seq: u64) {
+        let listeners: HashSet<Uuid> = self.prefix_listners.values().flatten().copied().collect();
+        for id in listeners {
+            self.deliver(id, WatchEvent::Resync { seq });
+        }
+    }
+
+    /// Send a key change to every session and stream listening to a prefix of the key.
+    /// Changes from peers skip catch-up, they aren't in our change log.
+    fn publish(&mut self, event: WatchEvent, ctx: &mut Context<Self>) {
+        let (change, local) = match &event {
+            WatchEvent::Change(local) => (&local.change, Some(local)),
+            WatchEvent::Remote(change) => (change, None),
+            _ => return,
+        };
+        // The prefix to coalesce the change under, if every matching prefix of the listener is coalesced
+        let mut recipients: HashMap<Uuid, Option<String>> = HashMap::new();
+        // The new value as JSON, parsed the first time a filter needs it
+        let mut parsed = None;
+        for (prefix, listeners) in &self.prefix_listners {
+            if !change.key().starts_with(prefix.as_str()) {
+                continue;
+            }
+            for id in listeners {
+                let catch_up = match (local, self.catching_up.is_empty()) {
+                    (Some(local), false) => self
+                        .catching_up
+                        .get_mut(&(*id, prefix.clone()))
+                        .map(|catch_up| (local, catch_up)),
+                    _ => None,
+                };
+                match catch_up {
+                    Some((local, CatchUp::Replaying(held))) => held.push(local.clone()),
+                    Some((local, CatchUp::Replayed(seq))) if local.seq <= *seq => {}
+                    // A listener of overlapping prefixes only gets the change once
+                    _ => {
+                        let filter = match self.filters.is_empty() {
+                            true => None,
+                            false => self.filters.get(&(*id, prefix.clone())),
+                        };
+                        if let (Some(filter), Change::Set { value, .. }) = (filter, change) {
+                            let value = parsed.get_or_insert_with(|| filter::parse_value(value));
+                            if !filter.matches_json(value) {
+                                continue;
+                            }
+                        }
+                        let coalesced = !self.coalescing.is_empty()
+                            && self.coalescing.contains_key(&(*id, prefix.clone()));
+                        recipients
+                            .entry(*id)
+                            .and_modify(|coalesce| {
+                                if !coalesced {
+                                    *coalesce = None;
+                                }
+                            })
+                            .or_insert_with(|| coalesced.then(|| prefix.clone()));
+                    }
+                }
+            }
+        }
+        for (id, coalesce) in recipients {
+            match coalesce {
+                Some(prefix) => {
+                    self.coalesce(id, prefix, change.key().to_string(), event.clone(), ctx)
+                }
+                None => {
+                    self.deliver(id, event.clone());
+                }
+            }
+        }
+    }
+
+    /// Sends a change to a coalescing listener if its key is outside the window, holds it otherwise
+    fn coalesce(
+        &mut self,
+        id: Uuid,
+        prefix: String,
+        key: String,
+        event: WatchEvent,
+        ctx: &mut Context<Self>,
+    ) {
+        let subscription = (id, prefix);
+        let coalesce = match self.coalescing.get_mut(&subscription) {
+            Some(coalesce) => coalesce,
+            None => return,
+        };
+        let now = Instant::now();
+        let window = coalesce.window;
+        let in_window = coalesce
+            .sent
+            .get(&key)
+            .is_some_and(|sent| now < *sent + window);
+        let send = if in_window || coalesce.pending.contains_key(&key) {
+            coalesce.pending.insert(key, (coalesce.next, event));
+            coalesce.next += 1;
+            None
+        } else {
+            coalesce.sent.insert(key, now);
+            Some(event)
+        };
+        if !coalesce.scheduled {
+            coalesce.scheduled = true;
+            ctx.run_later(window, move |act, ctx| {
+                act.flush_coalesced(subscription, ctx)
+            });
+        }
+        if let Some(event) = send {
+            self.deliver(id, event);
+        }
+    }
+
+    /// Sends the held changes whose window ended and forgets keys that are out of their window
+    fn flush_coalesced(&mut self, subscription: (Uuid, String), ctx: &mut Context<Self>) {
+        let coalesce = match self.coalescing.get_mut(&subscription) {
+            Some(coalesce) => coalesce,
+            None => return,
+        };
+        let now = Instant::now();
+        let window = coalesce.window;
+        coalesce
+            .sent
+            .retain(|key, sent| now < *sent + window || coalesce.pending.contains_key(key));
+        let due: Vec<String> = coalesce
+            .pending
+            .keys()
+            .filter(|key| {
+                coalesce
+                    .sent
+                    .get(*key)
+                    .is_none_or(|sent| now >= *sent + window)
+            })
+            .cloned()
+            .collect();
+        let mut events = Vec::with_capacity(due.len());
+        for key in due {
+            if let Some(event) = coalesce.pending.remove(&key) {
+                events.push(event);
+            }
+            coalesce.sent.insert(key, now);
+        }
+        events.sort_by_key(|(order, _)| *order);
+
+        coalesce.scheduled = false;
+        if let Some(next) = coalesce.sent.values().min() {
+            coalesce.scheduled = true;
+            let delay = (*next + window).saturating_duration_since(now);
+            let subscription = subscription.clone();
+            ctx.run_later(delay, move |act, ctx| {
+                act.flush_coalesced(subscription, ctx)
+            });
+        }
+        for (_, event) in events {
+            if !self.deliver(subscription.0, event) {
+                return;
+            }
+        }
+    }
+
+    /// Whether a change gets past the listener's filter on a prefix, deletes always do
+    fn passes(&self, subscription: &(Uuid, String), change: &Change) -> bool {
+        match (self.filters.get(subscription), change) {
+            (Some(filter), Change::Set { value, .. }) => filter.matches(value),
+            _ => true,
+        }
+    }
+
+    /// Sends an event to a session or stream, returns false if the listener is gone
+    fn deliver(&mut self, id: Uuid, event: WatchEvent) -> bool {
+        if self.sessions.contains_key(&id) {
+            return self.send(id, event.to_outgoing());
+        }
+        if let Some(sender) = self.streams.get(&id) {
+            if sender.try_send(event).is_ok() {
+                return true;
+            }
+            // A stream that can't keep up is dropped, it resumes from its last id when it reconnects
+            self.forget(id);
+        }
+        false
+    }
+
+    /// Starts sending changes under `prefix` to a listener, after whatever `start` asks for
+    fn subscribe(
+        &mut self,
+        id: Uuid,
+        prefix: String,
+        start: Start,
+        coalesce: Option<Duration>,
+        filter: Option<Filter>,
+        ctx: &mut Context<Self>,
+    ) {
+        self.prefix_listners
+            .entry(prefix.clone())
+            .or_default()
+            .insert(id);
+        match coalesce {
+            Some(window) => {
+                self.coalescing
+                    .insert((id, prefix.clone()), Coalesce::new(window));
+            }
+            None => {
+                self.coalescing.remove(&(id, prefix.clone()));
+            }
+        }
+        match filter {
+            Some(filter) => {
+                self.filters.insert((id, prefix.clone()), filter);
+            }
+            None => {
+                self.filters.remove(&(id, prefix.clone()));
+            }
+        }
+        if start == Start::Live {
+            return;
+        }
+        self.catching_up
+            .insert((id, prefix.clone()), CatchUp::Replaying(Vec::new()));
+        match start {
+            Start::After(after) => self.catch_up(id, prefix, after, ctx),
+            Start::Snapshot => self.take_snapshot(id, prefix, ctx),
+            Start::Live => {}
+        }
+    }
+
+    /// Reads every entry under the prefix for a listener off the actor's thread
+    fn take_snapshot(&self, id: Uuid, prefix: String, ctx: &mut Context<Self>) {
+        let storage = self.storage.clone();
+        let query = prefix.clone();
+        web::block(move || storage.snapshot(&query))
+            .into_actor(self)
+            .map(move |result, act, _| {
+                let subscription = (id, prefix);
+                // The listener left while the snapshot was read
+                if !act.catching_up.contains_key(&subscription) {
+                    return;
+                }
+                match result
+                    .map_err(|error| error.to_string().into())
+                    .and_then(|snapshot| snapshot)
+                {
+                    Ok((mut entries, seq)) => {
+                        if let Some(filter) = act.filters.get(&subscription) {
+                            entries.retain(|entry| filter.matches(&entry.value));
+                        }
+                        if act.deliver(id, WatchEvent::Snapshot { entries, seq }) {
+                            act.caught_up(subscription, seq);
+                        }
+                    }
+                    Err(error) => act.catch_up_failed(subscription, error),
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Reads the next page of the change log for a listener off the actor's thread
+    fn catch_up(&self, id: Uuid, prefix: String, after: u64, ctx: &mut Context<Self>) {
+        let storage = self.storage.clone();
+        let query = prefix.clone();
+        web::block(move || storage.changes_since(&query, after, CATCH_UP_PAGE))
+            .into_actor(self)
+            .map(move |result, act, ctx| {
+                let result = result
+                    .map_err(|error| error.to_string().into())
+                    .and_then(|page| page);
+                act.replay(id, prefix, after, result, ctx);
+            })
+            .spawn(ctx);
+    }
+
+    /// Sends a page of the change log, then either reads the next one or the changes held back meanwhile
+    fn replay(
+        &mut self,
+        id: Uuid,
+        prefix: String,
+        after: u64,
+        page: Result<Vec<Event>, StorageError>,
+        ctx: &mut Context<Self>,
+    ) {
+        let subscription = (id, prefix);
+        // The listener left while the page was read
+        if !self.catching_up.contains_key(&subscription) {
+            return;
+        }
+
+        let last = match page {
+            Ok(events) => {
+                let mut last = after;
+                let more = events.len() == CATCH_UP_PAGE;
+                for event in events {
+                    last = event.seq;
+                    if !self.passes(&subscription, &event.change) {
+                        continue;
+                    }
+                    if !self.deliver(id, WatchEvent::Change(event)) {
+                        return;
+                    }
+                }
+                if more {
+                    if let Some(CatchUp::Replaying(held)) = self.catching_up.get_mut(&subscription)
+                    {
+                        held.retain(|event| event.seq > last);
                     }
+                    self.catch_up(id, subscription.1, last, ctx);
+                    return;
                 }
+                last
+            }
+            Err(error) => match error.downcast::<ChangesPruned>() {
+                Ok(pruned) => {
+                    if !self.deliver(
+                        id,
+                        WatchEvent::Resync {
+                            seq: pruned.last_seq,
+                        },
+                    ) {
+                        return;
+                    }
+                    pruned.last_seq
+                }
+                Err(error) => return self.catch_up_failed(subscription, error),
+            },
+        };
+        self.caught_up(subscription, last);
+    }
+
+    /// Sends the live changes held back while the listener caught up to `last` and lets the rest through
+    fn caught_up(&mut self, subscription: (Uuid, String), mut last: u64) {
+        let id = subscription.0;
+        // Changes that came after the read are still on their way and will be sent live
+        if let Some(CatchUp::Replaying(held)) = self
+            .catching_up
+            .insert(subscription.clone(), CatchUp::Replayed(last))
+        {
+            let replayed = last;
+            for event in held.into_iter().filter(|event| event.seq > replayed) {
+                last = event.seq;
+                if !self.passes(&subscription, &event.change) {
+                    continue;
+                }
+                if !self.deliver(id, WatchEvent::Change(event)) {
+                    return;
+                }
+            }
+            if let Some(CatchUp::Replayed(seq)) = self.catching_up.get_mut(&subscription) {
+                *seq = last;
             }
         }
     }
+
+    fn catch_up_failed(&mut self, subscription: (Uuid, String), error: StorageError) {
+        let (id, prefix) = subscription;
+        log::error!("could not catch a listener of {prefix} up: {error}");
+        self.unsubscribe(id, &prefix);
+        self.send(
+            id,
+            Outgoing::Text(format!("!!! could not catch up on {prefix}: {error}")),
+        );
+    }
+
+    /// Stops sending changes under `prefix` to a listener
+    fn unsubscribe(&mut self, id: Uuid, prefix: &str) {
+        if let Some(listeners) = self.prefix_listners.get_mut(prefix) {
+            listeners.remove(&id);
+            if listeners.is_empty() {
+                self.prefix_listners.remove(prefix);
+            }
+        }
+        self.catching_up.remove(&(id, prefix.to_string()));
+        self.coalescing.remove(&(id, prefix.to_string()));
+        self.filters.remove(&(id, prefix.to_string()));
+        // A stream only ever listens to one prefix
+        self.streams.remove(&id);
+    }
+
+    /// Stops sending changes to a session or stream
+    fn forget(&mut self, id: Uuid) {
+        self.streams.remove(&id);
+        self.prefix_listners.retain(|_, listeners| {
+            listeners.remove(&id);
+            !listeners.is_empty()
+        });
+        self.catching_up.retain(|(listener, _), _| *listener != id);
+        self.coalescing.retain(|(listener, _), _| *listener != id);
+        self.filters.retain(|(listener, _), _| *listener != id);
+    }
+
+    /// Queues a message in a session's outbox, returns false if the session is gone or fell too far behind
+    fn send(&mut self, id: Uuid, message: Outgoing) -> bool {
+        let session = match self.sessions.get(&id) {
+            Some(session) => session,
+            None => return false,
+        };
+        match session.outbox.push(message) {
+            Pushed::Wake => session.addr.do_send(Flush),
+            Pushed::Queued => {}
+            Pushed::Closed => {
+                // The session closes with the reason once it gets to run
+                session.addr.do_send(Flush);
+                log::warn!("websocket session {id} fell behind and is disconnected");
+                self.remove_session(id, LeaveReason::SlowConsumer);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Removes a session from the rooms and listeners and tells the rooms it left
+    fn remove_session(&mut self, id: Uuid, reason: LeaveReason) {
+        let member = match self.member(id) {
+            Some(member) => member,
+            None => return,
+        };
+        self.sessions.remove(&id);
+        self.forget(id);
+        self.channels.retain(|_, subscribers| {
+            subscribers.remove(&id);
+            !subscribers.is_empty()
+        });
+        for room in self.leave_rooms(id) {
+            self.announce_leave(&room, &member, reason);
+        }
+    }
+
+    /// Takes a session out of every room, returns the rooms it was in
+    fn leave_rooms(&mut self, id: Uuid) -> Vec<String> {
+        let mut rooms = Vec::new();
+        for (name, sessions) in &mut self.rooms {
+            if sessions.remove(&id) {
+                rooms.push(name.to_owned());
+            }
+        }
+        rooms
+    }
+
+    /// A session as the members of its room see it
+    fn member(&self, id: Uuid) -> Option<Member> {
+        self.sessions.get(&id).map(|session| Member {
+            id,
+            name: session.name.clone(),
+            meta: session.meta.clone(),
+            since: session.since,
+        })
+    }
+
+    /// The room a session is in, a session is in one room at a time
+    fn room_of(&self, id: Uuid) -> Option<String> {
+        self.rooms
+            .iter()
+            .find(|(_, sessions)| sessions.contains(&id))
+            .map(|(name, _)| name.clone())
+    }
+
+    /// Puts a session in a room and tells the others there
+    fn join_room(&mut self, id: Uuid, room: String) {
+        if let Some(session) = self.sessions.get_mut(&id) {
+            session.since = unix_time();
+        }
+        self.rooms.entry(room.clone()).or_default().insert(id);
+        if let Some(member) = self.member(id) {
+            let event = json!({ "event": "join", "room": room, "member": member });
+            self.send_message(&room, &event.to_string(), id);
+        }
+    }
+
+    fn announce_leave(&mut self, room: &str, member: &Member, reason: LeaveReason) {
+        let event = json!({ "event": "leave", "room": room, "member": member, "reason": reason });
+        self.send_message(room, &event.to_string(), member.id);
+    }
+
+    /// Send message to all users in the room
+    fn send_message(&mut self, room: &str, message: &str, skip_id: Uuid) {
+        let recipients: Vec<Uuid> = match self.rooms.get(room) {
+            Some(sessions) => sessions
+                .iter()
+                .copied()
+                .filter(|id| *id != skip_id)
+                .collect(),
+            None => return,
+        };
+        for id in recipients {
+            self.send(id, Outgoing::Text(message.to_owned()));
+        }
+    }
 }
 
 /// Make actor from `ClientWebSocketConnection`
@@ -127,6 +942,12 @@ impl Actor for ClientWebSocketConnection {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(changes) = self.changes.take() {
+            ctx.add_stream(BroadcastStream::new(changes));
+        }
+    }
 }
 
 /// Handler for Connect message.
@@ -144,15 +965,23 @@ impl Handler<Connect> for ClientWebSocketConnection {
         // register session with random id
         let id = Uuid::new_v4();
 
-        self.sessions.insert(id, msg.addr);
+        self.sessions.insert(
+            id,
+            Session {
+                addr: msg.addr,
+                outbox: msg.outbox,
+                name: None,
+                meta: Value::Null,
+                since: unix_time(),
+            },
+        );
 
         // auto join session to main room
-        self.rooms
-            .entry("main".to_owned())
-            .or_insert_with(HashSet::new)
-            .insert(id);
+        if let Some(room) = msg.room {
+            self.join_room(id, room);
+        }
 
-        let count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
+        let _count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
         //        self.send_message("main", &format!("Total visitors {count}"), 0);
 
         // send id back
@@ -167,24 +996,113 @@ impl Handler<Disconnect> for ClientWebSocketConnection {
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
         println!("Someone disconnected");
 
-        let mut rooms: Vec<String> = Vec::new();
+        // remove address, session from all rooms, and stop sending it key changes
+        self.remove_session(msg.id, msg.reason);
+    }
+}
+
+/// Handler for `SetPresence`, tells the room about the new name or metadata
+impl Handler<SetPresence> for ClientWebSocketConnection {
+    type Result = ();
 
-        // remove address
-        if self.sessions.remove(&msg.id).is_some() {
-            // remove session from all rooms
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
-                }
-            }
+    fn handle(&mut self, msg: SetPresence, _: &mut Context<Self>) {
+        let SetPresence { id, name, meta } = msg;
+        let session = match self.sessions.get_mut(&id) {
+            Some(session) => session,
+            None => return,
+        };
+        if name.is_some() {
+            session.name = name;
         }
-        // send message to other users
-        for room in rooms {
-            //            self.send_message(&room, "Someone disconnected", 0);
+        if let Some(meta) = meta {
+            session.meta = meta;
+        }
+        if let (Some(room), Some(member)) = (self.room_of(id), self.member(id)) {
+            let event = json!({ "event": "update", "room": room, "member": member });
+            self.send_message(&room, &event.to_string(), id);
+        }
+    }
+}
+
+/// Handler for `Subscribe`
+impl Handler<Subscribe> for ClientWebSocketConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
+        if self.sessions.contains_key(&msg.id) {
+            self.channels.entry(msg.channel).or_default().insert(msg.id);
         }
     }
 }
 
+/// Handler for `Unsubscribe`
+impl Handler<Unsubscribe> for ClientWebSocketConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Context<Self>) {
+        if let Some(subscribers) = self.channels.get_mut(&msg.channel) {
+            subscribers.remove(&msg.id);
+            if subscribers.is_empty() {
+                self.channels.remove(&msg.channel);
+            }
+        }
+    }
+}
+
+/// Handler for `Publish`, nothing is kept for subscribers that come later
+impl Handler<Publish> for ClientWebSocketConnection {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Publish, _: &mut Context<Self>) -> Self::Result {
+        let Publish {
+            from,
+            channel,
+            message,
+        } = msg;
+        let subscribers: Vec<Uuid> = match self.channels.get(&channel) {
+            Some(subscribers) => subscribers
+                .iter()
+                .copied()
+                .filter(|id| Some(*id) != from)
+                .collect(),
+            None => return 0,
+        };
+        let event = json!({
+            "event": "message",
+            "channel": channel,
+            "from": from,
+            "data": message,
+        })
+        .to_string();
+        subscribers
+            .into_iter()
+            .filter(|id| self.send(*id, Outgoing::Text(event.clone())))
+            .count()
+    }
+}
+
+/// Handler for `Who`, the members of a room sorted by when they joined
+impl Handler<Who> for ClientWebSocketConnection {
+    type Result = MessageResult<Who>;
+
+    fn handle(&mut self, msg: Who, _: &mut Context<Self>) -> Self::Result {
+        let room = match (msg.room, msg.id) {
+            (Some(room), _) => room,
+            (None, Some(id)) => match self.room_of(id) {
+                Some(room) => room,
+                None => return MessageResult(None),
+            },
+            (None, None) => return MessageResult(None),
+        };
+        let mut members: Vec<Member> = match self.rooms.get(&room) {
+            Some(sessions) => sessions.iter().filter_map(|id| self.member(*id)).collect(),
+            None => Vec::new(),
+        };
+        members.sort_by_key(|member| member.since);
+        MessageResult(Some((room, members)))
+    }
+}
+
 /// Handler for Message message.
 impl Handler<ClientMessage> for ClientWebSocketConnection {
     type Result = ();
@@ -216,25 +1134,17 @@ impl Handler<Join> for ClientWebSocketConnection {
 
     fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
         let Join { id, name } = msg;
-        let mut rooms = Vec::new();
+        let member = match self.member(id) {
+            Some(member) => member,
+            None => return,
+        };
 
-        // remove session from all rooms
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&id) {
-                rooms.push(n.to_owned());
-            }
+        // remove session from all rooms and send message to other users
+        for room in self.leave_rooms(id) {
+            self.announce_leave(&room, &member, LeaveReason::Left);
         }
-        // send message to other users
-        for room in rooms {
-            //            self.send_message(&room, "Someone disconnected", 0);
-        }
-
-        self.rooms
-            .entry(name.clone())
-            .or_insert_with(HashSet::new)
-            .insert(id);
 
-        self.send_message(&name, "Someone connected", id);
+        self.join_room(id, name);
     }
 }
 
@@ -242,8 +1152,14 @@ impl Handler<Join> for ClientWebSocketConnection {
 impl Handler<Listen> for ClientWebSocketConnection {
     type Result = ();
 
-    fn handle(&mut self, msg: Listen, _: &mut Context<Self>) {
-        let Listen { id, key_prefix } = msg;
+    fn handle(&mut self, msg: Listen, ctx: &mut Context<Self>) {
+        let Listen {
+            id,
+            key_prefix,
+            start,
+            coalesce,
+            filter,
+        } = msg;
 
         //        // remove session from all rooms
         //        for (n, sessions) in &mut self.rooms {
@@ -252,20 +1168,89 @@ impl Handler<Listen> for ClientWebSocketConnection {
         //            }
         //        }
         //        // send message to other users
-        //        for room in rooms {
+        //        for _room in rooms {
         //            self.send_message(&room, "Someone disconnected", 0);
         //        }
 
-        self.prefix_listners.insert(key_prefix, HashSet::from([id]));
+        self.subscribe(id, key_prefix, start, coalesce, filter, ctx);
 
         //        self.send_message(&prefix_clone, "Someone connected", id);
     }
 }
 
+/// Handler for committed key changes, sends each change to the listeners of a matching prefix
+impl StreamHandler<Result<Changes, BroadcastStreamRecvError>> for ClientWebSocketConnection {
+    fn handle(&mut self, msg: Result<Changes, BroadcastStreamRecvError>, ctx: &mut Context<Self>) {
+        match msg {
+            Ok(changes) => {
+                match &mut self.lag_held {
+                    Some(held) => held.push(changes),
+                    None => self.publish_changes(changes, ctx),
+                }
+                // Watch for closed streams even if nothing under their prefix changes
+                let closed: Vec<Uuid> = self
+                    .streams
+                    .iter()
+                    .filter(|(_, sender)| sender.is_closed())
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in closed {
+                    self.forget(id);
+                }
+            }
+            // Catch up from the change log like webhooks do, live changes wait until then
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                log::warn!(
+                    "websocket listeners fell behind and missed {missed} change groups, catching up"
+                );
+                match (self.lag_held.is_some(), self.last_seq) {
+                    (true, _) => self.lagged_again = true,
+                    (false, Some(after)) => {
+                        self.lag_held = Some(Vec::new());
+                        self.catch_up_lag(after, ctx);
+                    }
+                    (false, None) => {
+                        log::error!("websocket listeners can't catch up, no change was seen yet")
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Handler for changes forwarded by peers, listeners get them like local ones
+impl Handler<Remote> for ClientWebSocketConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: Remote, ctx: &mut Context<Self>) {
+        for change in msg.0 {
+            self.publish(WatchEvent::Remote(change), ctx);
+        }
+    }
+}
+
+/// Handler for `/watch` streams, sends what they asked for and adds them to the listeners
+impl Handler<Watch> for ClientWebSocketConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: Watch, ctx: &mut Context<Self>) {
+        let Watch {
+            prefix,
+            start,
+            coalesce,
+            filter,
+            sender,
+        } = msg;
+        let id = Uuid::new_v4();
+        self.streams.insert(id, sender);
+        self.subscribe(id, prefix, start, coalesce, filter, ctx);
+    }
+}
+
 impl Handler<Test> for ClientWebSocketConnection {
     type Result = String;
 
-    fn handle(&mut self, msg: Test, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, _msg: Test, _: &mut Context<Self>) -> Self::Result {
         //        // remove session from all rooms
         //        for (n, sessions) in &mut self.rooms {
         //            if sessions.remove(&id) {
@@ -273,13 +1258,12 @@ impl Handler<Test> for ClientWebSocketConnection {
         //            }
         //        }
         //        // send message to other users
-        //        for room in rooms {
+        //        for _room in rooms {
         //            self.send_message(&room, "Someone disconnected", 0);
         //        }
         //            self.send_message("main".to_string(), "Someone connected", id);
         //            let values = self.prefix_listners.values();
-        return "That worked".to_string();
-
         //        self.send_message(&prefix_clone, "Someone connected", id);
+        "That worked".to_string()
     }
 }
@@ -6,14 +6,20 @@ use futures::{
     future::{ok, Ready},
     Future,
 };
-use std::env;
-use std::env::VarError;
 use std::pin::Pin;
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
 //    next service in chain as parameter.
 // 2. Middleware's call method gets called with normal request.
-pub struct CheckForSecret;
+pub struct CheckForSecret {
+    secret: Option<String>,
+}
+
+impl CheckForSecret {
+    pub fn new(secret: Option<String>) -> CheckForSecret {
+        CheckForSecret { secret }
+    }
+}
 
 // Middleware factory is `Transform` trait
 // `S` - type of the next service
@@ -30,12 +36,16 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(CheckForSecretMiddleware { service })
+        ok(CheckForSecretMiddleware {
+            service,
+            secret: self.secret.clone(),
+        })
     }
 }
 
 pub struct CheckForSecretMiddleware<S> {
     service: S,
+    secret: Option<String>,
 }
 
 impl<S> Service<ServiceRequest> for CheckForSecretMiddleware<S>
@@ -51,33 +61,30 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let secret: String = req.match_info().get("secret").unwrap().parse().unwrap();
-        return match env::var("SECRET") {
-            saved_secret => match saved_secret {
-                Ok(unwrapped_secret) => {
-                    if unwrapped_secret == secret {
-                        let fut = self.service.call(req);
-                        Box::pin(async move {
-                            let res = fut.await?;
-                            return Ok(res);
-                        })
-                    } else {
-                        Box::pin(async move {
-                            return Ok(ServiceResponse::new(
-                                req.request().clone(),
-                                HttpResponse::Unauthorized()
-                                    .body("You do not have the correct secret"),
-                            ));
-                        })
-                    }
+        match &self.secret {
+            Some(unwrapped_secret) => {
+                if *unwrapped_secret == secret {
+                    let fut = self.service.call(req);
+                    Box::pin(async move {
+                        let res = fut.await?;
+                        Ok(res)
+                    })
+                } else {
+                    Box::pin(async move {
+                        Ok(ServiceResponse::new(
+                            req.request().clone(),
+                            HttpResponse::Unauthorized().body("You do not have the correct secret"),
+                        ))
+                    })
                 }
-                Err(_) => Box::pin(async move {
-                    Ok(ServiceResponse::new(
-                        req.request().clone(),
-                        HttpResponse::Unauthorized()
-                            .body("You do not have an env variable set for the secret"),
-                    ))
-                }),
-            },
-        };
+            }
+            None => Box::pin(async move {
+                Ok(ServiceResponse::new(
+                    req.request().clone(),
+                    HttpResponse::Unauthorized()
+                        .body("You do not have a secret set in the config or env"),
+                ))
+            }),
+        }
     }
 }
@@ -0,0 +1,45 @@
+//! Writes a backup every `backup.interval_secs` and prunes old ones.
+
+use crate::config::BackupConfig;
+use crate::data_access::backup;
+use crate::storage::Storage;
+use actix_web::{rt, web};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Spawns the backup loop on the current runtime, does nothing if backups are turned off
+pub fn start(storage: Arc<dyn Storage>, config: BackupConfig) {
+    let dir = match (&config.dir, config.interval_secs) {
+        (Some(dir), interval_secs) if interval_secs > 0 => dir.clone(),
+        _ => return,
+    };
+    log::info!(
+        "writing backups to {} every {}s, keeping {}",
+        dir.display(),
+        config.interval_secs,
+        config.retain
+    );
+
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(Duration::from_secs(config.interval_secs));
+        // The first tick is right away, we don't need a backup of what we just opened
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let storage = storage.clone();
+            let dir = dir.clone();
+            let retain = config.retain;
+            let result = web::block(move || {
+                let path = backup::backup_into_dir(storage.as_ref(), &dir)?;
+                backup::prune_backups(&dir, retain)?;
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(path)
+            })
+            .await;
+            match result {
+                Ok(Ok(path)) => log::info!("wrote backup {}", path.display()),
+                Ok(Err(error)) => log::error!("scheduled backup failed: {error}"),
+                Err(error) => log::error!("scheduled backup failed: {error}"),
+            }
+        }
+    });
+}
@@ -0,0 +1,168 @@
+//! Ephemeral pub/sub channels.
+//!
+//! Messages published to a channel go to the WebSocket sessions subscribed to
+//! it at that moment and are never stored, which suits typing indicators and
+//! signaling. The secret can use every channel. Rules in the config can let
+//! channel tokens publish or subscribe to channels by name prefix, and cap
+//! the size of their messages.
+
+use actix_web::{http::StatusCode, HttpResponse};
+use serde::Deserialize;
+use std::fmt;
+
+/// Largest message when no rule sets one, in bytes
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Longest channel name, in bytes
+pub const MAX_CHANNEL_NAME: usize = 256;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelsConfig {
+    /// Largest message in bytes, unless a rule sets its own
+    pub max_message_size: usize,
+    /// Settings for the channels starting with a prefix, the longest matching prefix wins
+    pub rules: Vec<ChannelRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelRule {
+    pub prefix: String,
+    pub max_message_size: Option<usize>,
+    /// Tokens that can publish to the channels
+    #[serde(default)]
+    pub publish: Vec<String>,
+    /// Tokens that can subscribe to the channels
+    #[serde(default)]
+    pub subscribe: Vec<String>,
+}
+
+/// Who is using a channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    /// Connected with the secret, can use every channel
+    Secret,
+    /// Connected with a channel token, can only use the channels rules give it
+    Token(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Publish,
+    Subscribe,
+}
+
+/// Why a channel can't be used
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChannelError {
+    InvalidName,
+    Forbidden { channel: String, scope: Scope },
+    MessageTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::InvalidName => write!(
+                f,
+                "Channel names can't be empty, contain spaces or be longer than {MAX_CHANNEL_NAME} bytes"
+            ),
+            ChannelError::Forbidden { channel, scope } => {
+                let scope = match scope {
+                    Scope::Publish => "publish to",
+                    Scope::Subscribe => "subscribe to",
+                };
+                write!(f, "This token can't {scope} {channel}")
+            }
+            ChannelError::MessageTooLarge { size, max } => write!(
+                f,
+                "Message is {size} bytes, the max message size is {max} bytes"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+impl ChannelError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ChannelError::InvalidName => StatusCode::BAD_REQUEST,
+            ChannelError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            ChannelError::MessageTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).body(self.to_string())
+    }
+}
+
+impl Default for ChannelsConfig {
+    fn default() -> Self {
+        ChannelsConfig {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl ChannelsConfig {
+    fn rule(&self, channel: &str) -> Option<&ChannelRule> {
+        self.rules
+            .iter()
+            .filter(|rule| channel.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+    }
+
+    /// Whether any rule lets `token` use a channel
+    pub fn knows(&self, token: &str) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.publish.iter().any(|known| known == token)
+                || rule.subscribe.iter().any(|known| known == token)
+        })
+    }
+
+    /// Checks the channel name and that `access` can use the channel that way
+    pub fn check(&self, access: &Access, channel: &str, scope: Scope) -> Result<(), ChannelError> {
+        if channel.is_empty()
+            || channel.len() > MAX_CHANNEL_NAME
+            || channel.contains(char::is_whitespace)
+        {
+            return Err(ChannelError::InvalidName);
+        }
+        let token = match access {
+            Access::Secret => return Ok(()),
+            Access::Token(token) => token,
+        };
+        let allowed = self.rule(channel).is_some_and(|rule| {
+            let tokens = match scope {
+                Scope::Publish => &rule.publish,
+                Scope::Subscribe => &rule.subscribe,
+            };
+            tokens.contains(token)
+        });
+        match allowed {
+            true => Ok(()),
+            false => Err(ChannelError::Forbidden {
+                channel: channel.to_string(),
+                scope,
+            }),
+        }
+    }
+
+    pub fn check_message(&self, channel: &str, message: &str) -> Result<(), ChannelError> {
+        let max = self
+            .rule(channel)
+            .and_then(|rule| rule.max_message_size)
+            .unwrap_or(self.max_message_size);
+        match message.len() > max {
+            true => Err(ChannelError::MessageTooLarge {
+                size: message.len(),
+                max,
+            }),
+            false => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Command line interface. Everything except `serve` works directly against the
+//! configured storage, so it can be used for maintenance without a running server.
+//! Commands that write refuse to run while a server is using the same data.
+
+use crate::config::{Config, ConfigArgs};
+use crate::data_access::{backup, migrations, pool::ConnectionOptions};
+use crate::data_lock;
+use crate::storage::{self, transfer::*, Storage};
+use clap::{Parser, Subcommand};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use urlencoding::encode;
+
+type CliError = Box<dyn Error + Send + Sync>;
+
+/// A tiny Replit DB compatible database with WebSocket updates
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+
+    /// Defaults to `serve`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the HTTP and WebSocket server
+    Serve {
+        /// Replace the database with this backup before starting
+        #[arg(long)]
+        restore_from: Option<PathBuf>,
+    },
+    /// Run any pending database migrations
+    Migrate,
+    /// Print the value of a key
+    Get { key: String },
+    /// Set a key, reads the value from stdin when it is not passed
+    Set { key: String, value: Option<String> },
+    /// List the keys starting with a prefix
+    List {
+        #[arg(default_value = "")]
+        prefix: String,
+        /// Url encode the keys like `?encode=true` does
+        #[arg(long)]
+        encode: bool,
+    },
+    /// Delete a key
+    Delete { key: String },
+    /// Dump every key and value
+    Export {
+        /// Only export keys starting with this prefix
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[arg(long, value_enum, default_value_t)]
+        format: TransferFormat,
+        /// File to write to, defaults to stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Write a consistent copy of the database, safe to run while the server is up
+    Backup {
+        /// Where to write the backup, must not exist yet
+        path: PathBuf,
+    },
+    /// Replace the database with a backup, fails while the server is running
+    Restore { path: PathBuf },
+    /// Load keys and values from an export
+    Import {
+        /// File to read from, defaults to stdin
+        input: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t)]
+        format: TransferFormat,
+        /// What to do with keys that already exist
+        #[arg(long, value_enum, default_value_t)]
+        on_conflict: ConflictPolicy,
+        /// Number of keys written per transaction
+        #[arg(long, default_value_t = DEFAULT_BATCH_SIZE)]
+        batch_size: usize,
+    },
+}
+
+/// Runs an administrative command, `serve` is handled in main
+pub fn run(command: Command, config: &Config) -> Result<(), CliError> {
+    // A running server wouldn't see writes made here, see `data_lock`
+    let _lock = match &command {
+        Command::Set { .. }
+        | Command::Delete { .. }
+        | Command::Import { .. }
+        | Command::Restore { .. }
+        | Command::Migrate => Some(data_lock::acquire(config)?),
+        _ => None,
+    };
+
+    // Restoring swaps the file out, so it can't have a connection open
+    if let Command::Restore { path } = &command {
+        backup::restore_from(path, &config.database.url)?;
+        println!("Restored {} from {}", config.database.url, path.display());
+        return Ok(());
+    }
+
+    if let Command::Migrate = &command {
+        let mut conn =
+            ConnectionOptions::from_config(&config.database).establish(&config.database.url)?;
+        migrations::run(&mut conn)?;
+        println!("Migrations are up to date");
+        return Ok(());
+    }
+
+    let storage = storage::open(config, None)?;
+    let mut stdout = io::stdout().lock();
+
+    match command {
+        Command::Serve { .. } | Command::Restore { .. } | Command::Migrate => {
+            unreachable!("handled before opening storage")
+        }
+        Command::Get { key } => match storage.get(&key)? {
+            Some(value) => writeln!(stdout, "{value}")?,
+            None => return Err(format!("Key {key} does not exist").into()),
+        },
+        Command::Set { key, value } => {
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    let mut value = String::new();
+                    io::stdin().read_to_string(&mut value)?;
+                    value
+                }
+            };
+            config.limits.check(&key, &value)?;
+            storage.set(key, value)?;
+        }
+        Command::List {
+            prefix,
+            encode: encode_keys,
+        } => {
+            for key in storage.scan(&prefix)? {
+                match encode_keys {
+                    true => writeln!(stdout, "{}", encode(&key))?,
+                    false => writeln!(stdout, "{key}")?,
+                }
+            }
+        }
+        Command::Delete { key } => {
+            if !storage.delete(&key)? {
+                return Err(format!("Key {key} does not exist").into());
+            }
+        }
+        Command::Export {
+            prefix,
+            format,
+            output,
+        } => {
+            let mut writer: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(stdout),
+            };
+            export(storage.as_ref(), &prefix, format, &mut writer)?;
+        }
+        Command::Backup { path } => {
+            storage.backup_to(&path)?;
+            writeln!(stdout, "Wrote backup to {}", path.display())?;
+        }
+        Command::Import {
+            input,
+            format,
+            on_conflict,
+            batch_size,
+        } => {
+            let reader: Box<dyn BufRead> = match input {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(io::stdin().lock()),
+            };
+            let summary = import(storage.as_ref(), reader, format, on_conflict, batch_size)?;
+            writeln!(
+                stdout,
+                "Inserted {}, overwrote {}, skipped {}",
+                summary.inserted, summary.overwritten, summary.skipped
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn export(
+    storage: &dyn Storage,
+    prefix: &str,
+    format: TransferFormat,
+    writer: &mut impl Write,
+) -> Result<(), CliError> {
+    let mut exporter = Exporter::new(format);
+    let mut last_key: Option<String> = None;
+    write!(writer, "{}", exporter.start())?;
+    loop {
+        let page = export_page(storage, prefix, last_key.as_deref(), DEFAULT_BATCH_SIZE)?;
+        for entry in &page {
+            write!(writer, "{}", exporter.entry(entry))?;
+        }
+        match page.last() {
+            Some(last) if page.len() == DEFAULT_BATCH_SIZE => last_key = Some(last.key.clone()),
+            _ => break,
+        }
+    }
+    write!(writer, "{}", exporter.finish())?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn import(
+    storage: &dyn Storage,
+    mut reader: impl BufRead,
+    format: TransferFormat,
+    policy: ConflictPolicy,
+    batch_size: usize,
+) -> Result<ImportSummary, CliError> {
+    let mut importer = Importer::new(policy, batch_size);
+    match format {
+        TransferFormat::Jsonl => {
+            for (index, line) in reader.lines().enumerate() {
+                if let Some(record) = parse_jsonl_line(index + 1, &line?)? {
+                    if importer.push(record) {
+                        importer.flush(storage)?;
+                    }
+                }
+            }
+        }
+        TransferFormat::Json => {
+            let mut body = Vec::new();
+            reader.read_to_end(&mut body)?;
+            for record in parse_json_object(&body)? {
+                if importer.push(record) {
+                    importer.flush(storage)?;
+                }
+            }
+        }
+    }
+    importer.flush(storage)?;
+    Ok(importer.summary)
+}
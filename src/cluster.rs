@@ -0,0 +1,140 @@
+//! Change notifications shared between instances.
+//!
+//! Every instance streams the changes committed on it at
+//! `/cluster/{secret}/changes` (see `actors::peer`). With peers configured an
+//! instance connects to each of them and hands the changes they stream to its
+//! own listeners and long-polls, as if they were made here. That way a client
+//! connected to one instance hears about writes made through another, as long
+//! as the instances share their data or are kept in sync some other way.
+//!
+//! Lost connections are retried with backoff. Changes made on a peer while it
+//! was unreachable are not sent again.
+
+use crate::actors::ws_actor::{ClientWebSocketConnection, Remote};
+use crate::config::ClusterConfig;
+use crate::limits::Limits;
+use crate::storage::Event;
+use crate::waiters::Waiters;
+use actix::Addr;
+use actix_web::rt;
+use awc::ws::{Frame, Message};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[cfg(test)]
+mod tests;
+
+/// First wait before connecting to a peer again
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait before connecting to a peer again
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Peers ping every 5 seconds, a connection quiet for longer than this is gone
+const PEER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// This instance's id, made up at start so instances can tell each other apart
+#[derive(Debug, Clone, Copy)]
+pub struct NodeId(pub Uuid);
+
+/// First message on a peer connection
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub node: Uuid,
+}
+
+/// Why a connection to a peer ended without an error
+enum Ended {
+    /// The peer is this instance
+    Ourselves,
+    Closed,
+}
+
+/// Connects to every configured peer and forwards the changes they stream
+pub fn start(
+    config: &ClusterConfig,
+    node: NodeId,
+    server: Addr<ClientWebSocketConnection>,
+    waiters: Arc<Waiters>,
+    limits: Limits,
+) {
+    let secret = match &config.secret {
+        Some(secret) => urlencoding::encode(secret).into_owned(),
+        None => return,
+    };
+    for peer in &config.peers {
+        let peer = peer.trim_end_matches('/').to_string();
+        let url = format!("{peer}/cluster/{secret}/changes");
+        let server = server.clone();
+        let waiters = waiters.clone();
+        rt::spawn(async move {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                match follow(&url, node, &server, &waiters, limits, &mut backoff).await {
+                    Ok(Ended::Ourselves) => {
+                        log::warn!("cluster peer {peer} is this instance, not following it");
+                        return;
+                    }
+                    Ok(Ended::Closed) => log::info!("cluster peer {peer} closed the connection"),
+                    Err(error) => log::warn!("cluster peer {peer}: {error}"),
+                }
+                rt::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+    }
+}
+
+/// Forwards the changes a peer streams until the connection ends, resets the backoff once connected
+async fn follow(
+    url: &str,
+    node: NodeId,
+    server: &Addr<ClientWebSocketConnection>,
+    waiters: &Waiters,
+    limits: Limits,
+    backoff: &mut Duration,
+) -> Result<Ended, String> {
+    let (_, mut connection) = awc::Client::new()
+        .ws(url)
+        // the payload limit is three times a key and value, JSON escapes can make them six times longer
+        .max_frame_size(limits.max_payload_size() * 2)
+        .connect()
+        .await
+        .map_err(|error| format!("could not connect: {error}"))?;
+    let mut greeted = false;
+    loop {
+        let frame = match rt::time::timeout(PEER_TIMEOUT, connection.next()).await {
+            Ok(Some(frame)) => frame.map_err(|error| error.to_string())?,
+            Ok(None) => return Ok(Ended::Closed),
+            Err(_) => return Err("stopped sending pings".to_string()),
+        };
+        match frame {
+            Frame::Text(text) if !greeted => {
+                let hello: Hello =
+                    serde_json::from_slice(&text).map_err(|error| format!("bad hello: {error}"))?;
+                if hello.node == node.0 {
+                    let _ = connection.send(Message::Close(None)).await;
+                    return Ok(Ended::Ourselves);
+                }
+                log::info!("following cluster peer {}", hello.node);
+                greeted = true;
+                *backoff = MIN_BACKOFF;
+            }
+            Frame::Text(text) => {
+                let event: Event = serde_json::from_slice(&text)
+                    .map_err(|error| format!("bad change: {error}"))?;
+                waiters.wake(event.change.key());
+                server.do_send(Remote(vec![event.change]));
+            }
+            Frame::Ping(ping) => connection
+                .send(Message::Pong(ping))
+                .await
+                .map_err(|error| error.to_string())?,
+            Frame::Close(_) => return Ok(Ended::Closed),
+            _ => {}
+        }
+    }
+}
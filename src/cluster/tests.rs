@@ -0,0 +1,176 @@
+//! Changes from a peer served on a local port reaching this instance's listeners
+
+use super::*;
+use crate::actors::outbox::{Outbox, Outgoing, WsMetrics};
+use crate::actors::ws_actor::{Connect, Flush, Listen, Start, Watch, WatchEvent};
+use crate::config::WebSocketConfig;
+use crate::controllers::{cluster_controller, watch_controller::sse_event};
+use crate::storage::{Change, MemoryStorage, Storage};
+use actix::prelude::*;
+use actix_web::{web, App, HttpServer};
+use std::sync::atomic::AtomicUsize;
+use tokio::sync::mpsc;
+
+/// An instance with its listeners and its own `/cluster/secret/changes`
+struct Instance {
+    storage: Arc<dyn Storage>,
+    server: Addr<ClientWebSocketConnection>,
+    waiters: Arc<Waiters>,
+    url: String,
+}
+
+fn instance() -> Instance {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let server =
+        ClientWebSocketConnection::new(Arc::new(AtomicUsize::new(0)), storage.clone()).start();
+    let waiters = Waiters::start(storage.watch());
+    let data = storage.clone();
+    let http = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::from(data.clone()))
+            .app_data(web::Data::new(NodeId(Uuid::new_v4())))
+            .service(web::scope("/cluster/secret").service(cluster_controller::changes))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}", http.addrs()[0]);
+    rt::spawn(http.run());
+    Instance {
+        storage,
+        server,
+        waiters,
+        url,
+    }
+}
+
+/// Stands in for a WebSocket session, hands the changes it is sent to the test
+struct Inbox {
+    outbox: Arc<Outbox>,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl Actor for Inbox {
+    type Context = Context<Self>;
+}
+
+impl Handler<Flush> for Inbox {
+    type Result = ();
+
+    fn handle(&mut self, _: Flush, _: &mut Context<Self>) {
+        let (queue, _) = self.outbox.drain();
+        for message in queue {
+            if let Outgoing::Change { text, .. } = message {
+                let _ = self.sender.send(text);
+            }
+        }
+    }
+}
+
+async fn recv<T>(receiver: &mut mpsc::Receiver<T>) -> T {
+    rt::time::timeout(Duration::from_secs(5), receiver.recv())
+        .await
+        .expect("timed out")
+        .unwrap()
+}
+
+#[actix_rt::test]
+async fn changes_from_a_peer_reach_listeners_and_stop_here() {
+    let peer = instance();
+    let local = instance();
+
+    let (sender, mut session) = mpsc::unbounded_channel();
+    let outbox = Arc::new(Outbox::new(
+        WebSocketConfig::default(),
+        Arc::new(WsMetrics::default()),
+    ));
+    let inbox = Inbox {
+        outbox: outbox.clone(),
+        sender,
+    }
+    .start();
+    let id = local
+        .server
+        .send(Connect {
+            addr: inbox.recipient(),
+            outbox,
+            room: None,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    local.server.do_send(Listen {
+        id,
+        key_prefix: "users/".to_string(),
+        start: Start::Live,
+        coalesce: None,
+        filter: None,
+    });
+    let (sender, mut stream) = mpsc::channel(16);
+    local.server.do_send(Watch {
+        prefix: "users/".to_string(),
+        start: Start::Live,
+        coalesce: None,
+        filter: None,
+        sender,
+    });
+
+    // Another instance following this one, it must not hear about the peer's changes
+    let (_, mut follower) = awc::Client::new()
+        .ws(format!("{}/cluster/secret/changes", local.url))
+        .connect()
+        .await
+        .unwrap();
+    assert!(matches!(follower.next().await, Some(Ok(Frame::Text(_)))));
+
+    start(
+        &ClusterConfig {
+            secret: Some("secret".to_string()),
+            peers: vec![peer.url.clone()],
+        },
+        NodeId(Uuid::new_v4()),
+        local.server.clone(),
+        local.waiters.clone(),
+        Limits::default(),
+    );
+
+    // Written until the connection to the peer is up and one comes through
+    let mut tries = 0;
+    let event = loop {
+        tries += 1;
+        peer.storage
+            .set("users/1".into(), tries.to_string())
+            .unwrap();
+        if let Ok(event) = rt::time::timeout(Duration::from_millis(100), stream.recv()).await {
+            break event.unwrap();
+        }
+        assert!(tries < 50, "the peer's changes never came");
+    };
+    match &event {
+        WatchEvent::Remote(Change::Set { key, .. }) => assert_eq!(key, "users/1"),
+        other => panic!("expected a change from the peer, got {other:?}"),
+    }
+    let sse = String::from_utf8(sse_event(event).to_vec()).unwrap();
+    assert!(!sse.contains("id:"), "{sse}");
+    let text = rt::time::timeout(Duration::from_secs(5), session.recv())
+        .await
+        .expect("timed out")
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(json["key"], "users/1");
+    assert!(json.get("seq").is_none(), "{text}");
+
+    // Long-polls on the key wake up
+    let registration = local.waiters.register("users/2");
+    let notified = registration.notified();
+    peer.storage.set("users/2".into(), "b".into()).unwrap();
+    rt::time::timeout(Duration::from_secs(5), notified)
+        .await
+        .expect("the waiter was never woken");
+    assert!(matches!(recv(&mut stream).await, WatchEvent::Remote(_)));
+
+    // Nothing went into our change log, so nothing went on to the follower
+    assert_eq!(local.storage.last_seq().unwrap(), 0);
+    let next = rt::time::timeout(Duration::from_millis(200), follower.next()).await;
+    assert!(next.is_err(), "a peer's change was sent on: {next:?}");
+}
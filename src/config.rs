@@ -0,0 +1,911 @@
+//! Typed configuration for tinybase.
+//!
+//! Values are loaded from a TOML file first, then overridden by env vars and
+//! finally by CLI flags. Clap handles the env and CLI layers for us, so any flag
+//! that was set (either way) wins over the file.
+
+use crate::channels::ChannelsConfig;
+use crate::limits::Limits;
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use std::{fmt, fs, io::BufReader, path::PathBuf};
+
+#[cfg(test)]
+mod tests;
+
+/// Config file that is used when `--config` is not passed and it exists
+pub const DEFAULT_CONFIG_FILE: &str = "tinybase.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Secret clients must put in the url (`/v0/{secret}`)
+    pub secret: Option<String>,
+    /// Secret for the admin API (`/admin/{secret}`), admin routes are disabled without it
+    pub admin_secret: Option<String>,
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub cache: CacheConfig,
+    pub changelog: ChangeLogConfig,
+    pub websocket: WebSocketConfig,
+    pub channels: ChannelsConfig,
+    pub cluster: ClusterConfig,
+    pub replication: ReplicationConfig,
+    pub webhooks: WebhooksConfig,
+    pub triggers: TriggersConfig,
+    pub database: DatabaseConfig,
+    pub limits: Limits,
+    pub cors: CorsConfig,
+    pub tls: Option<TlsConfig>,
+    pub log: LogConfig,
+    pub backup: BackupConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Number of HTTP workers
+    pub workers: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Settings for the `log` backend
+    pub log: LogStorageConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogStorageConfig {
+    /// Path to the log file
+    pub path: PathBuf,
+    /// fsync after every write. Turning it off is faster but a crash can lose the last writes.
+    pub sync: bool,
+    /// Compact once at least this many bytes are stale and they outweigh the live data
+    pub compact_min_bytes: u64,
+}
+
+/// Where keys are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// SQLite database from the `[database]` section
+    #[default]
+    Sqlite,
+    /// Kept in memory only, everything is lost on restart
+    Memory,
+    /// Append-only log file from the `[storage.log]` section
+    Log,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Bytes of keys and values the read cache can hold, 0 turns it off
+    pub max_bytes: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChangeLogConfig {
+    /// Seconds changes are kept for listeners to catch up on, 0 keeps them regardless of age
+    pub retain_secs: u64,
+    /// Most changes kept, older ones are dropped first. 0 means no limit.
+    pub max_entries: u64,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Messages a session can have waiting to be sent before `slow_consumer` kicks in
+    pub queue_size: usize,
+    pub slow_consumer: SlowConsumer,
+}
+
+/// What happens when a session's queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumer {
+    /// Drop the oldest waiting message
+    #[default]
+    DropOldest,
+    /// Replace the waiting change to the same key, drop the oldest message if there is none
+    Coalesce,
+    /// Close the connection with code 1008
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Secret peers use to stream this instance's changes (`/cluster/{secret}/changes`)
+    pub secret: Option<String>,
+    /// Other instances to get changes from, ie `http://10.0.0.2:8080`. They need the same secret.
+    pub peers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// Leader to follow, ie `http://10.0.0.1:8080`. Makes this instance a read-only follower.
+    /// The leader needs the same `cluster.secret`.
+    pub leader: Option<String>,
+    pub writes: FollowerWrites,
+}
+
+/// What a follower does with writes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum FollowerWrites {
+    /// Answer 403 with the leader's address
+    #[default]
+    Reject,
+    /// Send REST writes on to the leader and answer with its response
+    Proxy,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// Tries per delivery before it goes to the dead letters
+    pub max_attempts: u32,
+    /// Wait after the first failed try, doubled after every try after it
+    pub initial_backoff_ms: u64,
+    /// Longest wait between tries
+    pub max_backoff_secs: u64,
+    /// Seconds an endpoint has to answer
+    pub timeout_secs: u64,
+    /// Most deliveries being sent at once, the rest wait their turn
+    pub max_in_flight: usize,
+    /// Most deliveries waiting for one webhook, changes past it go straight to the dead letters
+    pub max_queued: usize,
+}
+
+/// Limits for trigger scripts. Memory isn't measured directly, it is bounded
+/// by how long strings, arrays and maps can get.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TriggersConfig {
+    /// Milliseconds a script can run before it is stopped
+    pub timeout_ms: u64,
+    /// Most operations a script can run, whatever the time
+    pub max_operations: u64,
+    /// Longest string a script can make, in bytes
+    pub max_string_size: usize,
+    /// Most items in an array or map
+    pub max_collection_size: usize,
+    /// Deepest function calls can nest
+    pub max_call_levels: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Path to the SQLite file
+    pub url: String,
+    /// Max number of pooled connections
+    pub pool_size: u32,
+    /// Connections the pool keeps open while idle, defaults to `pool_size`
+    pub min_idle: Option<u32>,
+    /// Seconds to wait for a free pooled connection before giving up
+    pub connection_timeout_secs: u64,
+    /// Milliseconds SQLite waits on a locked database before returning `database is locked`
+    pub busy_timeout_ms: u64,
+    /// Use write-ahead logging so readers don't block the writer
+    pub wal: bool,
+    /// `PRAGMA synchronous` level: off, normal, full or extra
+    pub synchronous: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, `*` allows any origin
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain
+    pub cert: PathBuf,
+    /// PEM encoded private key
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// env_logger filter, ie `info` or `tinybase=debug,actix_web=info`
+    pub level: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// Directory scheduled backups are written to
+    pub dir: Option<PathBuf>,
+    /// Seconds between scheduled backups, 0 turns them off
+    pub interval_secs: u64,
+    /// How many scheduled backups to keep, older ones are deleted
+    pub retain: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            workers: 2,
+        }
+    }
+}
+
+impl Default for LogStorageConfig {
+    fn default() -> Self {
+        LogStorageConfig {
+            path: PathBuf::from("tinybase.log"),
+            sync: true,
+            compact_min_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for ChangeLogConfig {
+    fn default() -> Self {
+        ChangeLogConfig {
+            retain_secs: 24 * 60 * 60,
+            max_entries: 100_000,
+        }
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            queue_size: 1024,
+            slow_consumer: SlowConsumer::DropOldest,
+        }
+    }
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            max_attempts: 8,
+            initial_backoff_ms: 1000,
+            max_backoff_secs: 600,
+            timeout_secs: 10,
+            max_in_flight: 64,
+            max_queued: 10_000,
+        }
+    }
+}
+
+impl Default for TriggersConfig {
+    fn default() -> Self {
+        TriggersConfig {
+            timeout_ms: 50,
+            max_operations: 1_000_000,
+            max_string_size: 4 * 1024 * 1024,
+            max_collection_size: 100_000,
+            max_call_levels: 32,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: "tinybase.db".to_string(),
+            pool_size: 10,
+            min_idle: None,
+            connection_timeout_secs: 30,
+            busy_timeout_ms: 5000,
+            wal: true,
+            synchronous: "normal".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl DatabaseConfig {
+    /// A database in `dir`, gone with it
+    pub fn for_test(dir: &tempfile::TempDir) -> DatabaseConfig {
+        DatabaseConfig {
+            url: dir
+                .path()
+                .join("tinybase.db")
+                .to_string_lossy()
+                .into_owned(),
+            ..DatabaseConfig::default()
+        }
+    }
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            dir: None,
+            interval_secs: 0,
+            retain: 7,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+        }
+    }
+}
+
+/// Flags that override the config file. Every flag can also be set with the env var next to it.
+#[derive(Debug, Default, Args)]
+pub struct ConfigArgs {
+    /// Path to a TOML config file
+    #[arg(long, short, env = "TINYBASE_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Secret clients must use in the url
+    #[arg(long, env = "SECRET", global = true, hide_env_values = true)]
+    pub secret: Option<String>,
+
+    /// Secret for the admin API
+    #[arg(long, env = "ADMIN_SECRET", global = true, hide_env_values = true)]
+    pub admin_secret: Option<String>,
+
+    /// Address to bind the HTTP server to
+    #[arg(long, env = "DB_HOST", global = true)]
+    pub host: Option<String>,
+
+    /// Port to bind the HTTP server to
+    #[arg(long, env = "DB_PORT", global = true)]
+    pub port: Option<u16>,
+
+    /// Number of HTTP workers
+    #[arg(long, env = "WORKERS", global = true)]
+    pub workers: Option<usize>,
+
+    /// Storage backend
+    #[arg(long, env = "STORAGE_BACKEND", global = true)]
+    pub storage: Option<StorageBackend>,
+
+    /// Path to the log file of the `log` storage backend
+    #[arg(long, env = "STORAGE_LOG_PATH", global = true)]
+    pub storage_log_path: Option<PathBuf>,
+
+    /// Bytes the read cache can hold, 0 turns it off
+    #[arg(long, env = "CACHE_MAX_BYTES", global = true)]
+    pub cache_max_bytes: Option<usize>,
+
+    /// Seconds changes are kept in the change log, 0 keeps them regardless of age
+    #[arg(long, env = "CHANGELOG_RETAIN_SECS", global = true)]
+    pub changelog_retain_secs: Option<u64>,
+
+    /// Most changes kept in the change log, 0 means no limit
+    #[arg(long, env = "CHANGELOG_MAX_ENTRIES", global = true)]
+    pub changelog_max_entries: Option<u64>,
+
+    /// Messages a WebSocket session can have waiting to be sent
+    #[arg(long, env = "WS_QUEUE_SIZE", global = true)]
+    pub ws_queue_size: Option<usize>,
+
+    /// What to do when a WebSocket session's queue is full
+    #[arg(long, env = "WS_SLOW_CONSUMER", global = true)]
+    pub ws_slow_consumer: Option<SlowConsumer>,
+
+    /// Largest message published to a channel, in bytes
+    #[arg(long, env = "CHANNEL_MAX_MESSAGE_SIZE", global = true)]
+    pub channel_max_message_size: Option<usize>,
+
+    /// Secret other instances use to stream this one's changes
+    #[arg(long, env = "CLUSTER_SECRET", global = true, hide_env_values = true)]
+    pub cluster_secret: Option<String>,
+
+    /// Instance to forward changes from, can be passed more than once
+    #[arg(long = "peer", env = "PEERS", value_delimiter = ',', global = true)]
+    pub peers: Vec<String>,
+
+    /// Leader to replicate from, makes this instance a read-only follower
+    #[arg(long, env = "LEADER", global = true)]
+    pub leader: Option<String>,
+
+    /// What a follower does with writes
+    #[arg(long, env = "FOLLOWER_WRITES", global = true)]
+    pub follower_writes: Option<FollowerWrites>,
+
+    /// Tries per webhook delivery before it goes to the dead letters
+    #[arg(long, env = "WEBHOOK_MAX_ATTEMPTS", global = true)]
+    pub webhook_max_attempts: Option<u32>,
+
+    /// Milliseconds a trigger script can run before it is stopped
+    #[arg(long, env = "TRIGGER_TIMEOUT_MS", global = true)]
+    pub trigger_timeout_ms: Option<u64>,
+
+    /// Path to the SQLite database
+    #[arg(long, env = "DATABASE_URL", global = true)]
+    pub database_url: Option<String>,
+
+    /// Max number of pooled database connections
+    #[arg(long, env = "POOL_SIZE", global = true)]
+    pub pool_size: Option<u32>,
+
+    /// Seconds to wait for a pooled connection
+    #[arg(long, env = "POOL_TIMEOUT_SECS", global = true)]
+    pub pool_timeout_secs: Option<u64>,
+
+    /// Milliseconds SQLite waits on a locked database
+    #[arg(long, env = "BUSY_TIMEOUT_MS", global = true)]
+    pub busy_timeout_ms: Option<u64>,
+
+    /// Max key size in bytes
+    #[arg(long, env = "MAX_KEY_SIZE", global = true)]
+    pub max_key_size: Option<usize>,
+
+    /// Max value size in bytes
+    #[arg(long, env = "MAX_VALUE_SIZE", global = true)]
+    pub max_value_size: Option<usize>,
+
+    /// Origin allowed to make CORS requests, can be passed more than once
+    #[arg(
+        long = "cors-origin",
+        env = "CORS_ORIGINS",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub cors_origins: Vec<String>,
+
+    /// PEM certificate chain, enables HTTPS together with `--tls-key`
+    #[arg(long, env = "TLS_CERT", global = true)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key, enables HTTPS together with `--tls-cert`
+    #[arg(long, env = "TLS_KEY", global = true)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Directory to write scheduled backups to
+    #[arg(long, env = "BACKUP_DIR", global = true)]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Seconds between scheduled backups, 0 turns them off
+    #[arg(long, env = "BACKUP_INTERVAL_SECS", global = true)]
+    pub backup_interval_secs: Option<u64>,
+
+    /// Number of scheduled backups to keep
+    #[arg(long, env = "BACKUP_RETAIN", global = true)]
+    pub backup_retain: Option<usize>,
+
+    /// Log filter, ie `info` or `tinybase=debug`
+    #[arg(long, env = "RUST_LOG", global = true)]
+    pub log_level: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read
+    Read(PathBuf, std::io::Error),
+    /// The config file is not valid TOML or has unknown/mistyped fields
+    Parse(PathBuf, toml::de::Error),
+    /// A value was loaded but does not make sense
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, error) => {
+                write!(f, "Could not read config file {}: {error}", path.display())
+            }
+            ConfigError::Parse(path, error) => {
+                write!(f, "Config file {} is not valid: {error}", path.display())
+            }
+            ConfigError::Invalid(reason) => write!(f, "Invalid config: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the config file (if there is one), applies overrides and validates the result
+    pub fn load(args: &ConfigArgs) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => {
+                let default_path = PathBuf::from(DEFAULT_CONFIG_FILE);
+                if default_path.exists() {
+                    Config::from_file(&default_path)?
+                } else {
+                    Config::default()
+                }
+            }
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &PathBuf) -> Result<Config, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|error| ConfigError::Read(path.clone(), error))?;
+        toml::from_str(&contents).map_err(|error| ConfigError::Parse(path.clone(), error))
+    }
+
+    /// Overrides values from the file with anything set by env var or flag
+    fn apply(&mut self, args: &ConfigArgs) {
+        if let Some(secret) = &args.secret {
+            self.secret = Some(secret.clone());
+        }
+        if let Some(admin_secret) = &args.admin_secret {
+            self.admin_secret = Some(admin_secret.clone());
+        }
+        if let Some(host) = &args.host {
+            self.server.host = host.clone();
+        }
+        if let Some(port) = args.port {
+            self.server.port = port;
+        }
+        if let Some(workers) = args.workers {
+            self.server.workers = workers;
+        }
+        if let Some(backend) = args.storage {
+            self.storage.backend = backend;
+        }
+        if let Some(path) = &args.storage_log_path {
+            self.storage.log.path = path.clone();
+        }
+        if let Some(max_bytes) = args.cache_max_bytes {
+            self.cache.max_bytes = max_bytes;
+        }
+        if let Some(retain_secs) = args.changelog_retain_secs {
+            self.changelog.retain_secs = retain_secs;
+        }
+        if let Some(max_entries) = args.changelog_max_entries {
+            self.changelog.max_entries = max_entries;
+        }
+        if let Some(queue_size) = args.ws_queue_size {
+            self.websocket.queue_size = queue_size;
+        }
+        if let Some(slow_consumer) = args.ws_slow_consumer {
+            self.websocket.slow_consumer = slow_consumer;
+        }
+        if let Some(max_message_size) = args.channel_max_message_size {
+            self.channels.max_message_size = max_message_size;
+        }
+        if let Some(cluster_secret) = &args.cluster_secret {
+            self.cluster.secret = Some(cluster_secret.clone());
+        }
+        if !args.peers.is_empty() {
+            self.cluster.peers = args.peers.clone();
+        }
+        if let Some(leader) = &args.leader {
+            self.replication.leader = Some(leader.clone());
+        }
+        if let Some(writes) = args.follower_writes {
+            self.replication.writes = writes;
+        }
+        if let Some(max_attempts) = args.webhook_max_attempts {
+            self.webhooks.max_attempts = max_attempts;
+        }
+        if let Some(timeout_ms) = args.trigger_timeout_ms {
+            self.triggers.timeout_ms = timeout_ms;
+        }
+        if let Some(url) = &args.database_url {
+            self.database.url = url.clone();
+        }
+        if let Some(pool_size) = args.pool_size {
+            self.database.pool_size = pool_size;
+        }
+        if let Some(pool_timeout_secs) = args.pool_timeout_secs {
+            self.database.connection_timeout_secs = pool_timeout_secs;
+        }
+        if let Some(busy_timeout_ms) = args.busy_timeout_ms {
+            self.database.busy_timeout_ms = busy_timeout_ms;
+        }
+        if let Some(max_key_size) = args.max_key_size {
+            self.limits.max_key_size = max_key_size;
+        }
+        if let Some(max_value_size) = args.max_value_size {
+            self.limits.max_value_size = max_value_size;
+        }
+        if !args.cors_origins.is_empty() {
+            self.cors.allowed_origins = args.cors_origins.clone();
+        }
+        match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => {
+                self.tls = Some(TlsConfig {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                })
+            }
+            (Some(cert), None) => {
+                let key = self
+                    .tls
+                    .as_ref()
+                    .map(|tls| tls.key.clone())
+                    .unwrap_or_default();
+                self.tls = Some(TlsConfig {
+                    cert: cert.clone(),
+                    key,
+                })
+            }
+            (None, Some(key)) => {
+                let cert = self
+                    .tls
+                    .as_ref()
+                    .map(|tls| tls.cert.clone())
+                    .unwrap_or_default();
+                self.tls = Some(TlsConfig {
+                    cert,
+                    key: key.clone(),
+                })
+            }
+            (None, None) => {}
+        }
+        if let Some(dir) = &args.backup_dir {
+            self.backup.dir = Some(dir.clone());
+        }
+        if let Some(interval_secs) = args.backup_interval_secs {
+            self.backup.interval_secs = interval_secs;
+        }
+        if let Some(retain) = args.backup_retain {
+            self.backup.retain = retain;
+        }
+        if let Some(level) = &args.log_level {
+            self.log.level = level.clone();
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.host.is_empty() {
+            return Err(ConfigError::Invalid(
+                "server.host can not be empty".to_string(),
+            ));
+        }
+        if self.server.workers == 0 {
+            return Err(ConfigError::Invalid(
+                "server.workers must be at least 1".to_string(),
+            ));
+        }
+        if self.storage.backend == StorageBackend::Log
+            && self.storage.log.path.as_os_str().is_empty()
+        {
+            return Err(ConfigError::Invalid(
+                "storage.log.path can not be empty".to_string(),
+            ));
+        }
+        if self.websocket.queue_size == 0 {
+            return Err(ConfigError::Invalid(
+                "websocket.queue_size must be at least 1".to_string(),
+            ));
+        }
+        if self.webhooks.max_attempts == 0
+            || self.webhooks.timeout_secs == 0
+            || self.webhooks.max_in_flight == 0
+            || self.webhooks.max_queued == 0
+        {
+            return Err(ConfigError::Invalid(
+                "webhooks.max_attempts, timeout_secs, max_in_flight and max_queued must be at least 1"
+                    .to_string(),
+            ));
+        }
+        if self.triggers.timeout_ms == 0
+            || self.triggers.max_operations == 0
+            || self.triggers.max_string_size == 0
+            || self.triggers.max_collection_size == 0
+            || self.triggers.max_call_levels == 0
+        {
+            return Err(ConfigError::Invalid(
+                "triggers.timeout_ms, max_operations, max_string_size, max_collection_size and max_call_levels must be at least 1"
+                    .to_string(),
+            ));
+        }
+        if self.channels.max_message_size == 0 {
+            return Err(ConfigError::Invalid(
+                "channels.max_message_size must be at least 1".to_string(),
+            ));
+        }
+        for rule in &self.channels.rules {
+            if rule.max_message_size == Some(0) {
+                return Err(ConfigError::Invalid(format!(
+                    "channels.rules max_message_size for {:?} must be at least 1",
+                    rule.prefix
+                )));
+            }
+            for token in rule.publish.iter().chain(&rule.subscribe) {
+                if token.is_empty() {
+                    return Err(ConfigError::Invalid(
+                        "channel tokens can not be empty".to_string(),
+                    ));
+                }
+                if Some(token) == self.secret.as_ref() || Some(token) == self.admin_secret.as_ref()
+                {
+                    return Err(ConfigError::Invalid(
+                        "channel tokens must be different from secret and admin_secret".to_string(),
+                    ));
+                }
+            }
+        }
+        match &self.cluster.secret {
+            Some(secret) if secret.is_empty() => {
+                return Err(ConfigError::Invalid(
+                    "cluster.secret can not be empty".to_string(),
+                ));
+            }
+            Some(secret)
+                if Some(secret) == self.secret.as_ref()
+                    || Some(secret) == self.admin_secret.as_ref() =>
+            {
+                return Err(ConfigError::Invalid(
+                    "cluster.secret must be different from secret and admin_secret".to_string(),
+                ));
+            }
+            None if !self.cluster.peers.is_empty() => {
+                return Err(ConfigError::Invalid(
+                    "cluster.peers needs cluster.secret".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        for peer in &self.cluster.peers {
+            if !["http://", "https://", "ws://", "wss://"]
+                .iter()
+                .any(|scheme| peer.starts_with(scheme))
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "cluster peer {peer} must start with http://, https://, ws:// or wss://"
+                )));
+            }
+        }
+        if let Some(leader) = &self.replication.leader {
+            if !leader.starts_with("http://") && !leader.starts_with("https://") {
+                return Err(ConfigError::Invalid(format!(
+                    "replication.leader {leader} must start with http:// or https://"
+                )));
+            }
+            if self.cluster.secret.is_none() {
+                return Err(ConfigError::Invalid(
+                    "replication.leader needs cluster.secret".to_string(),
+                ));
+            }
+        }
+        if self.database.url.is_empty() {
+            return Err(ConfigError::Invalid(
+                "database.url can not be empty".to_string(),
+            ));
+        }
+        if self.database.pool_size == 0 {
+            return Err(ConfigError::Invalid(
+                "database.pool_size must be at least 1".to_string(),
+            ));
+        }
+        if let Some(min_idle) = self.database.min_idle {
+            if min_idle > self.database.pool_size {
+                return Err(ConfigError::Invalid(
+                    "database.min_idle can not be bigger than database.pool_size".to_string(),
+                ));
+            }
+        }
+        if self.database.connection_timeout_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "database.connection_timeout_secs must be at least 1".to_string(),
+            ));
+        }
+        let synchronous = self.database.synchronous.to_lowercase();
+        if !["off", "normal", "full", "extra"].contains(&synchronous.as_str()) {
+            return Err(ConfigError::Invalid(format!(
+                "database.synchronous must be off, normal, full or extra, not {}",
+                self.database.synchronous
+            )));
+        }
+        if self.limits.max_key_size == 0 || self.limits.max_value_size == 0 {
+            return Err(ConfigError::Invalid(
+                "limits.max_key_size and limits.max_value_size must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(secret) = &self.secret {
+            if secret.is_empty() {
+                return Err(ConfigError::Invalid("secret can not be empty".to_string()));
+            }
+        }
+        if let Some(admin_secret) = &self.admin_secret {
+            if admin_secret.is_empty() {
+                return Err(ConfigError::Invalid(
+                    "admin_secret can not be empty".to_string(),
+                ));
+            }
+            if Some(admin_secret) == self.secret.as_ref() {
+                return Err(ConfigError::Invalid(
+                    "admin_secret must be different from secret".to_string(),
+                ));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            if tls.cert.as_os_str().is_empty() || tls.key.as_os_str().is_empty() {
+                return Err(ConfigError::Invalid(
+                    "tls needs both a cert and a key".to_string(),
+                ));
+            }
+            for path in [&tls.cert, &tls.key] {
+                if !path.exists() {
+                    return Err(ConfigError::Invalid(format!(
+                        "tls file {} does not exist",
+                        path.display()
+                    )));
+                }
+            }
+        }
+        if self.backup.interval_secs > 0 {
+            if self.backup.dir.is_none() {
+                return Err(ConfigError::Invalid(
+                    "backup.dir must be set to schedule backups".to_string(),
+                ));
+            }
+            if self.backup.retain == 0 {
+                return Err(ConfigError::Invalid(
+                    "backup.retain must be at least 1".to_string(),
+                ));
+            }
+        }
+        if self.log.level.trim().is_empty() {
+            return Err(ConfigError::Invalid(
+                "log.level can not be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl TlsConfig {
+    /// Reads the PEM files into a rustls config for `HttpServer::bind_rustls`
+    pub fn load_rustls(&self) -> Result<rustls::ServerConfig, ConfigError> {
+        let invalid = |reason: String| ConfigError::Invalid(reason);
+
+        let cert_file = fs::File::open(&self.cert)
+            .map_err(|error| ConfigError::Read(self.cert.clone(), error))?;
+        let certs: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+            .map_err(|error| ConfigError::Read(self.cert.clone(), error))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+        if certs.is_empty() {
+            return Err(invalid(format!(
+                "no certificates found in {}",
+                self.cert.display()
+            )));
+        }
+
+        let key_file = fs::File::open(&self.key)
+            .map_err(|error| ConfigError::Read(self.key.clone(), error))?;
+        let key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+            .map_err(|error| ConfigError::Read(self.key.clone(), error))?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or_else(|| invalid(format!("no private key found in {}", self.key.display())))?;
+
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|error| invalid(format!("tls cert and key do not work together: {error}")))
+    }
+}
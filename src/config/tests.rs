@@ -0,0 +1,224 @@
+//! Loading the config from a file, env vars and flags, and rejecting what doesn't make sense
+
+use super::*;
+use clap::Parser;
+
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    args: ConfigArgs,
+}
+
+/// A change to the default config and what validating it should say
+type Case = (fn(&mut Config), &'static str);
+
+fn invalid(config: &Config) -> String {
+    match config.validate() {
+        Err(ConfigError::Invalid(reason)) => reason,
+        other => panic!("expected the config to be invalid, got {other:?}"),
+    }
+}
+
+#[test]
+fn flags_beat_env_vars_beat_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tinybase.toml");
+    fs::write(
+        &path,
+        r#"
+        [server]
+        host = "10.0.0.1"
+        port = 1000
+        workers = 3
+
+        [websocket]
+        queue_size = 7
+        "#,
+    )
+    .unwrap();
+
+    // The only test that sets these, so they can't leak into another one
+    std::env::set_var("DB_PORT", "2000");
+    std::env::set_var("WORKERS", "5");
+    let cli = Cli::try_parse_from([
+        "tinybase",
+        "--config",
+        path.to_str().unwrap(),
+        "--port",
+        "3000",
+    ]);
+    std::env::remove_var("DB_PORT");
+    std::env::remove_var("WORKERS");
+
+    let config = Config::load(&cli.unwrap().args).unwrap();
+    assert_eq!(config.server.port, 3000);
+    assert_eq!(config.server.workers, 5);
+    assert_eq!(config.server.host, "10.0.0.1");
+    assert_eq!(config.websocket.queue_size, 7);
+    // Sections the file leaves out keep their defaults
+    assert_eq!(
+        config.database.pool_size,
+        DatabaseConfig::default().pool_size
+    );
+}
+
+#[test]
+fn bad_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let args = ConfigArgs {
+        config: Some(dir.path().join("missing.toml")),
+        ..ConfigArgs::default()
+    };
+    assert!(matches!(Config::load(&args), Err(ConfigError::Read(..))));
+
+    let path = dir.path().join("tinybase.toml");
+    let args = ConfigArgs {
+        config: Some(path.clone()),
+        ..ConfigArgs::default()
+    };
+    fs::write(&path, "[server]\nprot = 8080\n").unwrap();
+    assert!(matches!(Config::load(&args), Err(ConfigError::Parse(..))));
+    fs::write(&path, "[server]\nport = \"8080\"\n").unwrap();
+    assert!(matches!(Config::load(&args), Err(ConfigError::Parse(..))));
+}
+
+#[test]
+fn the_defaults_are_valid() {
+    Config::default().validate().unwrap();
+}
+
+#[test]
+fn zero_sizes_are_rejected() {
+    let cases: Vec<Case> = vec![
+        (|c| c.server.workers = 0, "server.workers"),
+        (|c| c.websocket.queue_size = 0, "websocket.queue_size"),
+        (|c| c.webhooks.max_in_flight = 0, "webhooks.max_attempts"),
+        (|c| c.webhooks.max_queued = 0, "webhooks.max_attempts"),
+        (|c| c.triggers.timeout_ms = 0, "triggers.timeout_ms"),
+        (|c| c.triggers.max_call_levels = 0, "triggers.timeout_ms"),
+        (
+            |c| c.channels.max_message_size = 0,
+            "channels.max_message_size",
+        ),
+        (|c| c.database.pool_size = 0, "database.pool_size"),
+        (
+            |c| c.database.connection_timeout_secs = 0,
+            "database.connection_timeout_secs",
+        ),
+        (|c| c.limits.max_key_size = 0, "limits.max_key_size"),
+        (|c| c.limits.max_value_size = 0, "limits.max_key_size"),
+    ];
+    for (change, field) in cases {
+        let mut config = Config::default();
+        change(&mut config);
+        let reason = invalid(&config);
+        assert!(reason.starts_with(field), "{field}: {reason}");
+    }
+}
+
+#[test]
+fn values_that_dont_fit_together_are_rejected() {
+    let cases: Vec<Case> = vec![
+        (|c| c.server.host.clear(), "server.host can not be empty"),
+        (
+            |c| {
+                c.storage.backend = StorageBackend::Log;
+                c.storage.log.path = PathBuf::new();
+            },
+            "storage.log.path can not be empty",
+        ),
+        (
+            |c| c.replication.leader = Some("http://10.0.0.1:8080".to_string()),
+            "replication.leader needs cluster.secret",
+        ),
+        (
+            |c| {
+                c.cluster.secret = Some("cluster".to_string());
+                c.replication.leader = Some("10.0.0.1:8080".to_string());
+            },
+            "must start with http:// or https://",
+        ),
+        (
+            |c| c.cluster.peers = vec!["http://10.0.0.2:8080".to_string()],
+            "cluster.peers needs cluster.secret",
+        ),
+        (
+            |c| {
+                c.cluster.secret = Some("cluster".to_string());
+                c.cluster.peers = vec!["10.0.0.2:8080".to_string()];
+            },
+            "must start with http://, https://, ws:// or wss://",
+        ),
+        (
+            |c| {
+                c.secret = Some("same".to_string());
+                c.cluster.secret = Some("same".to_string());
+            },
+            "cluster.secret must be different",
+        ),
+        (
+            |c| {
+                c.secret = Some("same".to_string());
+                c.admin_secret = Some("same".to_string());
+            },
+            "admin_secret must be different from secret",
+        ),
+        (
+            |c| c.secret = Some(String::new()),
+            "secret can not be empty",
+        ),
+        (
+            |c| c.database.min_idle = Some(11),
+            "database.min_idle can not be bigger",
+        ),
+        (
+            |c| c.database.synchronous = "sometimes".to_string(),
+            "database.synchronous must be",
+        ),
+        (
+            |c| c.backup.interval_secs = 60,
+            "backup.dir must be set to schedule backups",
+        ),
+        (
+            |c| {
+                c.backup.interval_secs = 60;
+                c.backup.dir = Some(PathBuf::from("backups"));
+                c.backup.retain = 0;
+            },
+            "backup.retain must be at least 1",
+        ),
+        (
+            |c| c.log.level = " ".to_string(),
+            "log.level can not be empty",
+        ),
+    ];
+    for (change, expected) in cases {
+        let mut config = Config::default();
+        change(&mut config);
+        let reason = invalid(&config);
+        assert!(reason.contains(expected), "{expected}: {reason}");
+    }
+}
+
+#[test]
+fn tls_needs_a_cert_and_a_key_that_exist() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = dir.path().join("cert.pem");
+    fs::write(&cert, "").unwrap();
+
+    let mut config = Config::default();
+    config.apply(&ConfigArgs {
+        tls_cert: Some(cert),
+        ..ConfigArgs::default()
+    });
+    assert_eq!(invalid(&config), "tls needs both a cert and a key");
+
+    config.apply(&ConfigArgs {
+        tls_key: Some(dir.path().join("key.pem")),
+        ..ConfigArgs::default()
+    });
+    assert!(invalid(&config).contains("key.pem does not exist"));
+
+    fs::write(dir.path().join("key.pem"), "").unwrap();
+    config.validate().unwrap();
+}
@@ -0,0 +1,311 @@
+use crate::actors::outbox::WsMetrics;
+use crate::controllers::key_controller::db_error_status;
+use crate::replication::Follower;
+use crate::storage::{cache::CacheMetrics, transfer::*, Storage};
+use actix_files::NamedFile;
+use actix_web::{
+    get,
+    http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType},
+    post,
+    web::{self, Bytes, Query},
+    HttpRequest, HttpResponse,
+};
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    prefix: Option<String>,
+    format: Option<TransferFormat>,
+}
+
+/// Where the export stream is at between pages
+struct ExportState {
+    storage: web::Data<dyn Storage>,
+    prefix: String,
+    exporter: Exporter,
+    last_key: Option<String>,
+    done: bool,
+}
+
+#[get("/export")]
+pub async fn export(storage: web::Data<dyn Storage>, params: Query<ExportParams>) -> HttpResponse {
+    let params = params.into_inner();
+    let format = params.format.unwrap_or_default();
+    let exporter = Exporter::new(format);
+    let start = Bytes::from(exporter.start());
+    let state = ExportState {
+        storage,
+        prefix: params.prefix.unwrap_or_default(),
+        exporter,
+        last_key: None,
+        done: false,
+    };
+
+    let pages = stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+        let storage = state.storage.clone();
+        let prefix = state.prefix.clone();
+        let last_key = state.last_key.clone();
+        let page = web::block(move || {
+            export_page(
+                storage.as_ref(),
+                &prefix,
+                last_key.as_deref(),
+                DEFAULT_BATCH_SIZE,
+            )
+        })
+        .await;
+
+        match page {
+            Ok(Ok(page)) => {
+                let mut chunk = String::new();
+                for entry in &page {
+                    chunk.push_str(&state.exporter.entry(entry));
+                }
+                match page.last() {
+                    Some(last) if page.len() == DEFAULT_BATCH_SIZE => {
+                        state.last_key = Some(last.key.clone())
+                    }
+                    _ => {
+                        state.done = true;
+                        chunk.push_str(&state.exporter.finish());
+                    }
+                }
+                Some((Ok(Bytes::from(chunk)), state))
+            }
+            Ok(Err(error)) => {
+                log::error!("Export failed: {error}");
+                state.done = true;
+                Some((
+                    Err(actix_web::error::ErrorInternalServerError(error)),
+                    state,
+                ))
+            }
+            Err(error) => {
+                state.done = true;
+                Some((
+                    Err(actix_web::error::ErrorInternalServerError(error)),
+                    state,
+                ))
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(stream::once(async move { Ok(start) }).chain(pages))
+}
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+    format: Option<TransferFormat>,
+    on_conflict: Option<ConflictPolicy>,
+    batch_size: Option<usize>,
+}
+
+/// Writes the queued batch to storage
+async fn flush_import(
+    storage: &web::Data<dyn Storage>,
+    importer: &mut Importer,
+) -> Result<(), HttpResponse> {
+    let batch = importer.take_batch();
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let policy = importer.policy();
+    let storage = storage.clone();
+    let result = web::block(move || import_batch(storage.as_ref(), &batch, policy))
+        .await
+        .unwrap_or_else(|error| Err(ImportError::Db(Box::new(error))));
+    importer
+        .record_result(result)
+        .map_err(import_error_response)
+}
+
+fn import_error_response(error: ImportError) -> HttpResponse {
+    match error {
+        ImportError::Parse { .. } => HttpResponse::BadRequest().body(error.to_string()),
+        ImportError::Conflict { .. } => HttpResponse::Conflict().body(error.to_string()),
+        ImportError::Db(ref db_error) => {
+            HttpResponse::build(db_error_status(db_error)).body(error.to_string())
+        }
+    }
+}
+
+#[post("/import")]
+pub async fn import(
+    storage: web::Data<dyn Storage>,
+    params: Query<ImportParams>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    let params = params.into_inner();
+    let mut importer = Importer::new(
+        params.on_conflict.unwrap_or_default(),
+        params.batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+    );
+
+    match params.format.unwrap_or_default() {
+        TransferFormat::Jsonl => {
+            // Lines can be split across chunks, so keep the unfinished tail around
+            let mut buffer: Vec<u8> = Vec::new();
+            let mut line_number = 0;
+            loop {
+                let chunk = match payload.next().await {
+                    Some(Ok(chunk)) => Some(chunk),
+                    Some(Err(error)) => return HttpResponse::BadRequest().body(error.to_string()),
+                    None => None,
+                };
+                let finished = chunk.is_none();
+                if let Some(chunk) = chunk {
+                    buffer.extend_from_slice(&chunk);
+                }
+
+                let mut lines: Vec<Vec<u8>> = Vec::new();
+                while let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+                    lines.push(buffer.drain(..=newline).collect());
+                }
+                if finished && !buffer.is_empty() {
+                    lines.push(std::mem::take(&mut buffer));
+                }
+
+                for line in lines {
+                    line_number += 1;
+                    let line = match String::from_utf8(line) {
+                        Ok(line) => line,
+                        Err(_) => {
+                            return import_error_response(ImportError::Parse {
+                                line: line_number,
+                                reason: "not valid UTF-8".to_string(),
+                            })
+                        }
+                    };
+                    match parse_jsonl_line(line_number, &line) {
+                        Ok(Some(record)) => {
+                            if importer.push(record) {
+                                if let Err(response) = flush_import(&storage, &mut importer).await {
+                                    return response;
+                                }
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(error) => return import_error_response(error),
+                    }
+                }
+
+                if finished {
+                    break;
+                }
+            }
+        }
+        TransferFormat::Json => {
+            let mut body = web::BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                match chunk {
+                    Ok(chunk) => body.extend_from_slice(&chunk),
+                    Err(error) => return HttpResponse::BadRequest().body(error.to_string()),
+                }
+            }
+            let records = match parse_json_object(&body) {
+                Ok(records) => records,
+                Err(error) => return import_error_response(error),
+            };
+            for record in records {
+                if importer.push(record) {
+                    if let Err(response) = flush_import(&storage, &mut importer).await {
+                        return response;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Err(response) = flush_import(&storage, &mut importer).await {
+        return response;
+    }
+    HttpResponse::Ok().json(importer.summary)
+}
+
+/// Streams a consistent snapshot of the database as a download
+#[get("/backup")]
+pub async fn download_backup(req: HttpRequest, storage: web::Data<dyn Storage>) -> HttpResponse {
+    let path = std::env::temp_dir().join(format!("tinybase-backup-{}.db", Uuid::new_v4()));
+    let result = web::block(move || {
+        storage.backup_to(&path)?;
+        let file = std::fs::File::open(&path)?;
+        // The open handle keeps the data around, so the temp file is gone once the download ends
+        std::fs::remove_file(&path)?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(file)
+    })
+    .await;
+
+    let file = match result {
+        Ok(Ok(file)) => file,
+        Ok(Err(error)) => {
+            log::error!("Backup failed: {error}");
+            return HttpResponse::InternalServerError().body(error.to_string());
+        }
+        Err(error) => return HttpResponse::InternalServerError().body(error.to_string()),
+    };
+    match NamedFile::from_file(file, "tinybase-backup.db") {
+        Ok(named_file) => named_file
+            .set_content_type(ContentType::octet_stream().0)
+            .set_content_disposition(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename("tinybase-backup.db".to_string())],
+            })
+            .into_response(&req),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BackupParams {
+    /// Path on the server to write the backup to
+    path: PathBuf,
+}
+
+/// Writes a consistent snapshot of the database to a path on the server
+#[post("/backup")]
+pub async fn write_backup(
+    storage: web::Data<dyn Storage>,
+    params: Query<BackupParams>,
+) -> HttpResponse {
+    let path = params.into_inner().path;
+    let written_path = path.clone();
+    let result = web::block(move || storage.backup_to(&path)).await;
+
+    match result {
+        Ok(Ok(())) => HttpResponse::Ok().json(serde_json::json!({ "path": written_path })),
+        Ok(Err(error)) => HttpResponse::BadRequest().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+/// Hit, miss and size counters of the read cache
+#[get("/cache")]
+pub async fn cache_stats(metrics: Option<web::Data<CacheMetrics>>) -> HttpResponse {
+    match metrics {
+        Some(metrics) => HttpResponse::Ok().json(metrics.stats()),
+        None => HttpResponse::NotFound().body("The read cache is turned off"),
+    }
+}
+
+/// How far a follower is behind its leader
+#[get("/replication")]
+pub async fn replication_status(follower: Option<web::Data<Follower>>) -> HttpResponse {
+    match follower {
+        Some(follower) => HttpResponse::Ok().json(follower.status().await),
+        None => HttpResponse::NotFound().body("This instance is not a follower"),
+    }
+}
+
+/// Messages dropped or coalesced for WebSocket sessions that read too slowly, and sessions disconnected for it
+#[get("/websocket")]
+pub async fn websocket_stats(metrics: web::Data<WsMetrics>) -> HttpResponse {
+    HttpResponse::Ok().json(metrics.stats())
+}
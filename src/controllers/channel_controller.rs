@@ -0,0 +1,73 @@
+use crate::actors::ws_actor::{ClientWebSocketConnection, Publish};
+use crate::channels::{Access, ChannelsConfig, Scope};
+use actix::Addr;
+use actix_web::{post, web, HttpResponse};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct ChannelPath {
+    channel: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenChannelPath {
+    token: String,
+    channel: String,
+}
+
+/// Sends the body to the channel's subscribers, answers how many got it
+#[post("/channels/{channel}")]
+pub async fn publish(
+    server: web::Data<Addr<ClientWebSocketConnection>>,
+    channels: web::Data<ChannelsConfig>,
+    path: web::Path<ChannelPath>,
+    body: String,
+) -> HttpResponse {
+    send(
+        &server,
+        &channels,
+        Access::Secret,
+        path.into_inner().channel,
+        body,
+    )
+    .await
+}
+
+/// `publish` for channel tokens, the token has to be allowed to publish to the channel
+#[post("/{channel}")]
+pub async fn publish_with_token(
+    server: web::Data<Addr<ClientWebSocketConnection>>,
+    channels: web::Data<ChannelsConfig>,
+    path: web::Path<TokenChannelPath>,
+    body: String,
+) -> HttpResponse {
+    let TokenChannelPath { token, channel } = path.into_inner();
+    if !channels.knows(&token) {
+        return HttpResponse::Unauthorized().body("You do not have the correct secret");
+    }
+    send(&server, &channels, Access::Token(token), channel, body).await
+}
+
+async fn send(
+    server: &Addr<ClientWebSocketConnection>,
+    channels: &ChannelsConfig,
+    access: Access,
+    channel: String,
+    message: String,
+) -> HttpResponse {
+    if let Err(error) = channels
+        .check(&access, &channel, Scope::Publish)
+        .and_then(|_| channels.check_message(&channel, &message))
+    {
+        return error.to_response();
+    }
+    let message = Publish {
+        from: None,
+        channel,
+        message,
+    };
+    match server.send(message).await {
+        Ok(delivered) => HttpResponse::Ok().json(serde_json::json!({ "delivered": delivered })),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
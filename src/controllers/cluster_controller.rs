@@ -0,0 +1,52 @@
+use crate::actors::peer::PeerSession;
+use crate::cluster::NodeId;
+use crate::controllers::watch_controller::sse_response;
+use crate::replication::{self, LeaderSeq};
+use crate::storage::Storage;
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use std::time::Instant;
+
+/// WebSocket that streams the changes committed on this instance to a peer
+#[get("/changes")]
+pub async fn changes(
+    req: HttpRequest,
+    stream: web::Payload,
+    storage: web::Data<dyn Storage>,
+    node: web::Data<NodeId>,
+) -> Result<HttpResponse, Error> {
+    ws::WsResponseBuilder::new(
+        PeerSession {
+            node: node.0,
+            hb: Instant::now(),
+            changes: Some(storage.watch()),
+        },
+        &req,
+        stream,
+    )
+    .start()
+}
+
+/// Keys and changes for a follower, see `replication`. `Last-Event-ID` resumes after that change,
+/// without it the feed starts with every key.
+#[get("/replicate")]
+pub async fn replicate(req: HttpRequest, storage: web::Data<dyn Storage>) -> HttpResponse {
+    let after = match req.headers().get("Last-Event-ID") {
+        Some(header) => match header.to_str().map(|id| id.trim().parse::<u64>()) {
+            Ok(Ok(id)) => Some(id),
+            _ => return HttpResponse::BadRequest().body("Last-Event-ID must be a number"),
+        },
+        None => None,
+    };
+    sse_response(replication::feed(storage.into_inner(), after))
+}
+
+/// Sequence number of the last change committed here, followers use it to work out their lag
+#[get("/seq")]
+pub async fn seq(storage: web::Data<dyn Storage>) -> HttpResponse {
+    match web::block(move || storage.last_seq()).await {
+        Ok(Ok(seq)) => HttpResponse::Ok().json(LeaderSeq { seq }),
+        Ok(Err(error)) => HttpResponse::InternalServerError().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
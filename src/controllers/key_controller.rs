@@ -1,20 +1,23 @@
 //use urlencoding::encode;
-use crate::data_access::actions::*;
+use crate::limits::Limits;
+use crate::storage::{
+    read_only::ReadOnly, Busy, Storage, StorageError, TxAborted, TxOp, Versioned,
+};
+use crate::triggers::TriggerRejected;
+use crate::waiters::Waiters;
 use actix_web::web;
 use actix_web::{
-    delete, get, post,
-    web::{block, Path, Query},
-    HttpResponse,
-};
-use diesel::{
-    r2d2::{self, ConnectionManager},
-    SqliteConnection,
+    delete, get,
+    http::StatusCode,
+    post,
+    web::{Path, Query},
+    HttpResponse, HttpResponseBuilder,
 };
 use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::Instant;
 use urlencoding::{decode, encode};
 
-type DbPool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
-
 #[derive(Deserialize)]
 pub struct KeyValue {
     key: String,
@@ -22,34 +25,50 @@ pub struct KeyValue {
 }
 
 #[post("/{key}={value}")]
-pub async fn url_create_key(pool: web::Data<DbPool>, info: Path<KeyValue>) -> HttpResponse {
+pub async fn url_create_key(
+    storage: web::Data<dyn Storage>,
+    limits: web::Data<Limits>,
+    info: Path<KeyValue>,
+) -> HttpResponse {
     let key_value = info.into_inner();
     let key = key_value.key;
     let value = key_value.value;
-    let _ = block(move || {
-        let mut conn = pool.get().expect("Could not get instance of the DB");
-        insert_new_entry(&mut conn, key, value)
-    })
-    .await;
-    HttpResponse::Ok().body(format!(""))
+    if let Err(error) = limits.check(&key, &value) {
+        return error.to_response();
+    }
+    match web::block(move || storage.set(key, value)).await {
+        Ok(Ok(())) => HttpResponse::Ok().finish(),
+        Ok(Err(error)) => db_error_response(error),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
 }
 
 #[post("")]
-pub async fn create_key(pool: web::Data<DbPool>, body: String) -> HttpResponse {
-    let body_split: Vec<&str> = body.split("=").collect();
-    let key = body_split.get(0);
-    let value = body_split.get(1);
-    if let (Some(unwrapped_key), Some(unwrapped_value)) = (key, value) {
-        let decoded_key = decode(unwrapped_key).to_owned().unwrap().to_string();
-        let decoded_value = decode(unwrapped_value).to_owned().unwrap().to_string();
-        let _ = block(move || {
-            let mut conn = pool.get().expect("Could not get instance of the DB");
-            insert_new_entry(&mut conn, decoded_key, decoded_value)
-        })
-        .await;
-        return HttpResponse::Ok().body(format!(""));
-    } else {
-        return HttpResponse::BadRequest().into();
+pub async fn create_key(
+    storage: web::Data<dyn Storage>,
+    limits: web::Data<Limits>,
+    body: String,
+) -> HttpResponse {
+    // Replit DB bodies are `key=value`, the value can have `=` in it
+    let (key, value) = match body.split_once('=') {
+        Some(pair) => pair,
+        None => return HttpResponse::BadRequest().body("Body must be key=value"),
+    };
+    let decoded_key = match decode(key) {
+        Ok(decoded) => decoded.into_owned(),
+        Err(_) => return HttpResponse::BadRequest().body("Key is not valid UTF-8"),
+    };
+    let decoded_value = match decode(value) {
+        Ok(decoded) => decoded.into_owned(),
+        Err(_) => return HttpResponse::BadRequest().body("Value is not valid UTF-8"),
+    };
+    if let Err(error) = limits.check(&decoded_key, &decoded_value) {
+        return error.to_response();
+    }
+    match web::block(move || storage.set(decoded_key, decoded_value)).await {
+        Ok(Ok(())) => HttpResponse::Ok().finish(),
+        Ok(Err(error)) => db_error_response(error),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
     }
 }
 
@@ -58,43 +77,116 @@ pub struct KeyPath {
     key: String,
 }
 
+#[derive(Deserialize)]
+pub struct GetParams {
+    /// Long-poll for up to this long, ie `30s`, `500ms` or `2m`
+    wait: Option<String>,
+    /// Version the client already has, defaults to the current one
+    after_version: Option<u64>,
+}
+
+/// Longest a long-poll can wait
+const MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// Parses `30s`, `500ms`, `2m` or a plain number of seconds
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => duration.split_at(index),
+        None => (duration, "s"),
+    };
+    let number: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+/// The value with its version in `X-Version`, a missing key is version 0 with an empty body
+fn versioned_response(mut response: HttpResponseBuilder, entry: Option<Versioned>) -> HttpResponse {
+    match entry {
+        None => response.insert_header((VERSION_HEADER, 0)).finish(),
+        Some(entry) => response
+            .insert_header((VERSION_HEADER, entry.version))
+            .body(entry.value),
+    }
+}
+
+const VERSION_HEADER: &str = "X-Version";
+
+/// Reads a key. With `wait` it holds the request until the key's version differs from
+/// `after_version` (it was written or deleted), or answers 304 once `wait` runs out.
 #[get("/{key}")]
-pub async fn get_key(pool: web::Data<DbPool>, params: Path<KeyPath>) -> HttpResponse {
-    let params = params.into_inner();
-    let key = params.key;
-    let result = web::block(move || {
-        let mut conn = pool.get().unwrap();
-        get_entry(&mut conn, key)
-    })
-    .await
-    .map_err(actix_web::error::ErrorInternalServerError)
-    .unwrap();
-
-    match result {
-        Ok(entry) => match entry {
-            None => HttpResponse::Ok().body(format!("")),
-            Some(unwrapped_entry) => HttpResponse::Ok().body(format!("{}", unwrapped_entry.value)),
-        },
-        Err(_) => HttpResponse::Ok().body(format!("")),
+pub async fn get_key(
+    storage: web::Data<dyn Storage>,
+    waiters: web::Data<Waiters>,
+    params: Path<KeyPath>,
+    query: Query<GetParams>,
+) -> HttpResponse {
+    let key = params.into_inner().key;
+    let query = query.into_inner();
+    let wait = match query.wait.as_deref().map(parse_duration) {
+        None => None,
+        Some(Some(wait)) if wait <= MAX_WAIT => Some(wait),
+        Some(_) => {
+            return HttpResponse::BadRequest()
+                .body("wait must be a duration like 30s, 500ms or 2m, at most 5m")
+        }
+    };
+
+    let wait = match wait {
+        Some(wait) => wait,
+        None => {
+            let result = web::block(move || storage.get_versioned(&key)).await;
+            return match result {
+                Ok(Ok(entry)) => versioned_response(HttpResponse::Ok(), entry),
+                Ok(Err(error)) => db_error_response(error),
+                Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+            };
+        }
+    };
+
+    let deadline = Instant::now() + wait;
+    // Unregisters when dropped, also if the client goes away and this future with it
+    let registration = waiters.register(&key);
+    let mut after_version = query.after_version;
+    loop {
+        let notified = registration.notified();
+        let reader = storage.clone();
+        let read_key = key.clone();
+        let entry = match web::block(move || reader.get_versioned(&read_key)).await {
+            Ok(Ok(entry)) => entry,
+            Ok(Err(error)) => break db_error_response(error),
+            Err(error) => break HttpResponse::InternalServerError().body(error.to_string()),
+        };
+        let version = entry.as_ref().map_or(0, |entry| entry.version);
+        match after_version {
+            Some(after) if after != version => break versioned_response(HttpResponse::Ok(), entry),
+            Some(_) => {}
+            None => after_version = Some(version),
+        }
+        if tokio::time::timeout_at(deadline, notified).await.is_err() {
+            break HttpResponse::NotModified()
+                .insert_header((VERSION_HEADER, version))
+                .finish();
+        }
     }
 }
 
 #[delete("/{key}")]
-pub async fn delete_key(pool: web::Data<DbPool>, params: Path<KeyPath>) -> HttpResponse {
+pub async fn delete_key(storage: web::Data<dyn Storage>, params: Path<KeyPath>) -> HttpResponse {
     let params = params.into_inner();
-    let delete_results = web::block(move || {
-        let mut conn = pool.get().unwrap();
-        delete_by_key(&mut conn, params.key)
-    })
-    .await
-    .map_err(actix_web::error::ErrorInternalServerError);
+    let delete_results = web::block(move || storage.delete(&params.key)).await;
 
     match delete_results {
-        Ok(did_it_delete) => match did_it_delete {
-            true => HttpResponse::NoContent().body(format!("")),
-            false => HttpResponse::NotFound().body(format!("")),
+        Ok(Ok(did_it_delete)) => match did_it_delete {
+            true => HttpResponse::NoContent().finish(),
+            false => HttpResponse::NotFound().finish(),
         },
-        Err(_) => HttpResponse::BadRequest().body(format!("")),
+        Ok(Err(error)) => db_error_response(error),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
     }
 }
 
@@ -105,27 +197,19 @@ pub struct KeyList {
 }
 
 #[get("")]
-pub async fn list_keys(pool: web::Data<DbPool>, params: Query<KeyList>) -> HttpResponse {
+pub async fn list_keys(storage: web::Data<dyn Storage>, params: Query<KeyList>) -> HttpResponse {
     let params = params.into_inner();
 
     let prefix = match params.prefix {
         Some(param_prefix) => param_prefix,
-        None => return HttpResponse::Ok().body(format!("")),
-    };
-    let encode_keys = match params.encode {
-        Some(param_encode) => param_encode,
-        None => false,
+        None => return HttpResponse::Ok().finish(),
     };
+    let encode_keys = params.encode.unwrap_or_default();
 
-    let results = web::block(move || {
-        let mut conn = pool.get().unwrap();
-        get_keys_by_prefix(&mut conn, prefix)
-    })
-    .await
-    .map_err(actix_web::error::ErrorInternalServerError);
+    let results = web::block(move || storage.scan(&prefix)).await;
 
     match results {
-        Ok(keys) => match encode_keys {
+        Ok(Ok(keys)) => match encode_keys {
             true => {
                 let mut encoded_keys: Vec<String> = Vec::new();
                 for key in keys {
@@ -133,15 +217,79 @@ pub async fn list_keys(pool: web::Data<DbPool>, params: Query<KeyList>) -> HttpR
                     encoded_keys.push(encoded_key);
                 }
                 let res = encoded_keys.join("\n");
-                print!("Encode truye");
-                return HttpResponse::Ok().body(format!("{}", res));
+                HttpResponse::Ok().body(res)
             }
             false => {
                 let res = keys.join("\n");
 
-                return HttpResponse::Ok().body(format!("{}", res));
+                HttpResponse::Ok().body(res)
             }
         },
-        Err(_) => HttpResponse::Ok().body(format!("")),
+        Ok(Err(error)) => db_error_response(error),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Transaction {
+    ops: Vec<TxOp>,
+}
+
+/// Runs every operation in one transaction. Returns the state of each key after its operation,
+/// or a 409 with the index of the operation whose precondition failed.
+#[post("/transaction")]
+pub async fn transaction(
+    storage: web::Data<dyn Storage>,
+    limits: web::Data<Limits>,
+    body: web::Json<Transaction>,
+) -> HttpResponse {
+    let ops = body.into_inner().ops;
+    if ops.is_empty() {
+        return HttpResponse::BadRequest().body("A transaction needs at least one operation");
+    }
+    for op in &ops {
+        let checked = match op {
+            TxOp::Set { key, value } => limits.check(key, value),
+            op => limits.check_key(op.key()),
+        };
+        if let Err(error) = checked {
+            return error.to_response();
+        }
+    }
+
+    match web::block(move || storage.transact(ops)).await {
+        Ok(Ok(results)) => HttpResponse::Ok().json(serde_json::json!({ "results": results })),
+        Ok(Err(error)) => match error.downcast::<TxAborted>() {
+            Ok(aborted) => HttpResponse::Conflict().json(serde_json::json!({
+                "index": aborted.index,
+                "reason": aborted.reason,
+            })),
+            Err(error) => db_error_response(error),
+        },
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+/// Logs storage errors instead of hiding them
+fn db_error_response(error: StorageError) -> HttpResponse {
+    let status = db_error_status(&error);
+    if status.is_server_error() {
+        log::error!("Storage error: {error}");
+    }
+    HttpResponse::build(status).body(error.to_string())
+}
+
+/// A busy database is worth retrying so it gets a 503, a write to a follower is a 403,
+/// a write a trigger rejected is a 422, anything else is a 500
+pub fn db_error_status(error: &StorageError) -> StatusCode {
+    if error.is::<ReadOnly>() {
+        return StatusCode::FORBIDDEN;
+    }
+    if error.is::<TriggerRejected>() {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    if error.is::<Busy>() {
+        return StatusCode::SERVICE_UNAVAILABLE;
     }
+    StatusCode::INTERNAL_SERVER_ERROR
 }
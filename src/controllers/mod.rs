@@ -1,3 +1,10 @@
 //extern crate urlencoding;
 
+pub mod admin_controller;
+pub mod channel_controller;
+pub mod cluster_controller;
 pub mod key_controller;
+pub mod presence_controller;
+pub mod trigger_controller;
+pub mod watch_controller;
+pub mod webhook_controller;
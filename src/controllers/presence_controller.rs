@@ -0,0 +1,29 @@
+use crate::actors::ws_actor::{ClientWebSocketConnection, Who};
+use actix::Addr;
+use actix_web::{get, web, HttpResponse};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct RoomPath {
+    room: String,
+}
+
+/// Who is connected to a chat room over the WebSocket, with their names and metadata.
+/// An empty room, or one that doesn't exist, has no members.
+#[get("/presence/{room}")]
+pub async fn presence(
+    server: web::Data<Addr<ClientWebSocketConnection>>,
+    path: web::Path<RoomPath>,
+) -> HttpResponse {
+    let who = Who {
+        id: None,
+        room: Some(path.into_inner().room),
+    };
+    match server.send(who).await {
+        Ok(Some((room, members))) => {
+            HttpResponse::Ok().json(serde_json::json!({ "room": room, "members": members }))
+        }
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
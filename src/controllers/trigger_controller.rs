@@ -0,0 +1,37 @@
+use crate::triggers::{NewTrigger, Triggers};
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+pub struct TriggerPath {
+    id: i64,
+}
+
+/// Registers a trigger, `{"phase": ..., "event": ..., "prefix": ..., "script": ...}`
+#[post("/triggers")]
+pub async fn register(triggers: web::Data<Triggers>, body: web::Json<NewTrigger>) -> HttpResponse {
+    let new = body.into_inner();
+    match web::block(move || triggers.register(new)).await {
+        Ok(Ok(trigger)) => HttpResponse::Created().json(trigger),
+        Ok(Err(error)) => error.to_response(),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+/// Every registered trigger in the order they run
+#[get("/triggers")]
+pub async fn list(triggers: web::Data<Triggers>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "triggers": triggers.list() }))
+}
+
+#[delete("/triggers/{id}")]
+pub async fn remove(triggers: web::Data<Triggers>, path: web::Path<TriggerPath>) -> HttpResponse {
+    let id = path.into_inner().id;
+    match web::block(move || triggers.remove(id)).await {
+        Ok(Ok(true)) => HttpResponse::NoContent().finish(),
+        Ok(Ok(false)) => HttpResponse::NotFound().finish(),
+        Ok(Err(error)) => HttpResponse::InternalServerError().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
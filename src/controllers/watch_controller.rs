@@ -0,0 +1,140 @@
+use crate::actors::ws_actor::{
+    ClientWebSocketConnection, Start, Watch, WatchEvent, MAX_COALESCE, STREAM_BUFFER,
+};
+use crate::controllers::key_controller::parse_duration;
+use crate::filter::Filter;
+use crate::storage::Change;
+use actix::Addr;
+use actix_web::{
+    get, rt,
+    web::{self, Bytes, Query},
+    HttpRequest, HttpResponse,
+};
+use futures::{future, stream, Stream, StreamExt};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Comment sent on idle streams so proxies don't time them out
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+pub struct WatchParams {
+    prefix: Option<String>,
+    /// Start with every key and value under the prefix
+    #[serde(default)]
+    snapshot: bool,
+    /// At most one event per key per this window, ie `100ms`, the latest change wins
+    coalesce: Option<String>,
+    /// Only changes whose new value matches this, see `filter`
+    filter: Option<String>,
+}
+
+/// Formats an event in the `text/event-stream` format
+pub(crate) fn sse_event(event: WatchEvent) -> Bytes {
+    match event {
+        WatchEvent::Change(event) => {
+            Bytes::from(format!("id: {}\n{}", event.seq, sse_change(&event.change)))
+        }
+        // No id, a peer's change isn't in our change log so a reconnect can't resume from it
+        WatchEvent::Remote(change) => Bytes::from(sse_change(&change)),
+        // Entries have no id, only the marker does, so a reconnect resumes after the snapshot
+        WatchEvent::Snapshot { entries, seq } => {
+            let mut events = String::new();
+            for entry in entries {
+                let data = serde_json::to_string(&entry).unwrap();
+                events.push_str(&format!("event: snapshot\ndata: {data}\n\n"));
+            }
+            events.push_str(&format!(
+                "id: {seq}\nevent: synced\ndata: {{\"seq\":{seq}}}\n\n"
+            ));
+            Bytes::from(events)
+        }
+        // The id moves the client's `Last-Event-ID` past the gap so the next reconnect resumes normally
+        WatchEvent::Resync { seq } => {
+            Bytes::from(format!("id: {seq}\nevent: resync\ndata: {{}}\n\n"))
+        }
+    }
+}
+
+/// The event and data lines of a change
+fn sse_change(change: &Change) -> String {
+    let name = match change {
+        Change::Set { .. } => "set",
+        Change::Delete { .. } => "delete",
+    };
+    // Serializing a change can not fail
+    let data = serde_json::to_string(change).unwrap();
+    format!("event: {name}\ndata: {data}\n\n")
+}
+
+/// Streams changes to keys under `prefix` as server-sent events.
+/// Event ids are change log sequence numbers. Reconnecting with `Last-Event-ID` replays what was
+/// missed from the change log, or sends a `resync` event if it no longer goes back that far.
+/// With `snapshot=true` a new stream starts with every key under the prefix and a `synced` event.
+/// With `coalesce=100ms` live changes to a key are sent at most once per 100ms, the latest one.
+/// With `filter=<expression>` only sets whose value matches are sent, deletes always are.
+#[get("/watch")]
+pub async fn watch(
+    req: HttpRequest,
+    server: web::Data<Addr<ClientWebSocketConnection>>,
+    params: Query<WatchParams>,
+) -> HttpResponse {
+    let params = params.into_inner();
+    let prefix = params.prefix.unwrap_or_default();
+    // Resuming picks up from the snapshot it got the first time
+    let start = match req.headers().get("Last-Event-ID") {
+        Some(header) => match header.to_str().map(|id| id.trim().parse::<u64>()) {
+            Ok(Ok(id)) => Start::After(id),
+            _ => return HttpResponse::BadRequest().body("Last-Event-ID must be a number"),
+        },
+        None if params.snapshot => Start::Snapshot,
+        None => Start::Live,
+    };
+    let coalesce = match params.coalesce.as_deref().map(parse_duration) {
+        None => None,
+        Some(Some(window)) if !window.is_zero() && window <= MAX_COALESCE => Some(window),
+        Some(_) => {
+            return HttpResponse::BadRequest()
+                .body("coalesce must be a duration like 100ms or 1s, at most 60s")
+        }
+    };
+
+    let filter = match params.filter.as_deref().map(Filter::parse).transpose() {
+        Ok(filter) => filter,
+        Err(error) => return HttpResponse::BadRequest().body(error.to_string()),
+    };
+
+    // A watcher that falls further behind than this is dropped and resumes when it reconnects
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+    server.do_send(Watch {
+        prefix,
+        start,
+        coalesce,
+        filter,
+        sender,
+    });
+
+    // The actor dropping the watcher ends the events, which ends the response
+    sse_response(ReceiverStream::new(receiver).map(sse_event))
+}
+
+/// A `text/event-stream` response sending `events` with keep-alives in between, it ends with them
+pub fn sse_response(events: impl Stream<Item = Bytes> + 'static) -> HttpResponse {
+    let events = events.map(Some).chain(stream::once(async { None }));
+    let keep_alive = stream::unfold(rt::time::interval(KEEP_ALIVE), |mut interval| async {
+        interval.tick().await;
+        Some((Some(Bytes::from_static(b": keep-alive\n\n")), interval))
+    })
+    .skip(1);
+    let body = stream::select(events, keep_alive)
+        .take_while(|chunk| future::ready(chunk.is_some()))
+        .filter_map(future::ready)
+        .map(Ok::<_, actix_web::Error>);
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
@@ -0,0 +1,113 @@
+use crate::data_access::models::DeadLetter;
+use crate::webhooks::{NewWebhook, Webhooks};
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Most dead letters listed when no limit is given
+const DEFAULT_DEAD_LETTER_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct WebhookPath {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeadLetterPath {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct DeadLetterParams {
+    /// Only this webhook's dead letters
+    webhook: Option<String>,
+    limit: Option<usize>,
+}
+
+fn dead_letter_json(dead_letter: DeadLetter) -> Value {
+    json!({
+        "id": dead_letter.id,
+        "webhook": dead_letter.webhook_id,
+        "payload": serde_json::from_str::<Value>(&dead_letter.payload)
+            .unwrap_or(Value::String(dead_letter.payload)),
+        "attempts": dead_letter.attempts,
+        "error": dead_letter.error,
+        "failed_at": dead_letter.failed_at,
+    })
+}
+
+/// Registers a webhook, `{"url": ..., "prefix": ..., "pattern": ..., "secret": ...}`
+#[post("/webhooks")]
+pub async fn register(webhooks: web::Data<Webhooks>, body: web::Json<NewWebhook>) -> HttpResponse {
+    let new = body.into_inner();
+    match web::block(move || webhooks.register(new)).await {
+        Ok(Ok(webhook)) => HttpResponse::Created().json(webhook),
+        Ok(Err(error)) => error.to_response(),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+/// Every registered webhook, without their secrets
+#[get("/webhooks")]
+pub async fn list(webhooks: web::Data<Webhooks>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "webhooks": webhooks.list() }))
+}
+
+/// Failed deliveries, oldest first
+#[get("/webhooks/dead-letters")]
+pub async fn dead_letters(
+    webhooks: web::Data<Webhooks>,
+    params: web::Query<DeadLetterParams>,
+) -> HttpResponse {
+    let DeadLetterParams { webhook, limit } = params.into_inner();
+    let limit = limit.unwrap_or(DEFAULT_DEAD_LETTER_LIMIT);
+    match web::block(move || webhooks.dead_letters(webhook.as_deref(), limit)).await {
+        Ok(Ok(dead_letters)) => {
+            let dead_letters: Vec<Value> = dead_letters.into_iter().map(dead_letter_json).collect();
+            HttpResponse::Ok().json(json!({ "dead_letters": dead_letters }))
+        }
+        Ok(Err(error)) => HttpResponse::InternalServerError().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+/// Queues a dead letter again, it comes back as a new dead letter if every attempt fails again
+#[post("/webhooks/dead-letters/{id}/retry")]
+pub async fn retry_dead_letter(
+    webhooks: web::Data<Webhooks>,
+    path: web::Path<DeadLetterPath>,
+) -> HttpResponse {
+    let id = path.into_inner().id;
+    match web::block(move || webhooks.redeliver(id)).await {
+        Ok(Ok(true)) => HttpResponse::Accepted().finish(),
+        Ok(Ok(false)) => HttpResponse::NotFound().finish(),
+        Ok(Err(error)) => HttpResponse::InternalServerError().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+#[delete("/webhooks/dead-letters/{id}")]
+pub async fn delete_dead_letter(
+    webhooks: web::Data<Webhooks>,
+    path: web::Path<DeadLetterPath>,
+) -> HttpResponse {
+    let id = path.into_inner().id;
+    match web::block(move || webhooks.take_dead_letter(id)).await {
+        Ok(Ok(Some(_))) => HttpResponse::NoContent().finish(),
+        Ok(Ok(None)) => HttpResponse::NotFound().finish(),
+        Ok(Err(error)) => HttpResponse::InternalServerError().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+/// Removes a webhook along with its dead letters
+#[delete("/webhooks/{id}")]
+pub async fn remove(webhooks: web::Data<Webhooks>, path: web::Path<WebhookPath>) -> HttpResponse {
+    let id = path.into_inner().id;
+    match web::block(move || webhooks.remove(&id)).await {
+        Ok(Ok(true)) => HttpResponse::NoContent().finish(),
+        Ok(Ok(false)) => HttpResponse::NotFound().finish(),
+        Ok(Err(error)) => HttpResponse::InternalServerError().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
@@ -1,28 +1,59 @@
 use crate::data_access::models;
+use crate::data_access::schema::change_log;
 use crate::data_access::schema::key_values::dsl::key_values;
 use crate::data_access::schema::key_values::dsl::*;
+use crate::storage::{Busy, Change, Event};
+use diesel::dsl::sql;
 use diesel::prelude::*;
+use diesel::r2d2::PoolError;
+use diesel::result::DatabaseErrorKind;
+use diesel::sql_types::{BigInt, Bool, Text};
 
-type DbError = Box<dyn std::error::Error + Send + Sync>;
+diesel::sql_function!(fn last_insert_rowid() -> BigInt);
 
-pub fn insert_new_entry(
-    conn: &mut SqliteConnection,
-    new_key: String,
-    new_value: String,
-) -> Result<models::NewKeyValue, DbError> {
-    let new_key_value = models::NewKeyValue {
-        key: new_key,
-        value: new_value,
-    };
+pub type DbError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Whether `error` means SQLite or the pool was busy. Diesel doesn't keep SQLite's error
+/// codes, a busy database only shows up as an unknown error with SQLite's message for it.
+pub fn is_busy(error: &(dyn std::error::Error + 'static)) -> bool {
+    match error.downcast_ref::<diesel::result::Error>() {
+        Some(diesel::result::Error::DatabaseError(DatabaseErrorKind::Unknown, info)) => {
+            matches!(
+                info.message(),
+                "database is locked" | "database table is locked"
+            )
+        }
+        _ => error.is::<PoolError>(),
+    }
+}
 
-    let _ = diesel::insert_into(key_values)
-        .values(&new_key_value)
+/// Turns errors that mean the database was busy into `Busy`
+pub fn busy_or(error: DbError) -> DbError {
+    match is_busy(error.as_ref()) {
+        true => Box::new(Busy(error.to_string())),
+        false => error,
+    }
+}
+
+/// Sets a key and its version, the sequence number of the change that writes it
+pub fn put_versioned_entry(
+    conn: &mut SqliteConnection,
+    new_key: &str,
+    new_value: &str,
+    new_version: i64,
+) -> Result<(), DbError> {
+    diesel::insert_into(key_values)
+        .values((
+            key.eq(new_key),
+            value.eq(new_value),
+            version.eq(new_version),
+        ))
         .on_conflict(key)
         .do_update()
-        .set(&new_key_value)
-        .execute(conn);
+        .set((value.eq(new_value), version.eq(new_version)))
+        .execute(conn)?;
 
-    Ok(new_key_value)
+    Ok(())
 }
 
 pub fn get_entry(
@@ -37,25 +68,172 @@ pub fn get_entry(
     Ok(entry)
 }
 
-pub fn get_keys_by_prefix(conn: &mut SqliteConnection, prefix: String) -> Vec<String> {
-    let pattern = format!("{}%", prefix);
+/// LIKE pattern matching keys that start with `prefix`, with `%` and `_` escaped
+fn prefix_pattern(prefix: &str) -> String {
+    let escaped = prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("{}%", escaped)
+}
 
+pub fn get_keys_by_prefix(
+    conn: &mut SqliteConnection,
+    prefix: String,
+) -> Result<Vec<String>, DbError> {
     let query_results = key_values
-        .filter(key.like(pattern))
-        .load::<models::KeyValue>(conn)
-        .expect("Could not load the keys");
+        .filter(key.like(prefix_pattern(&prefix)).escape('\\'))
+        .order(key.asc())
+        .load::<models::KeyValue>(conn)?;
     let mut results: Vec<String> = Vec::new();
 
     for entry in query_results {
-        results.push(entry.key);
+        // LIKE ignores case for ASCII, prefixes don't
+        if entry.key.starts_with(&prefix) {
+            results.push(entry.key);
+        }
     }
-    results
+    Ok(results)
 }
 
-pub fn delete_by_key(conn: &mut SqliteConnection, key_to_delete: String) -> bool {
-    let num_deleted = diesel::delete(key_values.filter(key.eq(key_to_delete)))
-        .execute(conn)
-        .expect("Error deleting");
+/// Up to `limit` entries starting with `prefix` that sort after `after`, in key order
+pub fn get_entries_by_prefix(
+    conn: &mut SqliteConnection,
+    prefix: &str,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<Vec<models::KeyValue>, DbError> {
+    // Keys are compared byte by byte, so everything with the prefix sits in one run starting at it
+    let query = key_values.order(key.asc()).limit(limit as i64).into_boxed();
+    let query = match after {
+        Some(after) if after >= prefix => query.filter(key.gt(after.to_string())),
+        _ => query.filter(key.ge(prefix.to_string())),
+    };
+    let entries = query
+        .load::<models::KeyValue>(conn)?
+        .into_iter()
+        .take_while(|entry| entry.key.starts_with(prefix))
+        .collect();
+    Ok(entries)
+}
 
-    num_deleted > 0
+pub fn delete_by_key(conn: &mut SqliteConnection, key_to_delete: String) -> Result<bool, DbError> {
+    let num_deleted = diesel::delete(key_values.filter(key.eq(key_to_delete))).execute(conn)?;
+
+    Ok(num_deleted > 0)
+}
+
+pub fn key_exists(conn: &mut SqliteConnection, search_key: &str) -> Result<bool, DbError> {
+    let count: i64 = key_values
+        .filter(key.eq(search_key))
+        .count()
+        .get_result(conn)?;
+
+    Ok(count > 0)
+}
+
+/// Appends `changes` to the change log and returns them with their sequence numbers
+pub fn append_changes(
+    conn: &mut SqliteConnection,
+    changes: Vec<Change>,
+    now: i64,
+) -> Result<Vec<Event>, DbError> {
+    let mut events = Vec::with_capacity(changes.len());
+    for change in changes {
+        let entry = match &change {
+            Change::Set {
+                key: changed_key,
+                value: new_value,
+            } => models::NewChangeLogEntry {
+                key: changed_key,
+                value: Some(new_value),
+                created_at: now,
+            },
+            Change::Delete { key: changed_key } => models::NewChangeLogEntry {
+                key: changed_key,
+                value: None,
+                created_at: now,
+            },
+        };
+        diesel::insert_into(change_log::table)
+            .values(&entry)
+            .execute(conn)?;
+        let seq: i64 = diesel::select(last_insert_rowid()).get_result(conn)?;
+        events.push(Event {
+            seq: seq as u64,
+            change,
+        });
+    }
+    Ok(events)
+}
+
+/// Sequence number the next change appended to the change log gets. AUTOINCREMENT carries on
+/// from the highest number ever handed out, which pruning doesn't reset.
+pub fn next_change_seq(conn: &mut SqliteConnection) -> Result<i64, DbError> {
+    let next = diesel::select(sql::<BigInt>(
+        "MAX(IFNULL((SELECT seq FROM sqlite_sequence WHERE name = 'change_log'), 0), \
+         IFNULL((SELECT MAX(seq) FROM change_log), 0)) + 1",
+    ))
+    .get_result(conn)?;
+    Ok(next)
+}
+
+/// Sequence numbers of the oldest and newest change still in the log
+pub fn change_log_bounds(
+    conn: &mut SqliteConnection,
+) -> Result<(Option<i64>, Option<i64>), DbError> {
+    let bounds = change_log::table
+        .select((
+            diesel::dsl::min(change_log::seq),
+            diesel::dsl::max(change_log::seq),
+        ))
+        .first(conn)?;
+    Ok(bounds)
+}
+
+/// Up to `limit` changes to keys starting with `prefix` with a sequence number above `after`
+pub fn get_changes_since(
+    conn: &mut SqliteConnection,
+    prefix: &str,
+    after: i64,
+    limit: usize,
+) -> Result<Vec<models::ChangeLogEntry>, DbError> {
+    let entries = change_log::table
+        .select((change_log::seq, change_log::key, change_log::value))
+        .filter(change_log::seq.gt(after))
+        // Unlike LIKE, instr is case sensitive, so every row of the page has the prefix
+        .filter(
+            sql::<Bool>("instr(key, ")
+                .bind::<Text, _>(prefix)
+                .sql(") = 1"),
+        )
+        .order(change_log::seq.asc())
+        .limit(limit as i64)
+        .load::<models::ChangeLogEntry>(conn)?;
+    Ok(entries)
+}
+
+/// Deletes changes made before `created_before` or with a sequence number below `below_seq`.
+/// The last change is kept either way, so the next sequence number can still be told apart
+/// from a log that was wiped.
+pub fn prune_change_log(
+    conn: &mut SqliteConnection,
+    created_before: i64,
+    below_seq: i64,
+) -> Result<usize, DbError> {
+    let last_seq = match change_log_bounds(conn)?.1 {
+        Some(last_seq) => last_seq,
+        None => return Ok(0),
+    };
+    let pruned = diesel::delete(
+        change_log::table
+            .filter(change_log::seq.lt(last_seq))
+            .filter(
+                change_log::seq
+                    .lt(below_seq)
+                    .or(change_log::created_at.lt(created_before)),
+            ),
+    )
+    .execute(conn)?;
+    Ok(pruned)
 }
@@ -0,0 +1,100 @@
+//! Consistent snapshots of the database using `VACUUM INTO`, which copies the
+//! database inside a read transaction so writers never leave it half written.
+
+use crate::storage::Storage;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type DbError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Prefix of the files written by scheduled backups
+const BACKUP_FILE_PREFIX: &str = "tinybase-";
+
+#[derive(QueryableByName)]
+struct IntegrityCheck {
+    #[diesel(sql_type = Text)]
+    integrity_check: String,
+}
+
+/// Writes a consistent copy of the database to `path`, which must not exist yet
+pub fn backup_to(conn: &mut SqliteConnection, path: &Path) -> Result<(), DbError> {
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    let path = path
+        .to_str()
+        .ok_or_else(|| format!("{} is not a valid UTF-8 path", path.display()))?;
+    diesel::sql_query("VACUUM INTO ?")
+        .bind::<Text, _>(path)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Writes a backup into `dir` named after the current time and returns its path
+pub fn backup_into_dir(storage: &dyn Storage, dir: &Path) -> Result<PathBuf, DbError> {
+    fs::create_dir_all(dir)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let path = dir.join(format!(
+        "{BACKUP_FILE_PREFIX}{}{:03}.db",
+        now.as_secs(),
+        now.subsec_millis()
+    ));
+    storage.backup_to(&path)?;
+    Ok(path)
+}
+
+/// Deletes the oldest scheduled backups in `dir` so only `retain` are left
+pub fn prune_backups(dir: &Path, retain: usize) -> Result<Vec<PathBuf>, DbError> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with(BACKUP_FILE_PREFIX) && name.ends_with(".db"))
+                .unwrap_or(false)
+        })
+        .collect();
+    // The names are timestamps so sorting by name sorts oldest first
+    backups.sort();
+
+    let mut removed = Vec::new();
+    while backups.len() > retain {
+        let oldest = backups.remove(0);
+        fs::remove_file(&oldest)?;
+        removed.push(oldest);
+    }
+    Ok(removed)
+}
+
+/// Replaces the database at `database_url` with a backup. Only safe before the server opens it.
+pub fn restore_from(backup: &Path, database_url: &str) -> Result<(), DbError> {
+    let backup_str = backup
+        .to_str()
+        .ok_or_else(|| format!("{} is not a valid UTF-8 path", backup.display()))?;
+    if !backup.is_file() {
+        return Err(format!("Backup {} does not exist", backup.display()).into());
+    }
+
+    // Make sure we are not about to replace the database with garbage
+    let mut backup_conn = SqliteConnection::establish(backup_str)?;
+    let checks =
+        diesel::sql_query("PRAGMA integrity_check").load::<IntegrityCheck>(&mut backup_conn)?;
+    if checks.len() != 1 || checks[0].integrity_check != "ok" {
+        return Err(format!("Backup {} failed the integrity check", backup.display()).into());
+    }
+    drop(backup_conn);
+
+    // Stale WAL files from the old database would be replayed on top of the backup
+    for suffix in ["-wal", "-shm"] {
+        let stale = PathBuf::from(format!("{database_url}{suffix}"));
+        if stale.exists() {
+            fs::remove_file(stale)?;
+        }
+    }
+    fs::copy(backup, database_url)?;
+    Ok(())
+}
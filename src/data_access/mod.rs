@@ -1,4 +1,9 @@
 pub mod actions;
+pub mod backup;
 pub mod migrations;
 pub mod models;
+pub mod pool;
 pub mod schema;
+pub mod triggers;
+pub mod webhooks;
+pub mod writer;
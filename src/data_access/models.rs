@@ -1,7 +1,9 @@
-use diesel::{AsChangeset, Insertable, Queryable};
+use diesel::{Insertable, Queryable};
 use serde::{Deserialize, Serialize};
 
-use crate::data_access::schema::key_values;
+use crate::data_access::schema::{
+    change_log, triggers, webhook_dead_letters, webhook_deliveries, webhooks,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Queryable)]
 #[diesel(primary_key(id))]
@@ -10,11 +12,90 @@ pub struct KeyValue {
     pub id: Option<i32>,
     pub key: String,
     pub value: String,
+    pub version: i64,
 }
 
-#[derive(Debug, Insertable, AsChangeset)]
-#[diesel(table_name = key_values)]
-pub struct NewKeyValue {
+#[derive(Debug, Clone, Queryable)]
+#[diesel(table_name = change_log)]
+pub struct ChangeLogEntry {
+    pub seq: i64,
     pub key: String,
-    pub value: String,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = change_log)]
+pub struct NewChangeLogEntry<'a> {
+    pub key: &'a str,
+    pub value: Option<&'a str>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = webhooks)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub prefix: String,
+    pub pattern: Option<String>,
+    pub secret: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Queryable)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct Delivery {
+    pub id: i64,
+    pub webhook_id: String,
+    pub payload: String,
+    pub attempts: i32,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewDelivery<'a> {
+    pub webhook_id: &'a str,
+    pub payload: &'a str,
+}
+
+#[derive(Debug, Clone, Queryable)]
+#[diesel(table_name = webhook_dead_letters)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub webhook_id: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub error: String,
+    pub failed_at: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = webhook_dead_letters)]
+pub struct NewDeadLetter<'a> {
+    pub webhook_id: &'a str,
+    pub payload: &'a str,
+    pub attempts: i32,
+    pub error: &'a str,
+    pub failed_at: i64,
+}
+
+#[derive(Debug, Clone, Queryable)]
+#[diesel(table_name = triggers)]
+pub struct Trigger {
+    pub id: i64,
+    pub phase: String,
+    pub event: String,
+    pub prefix: String,
+    pub script: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = triggers)]
+pub struct InsertTrigger<'a> {
+    pub phase: &'a str,
+    pub event: &'a str,
+    pub prefix: &'a str,
+    pub script: &'a str,
+    pub created_at: i64,
 }
@@ -0,0 +1,76 @@
+//! Connection pool setup. Every connection gets the same pragmas so concurrent
+//! writers wait for each other instead of failing with `database is locked`.
+
+use crate::config::DatabaseConfig;
+use crate::data_access::migrations;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection};
+use std::error::Error;
+use std::time::Duration;
+
+pub type DbPool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
+
+/// Pragmas applied to every connection the pool opens
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub wal: bool,
+    pub busy_timeout: Duration,
+    pub synchronous: String,
+}
+
+impl ConnectionOptions {
+    pub fn from_config(config: &DatabaseConfig) -> ConnectionOptions {
+        ConnectionOptions {
+            wal: config.wal,
+            busy_timeout: Duration::from_millis(config.busy_timeout_ms),
+            synchronous: config.synchronous.clone(),
+        }
+    }
+
+    pub fn apply(&self, conn: &mut SqliteConnection) -> QueryResult<()> {
+        // busy_timeout goes first so switching to WAL waits on other connections too
+        let mut pragmas = format!("PRAGMA busy_timeout = {};", self.busy_timeout.as_millis());
+        if self.wal {
+            pragmas.push_str("PRAGMA journal_mode = WAL;");
+        }
+        pragmas.push_str(&format!(
+            "PRAGMA synchronous = {}; PRAGMA foreign_keys = ON;",
+            self.synchronous
+        ));
+        conn.batch_execute(&pragmas)
+    }
+
+    /// Opens a single connection outside of the pool, used by the CLI
+    pub fn establish(&self, database_url: &str) -> ConnectionResult<SqliteConnection> {
+        let mut conn = SqliteConnection::establish(database_url)?;
+        self.apply(&mut conn)
+            .map_err(|error| ConnectionError::BadConnection(error.to_string()))?;
+        Ok(conn)
+    }
+}
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        self.apply(conn).map_err(r2d2::Error::QueryError)
+    }
+}
+
+/// Builds the pool described by the `[database]` config
+pub fn build(config: &DatabaseConfig) -> Result<DbPool, r2d2::PoolError> {
+    let manager = ConnectionManager::<SqliteConnection>::new(config.url.clone());
+    r2d2::Pool::builder()
+        .max_size(config.pool_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(Duration::from_secs(config.connection_timeout_secs))
+        .connection_customizer(Box::new(ConnectionOptions::from_config(config)))
+        .build(manager)
+}
+
+/// Builds the pool and brings the schema up to date. The server opens one and shares it
+/// between the storage, the webhooks and the triggers.
+pub fn open(config: &DatabaseConfig) -> Result<DbPool, Box<dyn Error + Send + Sync>> {
+    let pool = build(config)?;
+    migrations::run(&mut pool.get()?)?;
+    Ok(pool)
+}
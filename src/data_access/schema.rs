@@ -1,9 +1,73 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    change_log (seq) {
+        seq -> BigInt,
+        key -> Text,
+        value -> Nullable<Text>,
+        created_at -> BigInt,
+    }
+}
+
 diesel::table! {
     key_values (id) {
         id -> Nullable<Integer>,
         key -> Text,
         value -> Text,
+        version -> BigInt,
+    }
+}
+
+diesel::table! {
+    triggers (id) {
+        id -> BigInt,
+        phase -> Text,
+        event -> Text,
+        prefix -> Text,
+        script -> Text,
+        created_at -> BigInt,
     }
 }
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> BigInt,
+        webhook_id -> Text,
+        payload -> Text,
+        attempts -> Integer,
+    }
+}
+
+diesel::table! {
+    webhook_dead_letters (id) {
+        id -> BigInt,
+        webhook_id -> Text,
+        payload -> Text,
+        attempts -> Integer,
+        error -> Text,
+        failed_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Text,
+        url -> Text,
+        prefix -> Text,
+        pattern -> Nullable<Text>,
+        secret -> Nullable<Text>,
+        created_at -> BigInt,
+    }
+}
+
+diesel::joinable!(webhook_dead_letters -> webhooks (webhook_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    change_log,
+    key_values,
+    triggers,
+    webhook_dead_letters,
+    webhook_deliveries,
+    webhooks,
+);
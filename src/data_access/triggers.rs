@@ -0,0 +1,33 @@
+//! Queries for registered triggers
+
+use crate::data_access::actions::{last_insert_rowid, DbError};
+use crate::data_access::models::{InsertTrigger, Trigger};
+use crate::data_access::schema::triggers;
+use diesel::prelude::*;
+
+/// Returns the new trigger's id
+pub fn insert_trigger(
+    conn: &mut SqliteConnection,
+    trigger: &InsertTrigger,
+) -> Result<i64, DbError> {
+    conn.transaction::<_, DbError, _>(|conn| {
+        diesel::insert_into(triggers::table)
+            .values(trigger)
+            .execute(conn)?;
+        Ok(diesel::select(last_insert_rowid()).get_result(conn)?)
+    })
+}
+
+/// Every trigger in the order they run
+pub fn get_triggers(conn: &mut SqliteConnection) -> Result<Vec<Trigger>, DbError> {
+    let found = triggers::table
+        .order(triggers::id.asc())
+        .load::<Trigger>(conn)?;
+    Ok(found)
+}
+
+/// Returns false if there was no such trigger
+pub fn delete_trigger(conn: &mut SqliteConnection, id: i64) -> Result<bool, DbError> {
+    let deleted = diesel::delete(triggers::table.filter(triggers::id.eq(id))).execute(conn)?;
+    Ok(deleted > 0)
+}
@@ -0,0 +1,155 @@
+//! Queries for registered webhooks, their queued deliveries and their dead letters
+
+use crate::data_access::actions::DbError;
+use crate::data_access::models::{DeadLetter, Delivery, NewDeadLetter, NewDelivery, Webhook};
+use crate::data_access::schema::{webhook_dead_letters, webhook_deliveries, webhooks};
+use diesel::prelude::*;
+
+pub fn insert_webhook(conn: &mut SqliteConnection, webhook: &Webhook) -> Result<(), DbError> {
+    diesel::insert_into(webhooks::table)
+        .values(webhook)
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn get_webhooks(conn: &mut SqliteConnection) -> Result<Vec<Webhook>, DbError> {
+    let found = webhooks::table
+        .order(webhooks::created_at.asc())
+        .load::<Webhook>(conn)?;
+    Ok(found)
+}
+
+/// Returns false if there was no such webhook. Its dead letters go with it.
+pub fn delete_webhook(conn: &mut SqliteConnection, id: &str) -> Result<bool, DbError> {
+    let deleted = diesel::delete(webhooks::table.filter(webhooks::id.eq(id))).execute(conn)?;
+    Ok(deleted > 0)
+}
+
+/// Queues deliveries and keeps dead letters in one transaction, so a batch is queued whole
+pub fn queue_deliveries(
+    conn: &mut SqliteConnection,
+    deliveries: &[NewDelivery],
+    dead_letters: &[NewDeadLetter],
+) -> Result<(), DbError> {
+    conn.transaction::<_, DbError, _>(|conn| {
+        diesel::insert_into(webhook_deliveries::table)
+            .values(deliveries)
+            .execute(conn)?;
+        diesel::insert_into(webhook_dead_letters::table)
+            .values(dead_letters)
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// How many deliveries each webhook has queued
+pub fn count_deliveries(conn: &mut SqliteConnection) -> Result<Vec<(String, i64)>, DbError> {
+    let counts = webhook_deliveries::table
+        .group_by(webhook_deliveries::webhook_id)
+        .select((webhook_deliveries::webhook_id, diesel::dsl::count_star()))
+        .load::<(String, i64)>(conn)?;
+    Ok(counts)
+}
+
+/// The oldest delivery queued for a webhook
+pub fn next_delivery(
+    conn: &mut SqliteConnection,
+    webhook_id: &str,
+) -> Result<Option<Delivery>, DbError> {
+    let delivery = webhook_deliveries::table
+        .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+        .order(webhook_deliveries::id.asc())
+        .first::<Delivery>(conn)
+        .optional()?;
+    Ok(delivery)
+}
+
+pub fn set_delivery_attempts(
+    conn: &mut SqliteConnection,
+    id: i64,
+    attempts: i32,
+) -> Result<(), DbError> {
+    diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq(id)))
+        .set(webhook_deliveries::attempts.eq(attempts))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Takes a delivery off its queue, keeping it as a dead letter if one is given
+pub fn finish_delivery(
+    conn: &mut SqliteConnection,
+    id: i64,
+    dead_letter: Option<&NewDeadLetter>,
+) -> Result<(), DbError> {
+    conn.transaction::<_, DbError, _>(|conn| {
+        diesel::delete(webhook_deliveries::table.filter(webhook_deliveries::id.eq(id)))
+            .execute(conn)?;
+        if let Some(dead_letter) = dead_letter {
+            insert_dead_letter(conn, dead_letter)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn insert_dead_letter(
+    conn: &mut SqliteConnection,
+    dead_letter: &NewDeadLetter,
+) -> Result<(), DbError> {
+    diesel::insert_into(webhook_dead_letters::table)
+        .values(dead_letter)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Up to `limit` dead letters, of one webhook or all of them, oldest first
+pub fn get_dead_letters(
+    conn: &mut SqliteConnection,
+    webhook_id: Option<&str>,
+    limit: i64,
+) -> Result<Vec<DeadLetter>, DbError> {
+    let mut query = webhook_dead_letters::table
+        .order(webhook_dead_letters::id.asc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(webhook_id) = webhook_id {
+        query = query.filter(webhook_dead_letters::webhook_id.eq(webhook_id));
+    }
+    Ok(query.load::<DeadLetter>(conn)?)
+}
+
+/// Moves a dead letter to the end of its webhook's queue and returns it, `None` if there was no such dead letter
+pub fn requeue_dead_letter(
+    conn: &mut SqliteConnection,
+    id: i64,
+) -> Result<Option<DeadLetter>, DbError> {
+    conn.transaction::<_, DbError, _>(|conn| {
+        let dead_letter = take_dead_letter(conn, id)?;
+        if let Some(dead_letter) = &dead_letter {
+            diesel::insert_into(webhook_deliveries::table)
+                .values(NewDelivery {
+                    webhook_id: &dead_letter.webhook_id,
+                    payload: &dead_letter.payload,
+                })
+                .execute(conn)?;
+        }
+        Ok(dead_letter)
+    })
+}
+
+/// Removes a dead letter and returns it, `None` if there was no such dead letter
+pub fn take_dead_letter(
+    conn: &mut SqliteConnection,
+    id: i64,
+) -> Result<Option<DeadLetter>, DbError> {
+    conn.transaction::<_, DbError, _>(|conn| {
+        let dead_letter = webhook_dead_letters::table
+            .filter(webhook_dead_letters::id.eq(id))
+            .first::<DeadLetter>(conn)
+            .optional()?;
+        if dead_letter.is_some() {
+            diesel::delete(webhook_dead_letters::table.filter(webhook_dead_letters::id.eq(id)))
+                .execute(conn)?;
+        }
+        Ok(dead_letter)
+    })
+}
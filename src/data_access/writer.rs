@@ -0,0 +1,141 @@
+//! Single writer for the database.
+//!
+//! SQLite only lets one connection write at a time, so instead of every worker
+//! fighting over the write lock all mutations are queued to one thread. The
+//! thread takes everything that is waiting and commits it in one transaction
+//! (each job in its own savepoint), then tells listeners what changed in commit order.
+//! Changes are appended to the change log in the same savepoint as the write
+//! that made them, so the log never disagrees with the data.
+
+use crate::data_access::actions::{append_changes, busy_or, is_busy, DbError};
+use crate::data_access::pool::ConnectionOptions;
+use crate::storage::change_log::unix_time;
+use crate::storage::Busy;
+use crate::storage::{Change, Changes, Event};
+use diesel::prelude::*;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use tokio::sync::broadcast;
+
+/// Most jobs that get committed together in one transaction
+const MAX_GROUP_SIZE: usize = 256;
+
+type ApplyFn = Box<dyn FnOnce(&mut SqliteConnection) -> Result<Vec<Change>, DbError> + Send>;
+
+struct WriteJob {
+    apply: ApplyFn,
+    reply: mpsc::SyncSender<Result<(), DbError>>,
+}
+
+/// Handle used to queue writes, cheap to clone
+#[derive(Clone)]
+pub struct Writer {
+    sender: mpsc::Sender<WriteJob>,
+}
+
+impl Writer {
+    /// Opens the writer's connection and starts its thread
+    pub fn start(
+        database_url: &str,
+        options: &ConnectionOptions,
+        listener: broadcast::Sender<Changes>,
+    ) -> ConnectionResult<Writer> {
+        let conn = options.establish(database_url)?;
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("tinybase-writer".to_string())
+            .spawn(move || run(conn, receiver, listener))
+            .expect("Could not start the writer thread");
+        Ok(Writer { sender })
+    }
+
+    /// Queues `job` and blocks until it is committed. The job returns its result
+    /// along with the changes it made so they can be sent to listeners.
+    pub fn write<F, R>(&self, job: F) -> Result<R, DbError>
+    where
+        F: FnOnce(&mut SqliteConnection) -> Result<(R, Vec<Change>), DbError> + Send + 'static,
+        R: Send + 'static,
+    {
+        let output = Arc::new(Mutex::new(None));
+        let job_output = output.clone();
+        let apply: ApplyFn = Box::new(move |conn| {
+            let (result, changes) = job(conn)?;
+            *job_output.lock().unwrap() = Some(result);
+            Ok(changes)
+        });
+
+        let (reply, committed) = mpsc::sync_channel(1);
+        self.sender
+            .send(WriteJob { apply, reply })
+            .map_err(|_| "The writer has stopped")?;
+        committed
+            .recv()
+            .map_err(|_| "The writer dropped the write")??;
+
+        let result = output.lock().unwrap().take();
+        result.ok_or_else(|| "The write finished without a result".into())
+    }
+}
+
+fn run(
+    mut conn: SqliteConnection,
+    receiver: mpsc::Receiver<WriteJob>,
+    listener: broadcast::Sender<Changes>,
+) {
+    // Blocks until there is work, then grabs whatever else queued up meanwhile
+    while let Ok(first) = receiver.recv() {
+        let mut group = vec![first];
+        while group.len() < MAX_GROUP_SIZE {
+            match receiver.try_recv() {
+                Ok(job) => group.push(job),
+                Err(_) => break,
+            }
+        }
+
+        let now = unix_time() as i64;
+        let mut replies = Vec::with_capacity(group.len());
+        let mut outcomes: Vec<Result<Vec<Event>, DbError>> = Vec::with_capacity(group.len());
+        let commit = conn.transaction::<(), diesel::result::Error, _>(|conn| {
+            for job in group {
+                // A savepoint per job so one failing write doesn't roll back the others
+                let outcome = conn.transaction::<Vec<Event>, DbError, _>(|conn| {
+                    let changes = (job.apply)(conn)?;
+                    append_changes(conn, changes, now)
+                });
+                outcomes.push(outcome.map_err(busy_or));
+                replies.push(job.reply);
+            }
+            Ok(())
+        });
+
+        match commit {
+            Ok(()) => {
+                for (reply, outcome) in replies.into_iter().zip(outcomes) {
+                    match outcome {
+                        Ok(events) => {
+                            if !events.is_empty() {
+                                let _ = listener.send(Changes(events));
+                            }
+                            let _ = reply.send(Ok(()));
+                        }
+                        Err(error) => {
+                            let _ = reply.send(Err(error));
+                        }
+                    }
+                }
+            }
+            Err(error) => {
+                log::error!("Could not commit {} writes: {error}", replies.len());
+                let busy = is_busy(&error);
+                for reply in replies {
+                    let error: DbError = match busy {
+                        true => Box::new(Busy(error.to_string())),
+                        false => error.to_string().into(),
+                    };
+                    let _ = reply.send(Err(error));
+                }
+            }
+        }
+    }
+    log::debug!("writer stopped");
+}
@@ -0,0 +1,68 @@
+//! Keeps CLI writes away from data a running server holds.
+//!
+//! The server caches values and tells listeners about every write it makes,
+//! but it can't see writes another process makes to the files underneath it.
+//! While it runs it holds an exclusive lock on `<file>.lock` next to the
+//! SQLite database and, with the log backend, next to the log. CLI commands
+//! that write take the same locks, so they fail instead of going behind its
+//! back. Reads and backups don't need them.
+
+use crate::config::{Config, StorageBackend};
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::PathBuf;
+
+/// Returned when another process holds the data
+#[derive(Debug)]
+pub struct DataInUse {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for DataInUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is locked, a tinybase server is using this data. Stop it first or write through its API.",
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for DataInUse {}
+
+/// Held locks, released when dropped or when the process exits
+pub struct DataLock {
+    _files: Vec<File>,
+}
+
+/// Locks the files the configured storage, webhooks and triggers live in
+pub fn acquire(config: &Config) -> Result<DataLock, Box<dyn std::error::Error + Send + Sync>> {
+    let mut files = Vec::new();
+    for path in lock_paths(config) {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => files.push(file),
+            Err(TryLockError::WouldBlock) => return Err(Box::new(DataInUse { path })),
+            Err(TryLockError::Error(error)) => return Err(error.into()),
+        }
+    }
+    Ok(DataLock { _files: files })
+}
+
+fn lock_paths(config: &Config) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    // Webhooks and triggers are always in the SQLite database, whatever the backend
+    if config.database.url != ":memory:" {
+        paths.push(PathBuf::from(format!("{}.lock", config.database.url)));
+    }
+    if config.storage.backend == StorageBackend::Log {
+        let mut path = config.storage.log.path.clone().into_os_string();
+        path.push(".lock");
+        paths.push(PathBuf::from(path));
+    }
+    paths
+}
@@ -0,0 +1,342 @@
+//! Filter expressions over JSON values, so listeners only get the changes they care about.
+//!
+//! A filter compares fields of the new value to literals and combines the
+//! comparisons with `&&`, `||`, `!` and parentheses:
+//!
+//! ```text
+//! status == "open" && (priority >= 2 || assignee.name == "sam")
+//! ```
+//!
+//! A path is field names separated by dots with `[n]` for array items, `$` is
+//! the whole value. A path on its own is true if it's there and not `null`,
+//! `false`, 0 or `""`. Missing fields are `null`. Values that aren't JSON are
+//! treated as a JSON string, so `$ == "on"` works on plain values too.
+
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+/// Longest filter accepted, in bytes
+pub const MAX_FILTER_LEN: usize = 1024;
+
+/// Deepest nesting of `!` and parentheses accepted
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Filter(Expr);
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Vec<Segment>, Op, Value),
+    Truthy(Vec<Segment>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Why a filter could not be parsed
+#[derive(Debug, PartialEq, Eq)]
+pub struct FilterError(String);
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid filter: {}", self.0)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Path(Vec<Segment>),
+    Literal(Value),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Filter, FilterError> {
+        if source.len() > MAX_FILTER_LEN {
+            return Err(FilterError(format!(
+                "it is {} bytes, the max is {MAX_FILTER_LEN}",
+                source.len()
+            )));
+        }
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, at: 0 };
+        let expr = parser.or(0)?;
+        match parser.tokens.get(parser.at) {
+            None => Ok(Filter(expr)),
+            Some(token) => Err(unexpected(token)),
+        }
+    }
+
+    /// Checks a value as it is stored, a string that may or may not be JSON
+    pub fn matches(&self, value: &str) -> bool {
+        self.matches_json(&parse_value(value))
+    }
+
+    pub fn matches_json(&self, value: &Value) -> bool {
+        self.0.eval(value)
+    }
+}
+
+impl Expr {
+    fn eval(&self, value: &Value) -> bool {
+        match self {
+            Expr::Or(left, right) => left.eval(value) || right.eval(value),
+            Expr::And(left, right) => left.eval(value) && right.eval(value),
+            Expr::Not(expr) => !expr.eval(value),
+            Expr::Compare(path, op, literal) => {
+                let found = lookup(value, path).unwrap_or(&Value::Null);
+                compare(found, *op, literal)
+            }
+            Expr::Truthy(path) => match lookup(value, path) {
+                None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+                Some(Value::Number(number)) => number.as_f64() != Some(0.0),
+                Some(Value::String(string)) => !string.is_empty(),
+                Some(_) => true,
+            },
+        }
+    }
+}
+
+/// A stored value as the JSON filters see it
+pub fn parse_value(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+fn lookup<'a>(value: &'a Value, path: &[Segment]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |value, segment| match segment {
+        Segment::Field(field) => value.get(field),
+        Segment::Index(index) => value.get(*index),
+    })
+}
+
+fn compare(found: &Value, op: Op, literal: &Value) -> bool {
+    let ordering = match (found, literal) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (a, b) if a == b => Some(Ordering::Equal),
+        // Different types or values that don't order are only ever not equal
+        _ => None,
+    };
+    match (op, ordering) {
+        (Op::Eq, ordering) => ordering == Some(Ordering::Equal),
+        (Op::Ne, ordering) => ordering != Some(Ordering::Equal),
+        (_, None) => false,
+        (Op::Lt, Some(ordering)) => ordering == Ordering::Less,
+        (Op::Le, Some(ordering)) => ordering != Ordering::Greater,
+        (Op::Gt, Some(ordering)) => ordering == Ordering::Greater,
+        (Op::Ge, Some(ordering)) => ordering != Ordering::Less,
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    loop {
+        rest = rest.trim_start();
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Ok(tokens),
+        };
+        let (token, len) = match c {
+            '(' => (Token::Open, 1),
+            ')' => (Token::Close, 1),
+            '&' if rest.starts_with("&&") => (Token::And, 2),
+            '|' if rest.starts_with("||") => (Token::Or, 2),
+            '=' if rest.starts_with("==") => (Token::Op(Op::Eq), 2),
+            '!' if rest.starts_with("!=") => (Token::Op(Op::Ne), 2),
+            '!' => (Token::Not, 1),
+            '<' if rest.starts_with("<=") => (Token::Op(Op::Le), 2),
+            '<' => (Token::Op(Op::Lt), 1),
+            '>' if rest.starts_with(">=") => (Token::Op(Op::Ge), 2),
+            '>' => (Token::Op(Op::Gt), 1),
+            '"' => {
+                let len = string_len(rest)?;
+                let string = serde_json::from_str(&rest[..len]).map_err(|error| {
+                    FilterError(format!("bad string {}: {error}", &rest[..len]))
+                })?;
+                (Token::Literal(string), len)
+            }
+            '-' | '0'..='9' => {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.')))
+                    .unwrap_or(rest.len());
+                let number = serde_json::from_str::<serde_json::Number>(&rest[..len])
+                    .map_err(|_| FilterError(format!("bad number {}", &rest[..len])))?;
+                (Token::Literal(Value::Number(number)), len)
+            }
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let len = rest
+                    .find(|c: char| {
+                        !(c.is_alphanumeric() || matches!(c, '_' | '-' | '$' | '.' | '[' | ']'))
+                    })
+                    .unwrap_or(rest.len());
+                let word = &rest[..len];
+                let token = match word {
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => Token::Path(parse_path(word)?),
+                };
+                (token, len)
+            }
+            c => return Err(FilterError(format!("unexpected {c:?}"))),
+        };
+        tokens.push(token);
+        rest = &rest[len..];
+    }
+}
+
+/// Length of the string literal `rest` starts with, quotes included
+fn string_len(rest: &str) -> Result<usize, FilterError> {
+    let mut escaped = false;
+    for (index, c) in rest.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Ok(index + 1),
+            _ => {}
+        }
+    }
+    Err(FilterError(
+        "a string is missing its closing quote".to_string(),
+    ))
+}
+
+/// `a.b[0].c`, `$` or `$.a` for the whole value and fields of it
+fn parse_path(word: &str) -> Result<Vec<Segment>, FilterError> {
+    let invalid = || FilterError(format!("bad path {word}"));
+    let mut path = Vec::new();
+    let rest = match word.strip_prefix('$') {
+        Some("") => return Ok(path),
+        Some(rest) => rest.strip_prefix('.').ok_or_else(invalid)?,
+        None => word,
+    };
+    for part in rest.split('.') {
+        let (field, mut indexes) = match part.find('[') {
+            Some(index) => part.split_at(index),
+            None => (part, ""),
+        };
+        if field.is_empty() || field.contains(']') {
+            return Err(invalid());
+        }
+        path.push(Segment::Field(field.to_string()));
+        while !indexes.is_empty() {
+            let end = indexes.find(']').ok_or_else(invalid)?;
+            let index = indexes[1..end].parse().map_err(|_| invalid())?;
+            path.push(Segment::Index(index));
+            indexes = &indexes[end + 1..];
+            if !indexes.is_empty() && !indexes.starts_with('[') {
+                return Err(invalid());
+            }
+        }
+    }
+    Ok(path)
+}
+
+fn unexpected(token: &Token) -> FilterError {
+    let token = match token {
+        Token::Path(_) => "path".to_string(),
+        Token::Literal(literal) => literal.to_string(),
+        Token::Op(_) => "comparison".to_string(),
+        Token::And => "&&".to_string(),
+        Token::Or => "||".to_string(),
+        Token::Not => "!".to_string(),
+        Token::Open => "(".to_string(),
+        Token::Close => ")".to_string(),
+    };
+    FilterError(format!("unexpected {token}"))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn or(&mut self, depth: usize) -> Result<Expr, FilterError> {
+        let mut expr = self.and(depth)?;
+        while self.peek() == Some(&Token::Or) {
+            self.at += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and(depth)?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Expr, FilterError> {
+        let mut expr = self.unary(depth)?;
+        while self.peek() == Some(&Token::And) {
+            self.at += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary(depth)?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Expr, FilterError> {
+        if depth > MAX_DEPTH {
+            return Err(FilterError(format!("nested deeper than {MAX_DEPTH}")));
+        }
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary(depth + 1)?))),
+            Some(Token::Open) => {
+                let expr = self.or(depth + 1)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(FilterError("a ( is missing its )".to_string())),
+                }
+            }
+            Some(Token::Path(path)) => match self.peek() {
+                Some(Token::Op(op)) => {
+                    let op = *op;
+                    self.at += 1;
+                    match self.next() {
+                        Some(Token::Literal(literal)) => Ok(Expr::Compare(path, op, literal)),
+                        _ => Err(FilterError(
+                            "a comparison needs a string, number, true, false or null on its right"
+                                .to_string(),
+                        )),
+                    }
+                }
+                _ => Ok(Expr::Truthy(path)),
+            },
+            Some(token) => Err(unexpected(&token)),
+            None => Err(FilterError("it ends too early".to_string())),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Parsing and evaluating filters
+
+use super::*;
+use serde_json::json;
+
+fn matches(filter: &str, value: &Value) -> bool {
+    Filter::parse(filter).unwrap().matches_json(value)
+}
+
+fn error(filter: &str) -> String {
+    Filter::parse(filter).unwrap_err().to_string()
+}
+
+#[test]
+fn and_binds_tighter_than_or() {
+    let value = json!({"a": true, "b": false, "c": false});
+    assert!(matches("a || b && c", &value));
+    assert!(!matches("(a || b) && c", &value));
+    assert!(matches("c && b || a", &value));
+}
+
+#[test]
+fn not_binds_tighter_than_and() {
+    let value = json!({"a": true, "b": false});
+    assert!(!matches("!a && b", &value));
+    assert!(matches("!(a && b)", &value));
+    assert!(matches("!!a", &value));
+    assert!(matches("!b || !a", &value));
+}
+
+#[test]
+fn paths() {
+    let value = json!({"a": {"b": [{"c": "deep"}, {"c": 2}]}, "status": "open"});
+    assert!(matches(r#"a.b[0].c == "deep""#, &value));
+    assert!(matches("a.b[1].c == 2", &value));
+    assert!(matches(r#"$.status == "open""#, &value));
+    assert!(matches("$", &value));
+
+    // Missing fields and items are null
+    assert!(matches("a.missing == null", &value));
+    assert!(matches("a.b[5].c == null", &value));
+    assert!(matches("status.inner == null", &value));
+    assert!(!matches("a.missing", &value));
+    assert!(matches("a.missing != 1", &value));
+
+    assert!(matches("$ > 3", &json!(5)));
+}
+
+#[test]
+fn comparisons_across_types_are_only_not_equal() {
+    let value = json!({"n": 1, "s": "1", "list": [1]});
+    assert!(matches(r#"n != "1""#, &value));
+    assert!(!matches(r#"n == "1""#, &value));
+    assert!(!matches(r#"n < "2""#, &value));
+    assert!(!matches(r#"n >= "0""#, &value));
+    assert!(!matches("s < 2", &value));
+    assert!(matches("list != 1", &value));
+    assert!(!matches("list < 2", &value));
+}
+
+#[test]
+fn comparisons_within_a_type() {
+    let value = json!({"n": 1.5, "s": "b", "t": true});
+    assert!(matches("n > 1 && n < 2 && n >= 1.5 && n <= 1.5", &value));
+    assert!(matches(r#"s > "a" && s < "c""#, &value));
+    assert!(matches("t == true && t != false", &value));
+    // Booleans don't order
+    assert!(!matches("t > false", &value));
+}
+
+#[test]
+fn truthiness() {
+    let value = json!({
+        "zero": 0,
+        "half": 0.5,
+        "empty": "",
+        "text": "x",
+        "no": false,
+        "yes": true,
+        "nothing": null,
+        "list": [],
+        "map": {},
+    });
+    for falsy in ["zero", "empty", "no", "nothing", "missing"] {
+        assert!(!matches(falsy, &value), "{falsy} is true");
+    }
+    for truthy in ["half", "text", "yes", "list", "map"] {
+        assert!(matches(truthy, &value), "{truthy} is false");
+    }
+}
+
+#[test]
+fn values_that_are_not_json_are_strings() {
+    let filter = Filter::parse(r#"$ == "on""#).unwrap();
+    assert!(filter.matches("on"));
+    assert!(!filter.matches("off"));
+    assert!(filter.matches(r#""on""#));
+
+    let filter = Filter::parse("$.status").unwrap();
+    assert!(!filter.matches("status: open"));
+    assert!(filter.matches(r#"{"status": "open"}"#));
+    assert!(Filter::parse("$ == 12").unwrap().matches("12"));
+}
+
+#[test]
+fn bad_filters() {
+    assert!(error(r#"a == "open"#).contains("missing its closing quote"));
+    assert!(error("a.[").contains("bad path a.["));
+    assert!(error("a[x] == 1").contains("bad path"));
+    assert!(error("a == 1 b").contains("unexpected path"));
+    assert!(error("a )").contains("unexpected )"));
+    assert!(error("(a").contains("missing its )"));
+    assert!(error("a &&").contains("ends too early"));
+    assert!(error("a == b").contains("needs a string, number"));
+    assert!(error("a = 1").contains("unexpected '='"));
+    assert!(error("1.2.3 == a").contains("bad number"));
+
+    let long = format!("a == \"{}\"", "x".repeat(MAX_FILTER_LEN));
+    assert!(error(&long).contains(&format!("the max is {MAX_FILTER_LEN}")));
+
+    let deep = format!("{}a", "!".repeat(MAX_DEPTH + 1));
+    assert!(error(&deep).contains("nested deeper"));
+    let deep = format!(
+        "{}a{}",
+        "(".repeat(MAX_DEPTH + 1),
+        ")".repeat(MAX_DEPTH + 1)
+    );
+    assert!(error(&deep).contains("nested deeper"));
+    let shallow = format!("{}a", "!".repeat(MAX_DEPTH));
+    assert!(Filter::parse(&shallow).is_ok());
+}
@@ -0,0 +1,105 @@
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    Error, HttpResponse,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use std::pin::Pin;
+
+/// Sends REST writes made to a follower on to its leader and answers with the leader's response.
+/// Does nothing without a leader. Channel messages aren't stored, they stay on this instance.
+pub struct ForwardWrites {
+    leader: Option<String>,
+}
+
+impl ForwardWrites {
+    pub fn new(leader: Option<String>) -> ForwardWrites {
+        ForwardWrites { leader }
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for ForwardWrites
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ForwardWritesMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ForwardWritesMiddleware {
+            service,
+            leader: self.leader.clone(),
+        })
+    }
+}
+
+pub struct ForwardWritesMiddleware<S> {
+    service: S,
+    leader: Option<String>,
+}
+
+/// Whether a request under `/v0/{secret}` writes keys
+fn is_write(req: &ServiceRequest) -> bool {
+    ![Method::GET, Method::HEAD, Method::OPTIONS].contains(req.method())
+        && !req.match_info().unprocessed().starts_with("/channels/")
+}
+
+impl<S> Service<ServiceRequest> for ForwardWritesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let leader = match &self.leader {
+            Some(leader) if is_write(&req) => leader,
+            _ => return Box::pin(self.service.call(req)),
+        };
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        let url = format!("{leader}{path}");
+        let (request, payload) = req.into_parts();
+        Box::pin(async move {
+            let mut forward = awc::Client::new()
+                .request_from(url, request.head())
+                .no_decompress();
+            forward.headers_mut().remove(header::HOST);
+            let response = match forward.send_stream(payload).await {
+                Ok(leader_response) => {
+                    let mut response = HttpResponse::build(leader_response.status());
+                    for (name, value) in leader_response.headers() {
+                        if ![
+                            header::CONTENT_LENGTH,
+                            header::TRANSFER_ENCODING,
+                            header::CONNECTION,
+                        ]
+                        .contains(name)
+                        {
+                            response.append_header((name.clone(), value.clone()));
+                        }
+                    }
+                    response.streaming(leader_response)
+                }
+                Err(error) => HttpResponse::BadGateway().body(format!(
+                    "Could not forward the write to the leader: {error}"
+                )),
+            };
+            Ok(ServiceResponse::new(request, response))
+        })
+    }
+}
@@ -0,0 +1,101 @@
+use actix_web::{http::StatusCode, HttpResponse};
+use serde::Deserialize;
+
+#[cfg(test)]
+mod tests;
+
+/// Replit DB caps keys at 1KB
+pub const DEFAULT_MAX_KEY_SIZE: usize = 1024;
+
+/// Replit DB caps values at 5MB
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 5 * 1024 * 1024;
+
+/// Size limits applied to keys and values before they reach the database
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Max size of a key in bytes
+    pub max_key_size: usize,
+
+    /// Max size of a value in bytes
+    pub max_value_size: usize,
+}
+
+/// Why a key or value was rejected
+#[derive(Debug, PartialEq, Eq)]
+pub enum LimitError {
+    KeyTooLarge { size: usize, max: usize },
+    ValueTooLarge { size: usize, max: usize },
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::KeyTooLarge { size, max } => {
+                write!(f, "Key is {size} bytes, the max key size is {max} bytes")
+            }
+            LimitError::ValueTooLarge { size, max } => write!(
+                f,
+                "Value is {size} bytes, the max value size is {max} bytes"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+impl LimitError {
+    /// Keys that are too long are a bad request, values that are too big are a 413
+    pub fn status(&self) -> StatusCode {
+        match self {
+            LimitError::KeyTooLarge { .. } => StatusCode::BAD_REQUEST,
+            LimitError::ValueTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).body(self.to_string())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check_key(&self, key: &str) -> Result<(), LimitError> {
+        if key.len() > self.max_key_size {
+            return Err(LimitError::KeyTooLarge {
+                size: key.len(),
+                max: self.max_key_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_value(&self, value: &str) -> Result<(), LimitError> {
+        if value.len() > self.max_value_size {
+            return Err(LimitError::ValueTooLarge {
+                size: value.len(),
+                max: self.max_value_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check(&self, key: &str, value: &str) -> Result<(), LimitError> {
+        self.check_key(key)?;
+        self.check_value(value)
+    }
+
+    /// Largest request body we accept. Bodies are url encoded `key=value` pairs,
+    /// so every byte can take up to three bytes on the wire.
+    pub fn max_payload_size(&self) -> usize {
+        (self.max_key_size + self.max_value_size) * 3 + 1
+    }
+}
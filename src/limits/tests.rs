@@ -0,0 +1,68 @@
+//! Checking keys and values against the limits, and the status each rejection gets
+
+use super::*;
+
+const LIMITS: Limits = Limits {
+    max_key_size: 4,
+    max_value_size: 8,
+};
+
+#[test]
+fn keys_up_to_the_max_pass() {
+    assert_eq!(LIMITS.check_key("abcd"), Ok(()));
+    assert_eq!(
+        LIMITS.check_key("abcde"),
+        Err(LimitError::KeyTooLarge { size: 5, max: 4 })
+    );
+    // Sizes are in bytes, not characters
+    assert_eq!(
+        LIMITS.check_key("ééé"),
+        Err(LimitError::KeyTooLarge { size: 6, max: 4 })
+    );
+}
+
+#[test]
+fn values_up_to_the_max_pass() {
+    assert_eq!(LIMITS.check_value(""), Ok(()));
+    assert_eq!(LIMITS.check_value("12345678"), Ok(()));
+    assert_eq!(
+        LIMITS.check_value("123456789"),
+        Err(LimitError::ValueTooLarge { size: 9, max: 8 })
+    );
+}
+
+#[test]
+fn the_key_is_checked_first() {
+    assert_eq!(LIMITS.check("abcd", "12345678"), Ok(()));
+    assert_eq!(
+        LIMITS.check("abcde", "123456789"),
+        Err(LimitError::KeyTooLarge { size: 5, max: 4 })
+    );
+    assert_eq!(
+        LIMITS.check("abcd", "123456789"),
+        Err(LimitError::ValueTooLarge { size: 9, max: 8 })
+    );
+}
+
+#[test]
+fn large_keys_are_bad_requests_and_large_values_too_large() {
+    let key = LIMITS.check_key("abcde").unwrap_err();
+    assert_eq!(key.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(key.to_response().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        key.to_string(),
+        "Key is 5 bytes, the max key size is 4 bytes"
+    );
+
+    let value = LIMITS.check_value("123456789").unwrap_err();
+    assert_eq!(value.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(value.to_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[test]
+fn the_payload_fits_the_largest_url_encoded_pair() {
+    assert_eq!(LIMITS.max_payload_size(), 37);
+    // The largest key and value, every byte percent encoded, and the `=` between them
+    let body = format!("{}={}", "%FF".repeat(4), "%FF".repeat(8));
+    assert_eq!(body.len(), LIMITS.max_payload_size());
+}
@@ -1,11 +1,24 @@
-use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
-
 mod actors;
 mod auth_middleware;
+mod backup_scheduler;
+mod channels;
+mod cli;
+mod cluster;
+mod config;
 mod controllers;
 mod data_access;
+mod data_lock;
+mod filter;
+mod follower_middleware;
+mod limits;
+mod replication;
+mod storage;
+mod triggers;
+mod waiters;
+mod webhooks;
 
 use std::{
+    collections::VecDeque,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -14,22 +27,27 @@ use std::{
 };
 
 use actix::*;
-use actix_files::{Files, NamedFile};
+use actix_cors::Cors;
 use actix_web::{
     middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use actix_web_actors::ws;
-use actors::{session::WsChatSession, ws_actor::ClientWebSocketConnection};
-use controllers::key_controller::*;
-use diesel::r2d2::ManageConnection;
-// extern crate diesel_migrations;
-use diesel::{
-    prelude::*,
-    r2d2::{self, ConnectionManager},
+use actors::{
+    outbox::{Outbox, WsMetrics},
+    session::WsChatSession,
+    ws_actor::ClientWebSocketConnection,
 };
-use std::env;
-
-use data_access::migrations;
+use channels::{Access, ChannelsConfig};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::{Config, CorsConfig, FollowerWrites, StorageBackend, WebSocketConfig};
+use controllers::{
+    admin_controller, channel_controller, cluster_controller, key_controller::*,
+    presence_controller, trigger_controller, watch_controller, webhook_controller,
+};
+use data_access::{backup, pool};
+use limits::Limits;
+use storage::{read_only::ReadOnlyStorage, triggered::TriggeredStorage, Storage};
 use uuid::Uuid;
 
 extern crate dotenv;
@@ -38,23 +56,51 @@ extern crate dotenv;
 //     NamedFile::open_async("./static/index.html").await.unwrap()
 // }
 
-/// Entry point for our websocket route
+/// Entry point for our websocket route, under `/v0/{secret}` or a channel token's `/channels/{token}`
 async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<ClientWebSocketConnection>>,
+    storage: web::Data<dyn Storage>,
+    limits: web::Data<Limits>,
+    websocket: web::Data<WebSocketConfig>,
+    metrics: web::Data<WsMetrics>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(
+    let limits = *limits.into_inner();
+    let outbox = Outbox::new(*websocket.into_inner(), metrics.into_inner());
+    let channels = req
+        .app_data::<web::Data<ChannelsConfig>>()
+        .expect("the channels config is app data")
+        .clone()
+        .into_inner();
+    let access = match req.match_info().get("token") {
+        Some(token) if channels.knows(token) => Access::Token(token.to_string()),
+        Some(_) => {
+            return Ok(HttpResponse::Unauthorized().body("You do not have the correct secret"))
+        }
+        None => Access::Secret,
+    };
+    ws::WsResponseBuilder::new(
         WsChatSession {
             id: Uuid::new_v4(),
             hb: Instant::now(),
             room: "main".to_owned(),
             name: None,
             addr: srv.get_ref().clone(),
+            storage: storage.into_inner(),
+            limits,
+            commands: VecDeque::new(),
+            running: false,
+            outbox: Arc::new(outbox),
+            access,
+            channels,
         },
         &req,
         stream,
     )
+    // room for a set command with the largest key and value
+    .frame_size(limits.max_payload_size())
+    .start()
 }
 
 /// Displays state
@@ -74,66 +120,258 @@ async fn get_count(count: web::Data<AtomicUsize>) -> impl Responder {
 //     };
 // }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "debug");
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+/// Builds the CORS middleware from the allowed origins
+fn cors(config: &CorsConfig) -> Cors {
+    let mut cors = Cors::default()
+        .allow_any_method()
+        .allow_any_header()
+        .max_age(3600);
+    for origin in &config.allowed_origins {
+        cors = match origin.as_str() {
+            "*" => cors.allow_any_origin(),
+            _ => cors.allowed_origin(origin),
+        };
+    }
+    cors
+}
+
+fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
+    let cli = Cli::parse();
+    let config = match Config::load(&cli.config) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(2);
+        }
+    };
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or(&config.log.level));
+
+    match cli.command.unwrap_or(Command::Serve { restore_from: None }) {
+        Command::Serve { restore_from } => {
+            // Held until the server exits, so CLI writes can't go behind its back
+            let _lock = match data_lock::acquire(&config) {
+                Ok(lock) => lock,
+                Err(error) => {
+                    eprintln!("Could not start: {error}");
+                    std::process::exit(1);
+                }
+            };
+            if let Some(backup_path) = restore_from {
+                if let Err(error) = backup::restore_from(&backup_path, &config.database.url) {
+                    eprintln!("Could not restore {}: {error}", backup_path.display());
+                    std::process::exit(1);
+                }
+                log::info!("restored the database from {}", backup_path.display());
+            }
+            actix_web::rt::System::new().block_on(serve(config))
+        }
+        command => {
+            if let Err(error) = cli::run(command, &config) {
+                eprintln!("{error}");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Starts the HTTP and WebSocket server
+async fn serve(config: Config) -> std::io::Result<()> {
+    if config.secret.is_none() {
+        log::warn!("No secret is set, every request to /v0 will be rejected");
+    }
+
     // set up applications state
     // keep a count of the number of visitors
     let app_state = Arc::new(AtomicUsize::new(0));
-    // start chat server actor
-    let server = ClientWebSocketConnection::new(app_state.clone()).start();
-
-    // set up database connection pool
-    let conn_spec = match env::var("DATABASE_URL") {
-        Ok(db_name) => db_name,
-        Err(_) => "tinybase.db".to_string(),
+    // the storage, webhooks and triggers share one pool, migrated once here
+    let pool = match pool::open(&config.database) {
+        Ok(pool) => pool,
+        Err(error) => {
+            eprintln!("Could not open the database: {error}");
+            std::process::exit(1);
+        }
+    };
+    let storage = match storage::open(&config, Some(pool.clone())) {
+        Ok(storage) => storage,
+        Err(error) => {
+            eprintln!("Could not open storage: {error}");
+            std::process::exit(1);
+        }
+    };
+    // the in-memory backend is its own cache
+    let (storage, cache_metrics) = match config.storage.backend {
+        StorageBackend::Memory => (storage, None),
+        _ => storage::cache::wrap(storage, &config.cache),
     };
+    let triggers = match triggers::Triggers::open(pool.clone(), config.triggers, config.limits) {
+        Ok(triggers) => triggers,
+        Err(error) => {
+            eprintln!("Could not open the triggers: {error}");
+            std::process::exit(1);
+        }
+    };
+    // a follower only writes what the leader sends, it already ran the triggers
+    let (storage, follower) = match &config.replication.leader {
+        Some(leader) => {
+            let secret = config.cluster.secret.as_deref().unwrap_or_default();
+            let follower = replication::start(&config.replication, secret, storage.clone());
+            let read_only: Arc<dyn Storage> =
+                Arc::new(ReadOnlyStorage::new(storage, leader.clone()));
+            (read_only, follower)
+        }
+        None => {
+            let triggered: Arc<dyn Storage> =
+                Arc::new(TriggeredStorage::new(storage, triggers.clone()));
+            (triggered, None)
+        }
+    };
+    let forward_writes = match config.replication.writes {
+        FollowerWrites::Proxy => config.replication.leader.clone(),
+        FollowerWrites::Reject => None,
+    };
+    // start chat server actor, it forwards every committed change to listeners
+    let server = ClientWebSocketConnection::new(app_state.clone(), storage.clone()).start();
 
-    let manager = ConnectionManager::<SqliteConnection>::new(conn_spec);
+    // long-polls waiting on a key are woken by the same change stream
+    let waiters = waiters::Waiters::start(storage.watch());
 
-    let pool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool.");
-    let mut conn = pool.get().expect("Could not get instance of the DB");
-    migrations::run(&mut conn).unwrap();
-    drop(conn);
+    // changes committed on peers reach our listeners too
+    let node = cluster::NodeId(Uuid::new_v4());
+    cluster::start(
+        &config.cluster,
+        node,
+        server.clone(),
+        waiters.clone(),
+        config.limits,
+    );
 
-    let port: u16 = match env::var("DB_PORT") {
-        Ok(unwrapped_port) => unwrapped_port.parse().unwrap(),
-        Err(_) => 8080,
+    // webhooks are told about changes committed here, including the ones a follower replicates
+    let webhooks = match webhooks::Webhooks::open(pool.clone(), config.webhooks) {
+        Ok(webhooks) => webhooks,
+        Err(error) => {
+            eprintln!("Could not open the webhooks: {error}");
+            std::process::exit(1);
+        }
     };
+    webhooks.start(storage.clone());
 
-    let host = match env::var("DB_HOST") {
-        Ok(unwrapped_host) => unwrapped_host,
-        Err(_) => "0.0.0.0".to_string(),
-    };
+    storage::change_log::start_pruning(storage.clone(), config.changelog.clone());
+    backup_scheduler::start(storage.clone(), config.backup.clone());
 
-    log::info!("starting HTTP server at http://{host}:{port}");
+    let limits = config.limits;
+    let websocket = config.websocket;
+    let channels = config.channels.clone();
+    let ws_metrics = Arc::new(WsMetrics::default());
+    let host = config.server.host.clone();
+    let port = config.server.port;
+    let tls = match &config.tls {
+        Some(tls) => match tls.load_rustls() {
+            Ok(tls) => Some(tls),
+            Err(error) => {
+                eprintln!("{error}");
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
 
-    HttpServer::new(move || {
+    let app_config = config.clone();
+    let http_server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::from(storage.clone()))
+            .app_data(web::Data::from(waiters.clone()))
+            .configure(|cfg| {
+                if let Some(metrics) = &cache_metrics {
+                    cfg.app_data(web::Data::from(metrics.clone()));
+                }
+                if let Some(follower) = &follower {
+                    cfg.app_data(web::Data::from(follower.clone()));
+                }
+            })
             .app_data(web::Data::from(app_state.clone()))
             .app_data(web::Data::new(server.clone()))
+            .app_data(web::Data::new(limits))
+            .app_data(web::Data::new(websocket))
+            .app_data(web::Data::new(channels.clone()))
+            .app_data(web::Data::from(ws_metrics.clone()))
+            .app_data(web::Data::new(node))
+            .app_data(web::Data::from(webhooks.clone()))
+            .app_data(web::Data::from(triggers.clone()))
+            .app_data(web::PayloadConfig::new(limits.max_payload_size()))
+            .app_data(web::JsonConfig::default().limit(limits.max_payload_size()))
             // .service(Files::new("/static", "./static"))
             // .service(web::resource("/").to(index))
             .service(
                 web::scope("/v0/{secret}")
                     .route("/ws", web::get().to(chat_route))
+                    .service(transaction)
+                    .service(watch_controller::watch)
+                    .service(presence_controller::presence)
+                    .service(channel_controller::publish)
                     .service(url_create_key)
                     .service(create_key)
                     .service(get_key)
                     .service(list_keys)
                     .service(delete_key)
-                    .wrap(auth_middleware::CheckForSecret),
+                    .wrap(follower_middleware::ForwardWrites::new(
+                        forward_writes.clone(),
+                    ))
+                    .wrap(auth_middleware::CheckForSecret::new(
+                        app_config.secret.clone(),
+                    )),
+            )
+            .service(
+                web::scope("/admin/{secret}")
+                    .service(admin_controller::export)
+                    .service(admin_controller::import)
+                    .service(admin_controller::download_backup)
+                    .service(admin_controller::write_backup)
+                    .service(admin_controller::cache_stats)
+                    .service(admin_controller::websocket_stats)
+                    .service(admin_controller::replication_status)
+                    .service(webhook_controller::register)
+                    .service(webhook_controller::list)
+                    .service(webhook_controller::dead_letters)
+                    .service(webhook_controller::retry_dead_letter)
+                    .service(webhook_controller::delete_dead_letter)
+                    .service(webhook_controller::remove)
+                    .service(trigger_controller::register)
+                    .service(trigger_controller::list)
+                    .service(trigger_controller::remove)
+                    .wrap(auth_middleware::CheckForSecret::new(
+                        app_config.admin_secret.clone(),
+                    )),
+            )
+            .service(
+                web::scope("/cluster/{secret}")
+                    .service(cluster_controller::changes)
+                    .service(cluster_controller::seq)
+                    .service(cluster_controller::replicate)
+                    .wrap(auth_middleware::CheckForSecret::new(
+                        app_config.cluster.secret.clone(),
+                    )),
+            )
+            .service(
+                web::scope("/channels/{token}")
+                    .route("/ws", web::get().to(chat_route))
+                    .service(channel_controller::publish_with_token),
             )
             .route("/count", web::get().to(get_count))
+            .wrap(cors(&app_config.cors))
             .wrap(Logger::default())
     })
-    .workers(2)
-    .bind((host, port))?
-    .run()
-    .await
+    .workers(config.server.workers);
+
+    match tls {
+        Some(tls) => {
+            log::info!("starting HTTPS server at https://{host}:{port}");
+            http_server.bind_rustls((host, port), tls)?.run().await
+        }
+        None => {
+            log::info!("starting HTTP server at http://{host}:{port}");
+            http_server.bind((host, port))?.run().await
+        }
+    }
 }
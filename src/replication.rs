@@ -0,0 +1,522 @@
+//! Keeps a read-only follower in sync with a leader.
+//!
+//! The follower reads the leader's feed at `/cluster/{secret}/replicate`, server-sent
+//! events written by `feed`. A new feed starts with every key on the leader, a page
+//! at a time in key order, and each page replaces that range of keys on the follower.
+//! A `synced` event ends them, then every group of changes committed together on the
+//! leader comes as one event and is applied as one batch. Keys get the versions they
+//! have on the leader. A lost connection resumes from the last change applied with
+//! `Last-Event-ID`, or starts over with the keys if the leader's change log no longer
+//! goes back that far.
+//!
+//! The pages are read while writes go on, so the feed replays the change log from
+//! where it was before the first page and the follower ends up where the leader is.
+//! Changes replayed from the change log come a page at a time, the log doesn't keep
+//! which of them were committed together.
+//!
+//! Sequence numbers in the follower's own change log are its own, the leader's
+//! are only used to resume and to work out the lag.
+
+use crate::config::ReplicationConfig;
+use crate::storage::{
+    Change, Changes, ChangesPruned, Event, Replicated, Storage, StorageError, VersionedEntry,
+};
+use actix_web::{rt, web, web::Bytes};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+#[cfg(test)]
+mod tests;
+
+/// First wait before connecting to the leader again
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait before connecting to the leader again
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The leader sends a keep-alive every 15 seconds, a stream quiet for longer than this is gone
+const LEADER_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Keys the feed sends at a time, and the follower reads at a time to compare them with its own
+const SNAPSHOT_PAGE: usize = 1000;
+
+/// Changes the feed reads from the change log at a time while it catches up
+const CATCH_UP_PAGE: usize = 1000;
+
+/// Events the feed queues before it waits for the follower to read them
+const FEED_BUFFER: usize = 16;
+
+/// Where the follower is, for `GET /admin/{secret}/replication`
+#[derive(Debug)]
+pub struct Follower {
+    pub leader: String,
+    /// Cluster secret, the leader needs it too
+    secret: String,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    connected: bool,
+    /// Leader sequence number of the last change applied, none until the first snapshot
+    applied: Option<u64>,
+    /// When the last change or snapshot from the leader was applied
+    applied_at: Option<Instant>,
+    /// Why the last connection ended
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FollowerStatus {
+    pub leader: String,
+    pub connected: bool,
+    pub applied_seq: Option<u64>,
+    pub leader_seq: Option<u64>,
+    /// Changes committed on the leader and not applied here yet
+    pub lag: Option<u64>,
+    /// Seconds since the last change was applied
+    pub last_applied_secs: Option<f64>,
+    pub error: Option<String>,
+}
+
+/// Answer of the leader's `/cluster/{secret}/seq`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaderSeq {
+    pub seq: u64,
+}
+
+/// A server-sent event from the leader
+#[derive(Debug, Default)]
+struct Message {
+    id: Option<u64>,
+    event: String,
+    data: String,
+}
+
+impl Follower {
+    /// Where the follower is, asking the leader for its last sequence number to work out the lag
+    pub async fn status(&self) -> FollowerStatus {
+        let leader_seq = leader_seq(&self.leader, &self.secret).await;
+        let state = self.state.lock().unwrap();
+        FollowerStatus {
+            leader: self.leader.clone(),
+            connected: state.connected,
+            applied_seq: state.applied,
+            lag: match (leader_seq, state.applied) {
+                (Some(leader), Some(applied)) => Some(leader.saturating_sub(applied)),
+                _ => None,
+            },
+            leader_seq,
+            last_applied_secs: state
+                .applied_at
+                .map(|applied_at| applied_at.elapsed().as_secs_f64()),
+            error: state.error.clone(),
+        }
+    }
+
+    fn applied(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.applied = Some(seq);
+        state.applied_at = Some(Instant::now());
+    }
+}
+
+async fn leader_seq(leader: &str, secret: &str) -> Option<u64> {
+    let url = format!("{leader}/cluster/{}/seq", urlencoding::encode(secret));
+    let mut response = awc::Client::new().get(url).send().await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response
+        .json::<LeaderSeq>()
+        .await
+        .ok()
+        .map(|leader| leader.seq)
+}
+
+/// Starts following the leader, writing what it sends to `storage`
+pub fn start(
+    config: &ReplicationConfig,
+    secret: &str,
+    storage: Arc<dyn Storage>,
+) -> Option<Arc<Follower>> {
+    let leader = config.leader.as_ref()?.trim_end_matches('/').to_string();
+    let follower = Arc::new(Follower {
+        leader: leader.clone(),
+        secret: secret.to_string(),
+        state: Mutex::new(State::default()),
+    });
+    let url = format!("{leader}/cluster/{}/replicate", urlencoding::encode(secret));
+    let replica = follower.clone();
+    rt::spawn(async move {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let result = follow(&url, &replica, &storage, &mut backoff).await;
+            {
+                let mut state = replica.state.lock().unwrap();
+                state.connected = false;
+                if let Err(error) = result {
+                    log::warn!("replication from {leader}: {error}");
+                    state.error = Some(error);
+                }
+            }
+            rt::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+    Some(follower)
+}
+
+/// Applies what the leader streams until the connection ends, resets the backoff once connected
+async fn follow(
+    url: &str,
+    follower: &Follower,
+    storage: &Arc<dyn Storage>,
+    backoff: &mut Duration,
+) -> Result<(), String> {
+    let resume = follower.state.lock().unwrap().applied;
+    let mut request = awc::Client::new().get(url);
+    if let Some(seq) = resume {
+        request = request.insert_header(("Last-Event-ID", seq.to_string()));
+    }
+    let mut response = request
+        .send()
+        .await
+        .map_err(|error| format!("could not connect: {error}"))?;
+    if !response.status().is_success() {
+        return Err(format!("the leader answered {}", response.status()));
+    }
+    log::info!("following the leader at {}", follower.leader);
+    {
+        let mut state = follower.state.lock().unwrap();
+        state.connected = true;
+        state.error = None;
+    }
+    *backoff = MIN_BACKOFF;
+
+    let mut replica = Replica::new(follower, storage);
+    loop {
+        let chunk = match rt::time::timeout(LEADER_TIMEOUT, response.next()).await {
+            Ok(Some(chunk)) => chunk.map_err(|error| error.to_string())?,
+            Ok(None) => return Err("the leader ended the stream".to_string()),
+            Err(_) => return Err("the leader stopped sending keep-alives".to_string()),
+        };
+        replica.receive(&chunk).await?;
+    }
+}
+
+/// Applies the leader's stream to the follower as it comes in
+struct Replica<'a> {
+    follower: &'a Follower,
+    storage: &'a Arc<dyn Storage>,
+    /// Chunks can end in the middle of a character, events are only decoded once they are complete
+    buffer: Vec<u8>,
+    /// Last key of the snapshot pages applied so far, the next page replaces the keys after it
+    replaced: Option<String>,
+}
+
+impl<'a> Replica<'a> {
+    fn new(follower: &'a Follower, storage: &'a Arc<dyn Storage>) -> Replica<'a> {
+        Replica {
+            follower,
+            storage,
+            buffer: Vec::new(),
+            replaced: None,
+        }
+    }
+
+    /// Applies every event `chunk` completes
+    async fn receive(&mut self, chunk: &[u8]) -> Result<(), String> {
+        self.buffer.extend_from_slice(chunk);
+        while let Some(end) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+            let message = parse_message(&String::from_utf8_lossy(&self.buffer[..end]));
+            self.buffer.drain(..end + 2);
+            self.handle(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, message: Message) -> Result<(), String> {
+        let storage = self.storage;
+        let follower = self.follower;
+        match message.event.as_str() {
+            "entries" => {
+                let entries: Vec<VersionedEntry> = parse_data(&message)?;
+                let after = self.replaced.take();
+                self.replaced = entries.last().map(|entry| entry.key.clone());
+                let until = self.replaced.clone();
+                apply(storage, move |storage| {
+                    replace_range(storage, after, until, entries)
+                })
+                .await?;
+            }
+            // The keys after the last page aren't on the leader
+            "synced" => {
+                let synced: LeaderSeq = parse_data(&message)?;
+                let after = self.replaced.take();
+                apply(storage, move |storage| {
+                    replace_range(storage, after, None, Vec::new())
+                })
+                .await?;
+                follower.applied(synced.seq);
+                log::info!("replicated the leader's keys at {}", synced.seq);
+            }
+            "changes" => {
+                let seq = message
+                    .id
+                    .ok_or_else(|| "changes without an id".to_string())?;
+                let events: Vec<Event> = parse_data(&message)?;
+                let writes = events
+                    .into_iter()
+                    .map(|event| match event.change {
+                        // A key's version is the sequence number of the change that last wrote it
+                        Change::Set { key, value } => Replicated::Set {
+                            key,
+                            value,
+                            version: event.seq,
+                        },
+                        Change::Delete { key } => Replicated::Delete { key },
+                    })
+                    .collect();
+                apply(storage, move |storage| storage.replicate(writes)).await?;
+                follower.applied(seq);
+            }
+            // Our place is gone from the leader's change log, start over with a snapshot
+            "resync" => {
+                follower.state.lock().unwrap().applied = None;
+                return Err("the leader no longer has the changes we missed, reloading".into());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Reads the fields of one event, comments like keep-alives have none
+fn parse_message(text: &str) -> Message {
+    let mut message = Message::default();
+    for line in text.lines() {
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "id" => message.id = value.parse().ok(),
+            "event" => message.event = value.to_string(),
+            "data" => {
+                if !message.data.is_empty() {
+                    message.data.push('\n');
+                }
+                message.data.push_str(value);
+            }
+            _ => {}
+        }
+    }
+    message
+}
+
+fn parse_data<T: for<'a> Deserialize<'a>>(message: &Message) -> Result<T, String> {
+    serde_json::from_str(&message.data)
+        .map_err(|error| format!("bad {} event from the leader: {error}", message.event))
+}
+
+async fn apply<F>(storage: &Arc<dyn Storage>, write: F) -> Result<(), String>
+where
+    F: FnOnce(&dyn Storage) -> Result<(), StorageError> + Send + 'static,
+{
+    let storage = storage.clone();
+    match web::block(move || write(storage.as_ref())).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(error)) => Err(format!("could not apply a change: {error}")),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Makes the keys after `after` up to `until` exactly `entries`, or every key after `after` without
+/// `until`. Only what differs is written so listeners don't hear about the rest.
+fn replace_range(
+    storage: &dyn Storage,
+    after: Option<String>,
+    until: Option<String>,
+    entries: Vec<VersionedEntry>,
+) -> Result<(), StorageError> {
+    let mut current: HashMap<String, (String, u64)> = HashMap::new();
+    let mut from = after;
+    loop {
+        let page = storage.scan_versioned("", from.as_deref(), SNAPSHOT_PAGE)?;
+        let done = page.len() < SNAPSHOT_PAGE;
+        from = page.last().map(|entry| entry.key.clone());
+        for entry in page {
+            if until.as_ref().is_some_and(|until| entry.key > *until) {
+                break;
+            }
+            current.insert(entry.key, (entry.value, entry.version));
+        }
+        let past_until = match (&from, &until) {
+            (Some(from), Some(until)) => from > until,
+            _ => false,
+        };
+        if done || past_until {
+            break;
+        }
+    }
+
+    let mut writes = Vec::new();
+    for entry in entries {
+        let leader = (entry.value, entry.version);
+        if current.remove(&entry.key).as_ref() != Some(&leader) {
+            writes.push(Replicated::Set {
+                key: entry.key,
+                value: leader.0,
+                version: leader.1,
+            });
+        }
+    }
+    writes.extend(current.into_keys().map(|key| Replicated::Delete { key }));
+    if !writes.is_empty() {
+        storage.replicate(writes)?;
+    }
+    Ok(())
+}
+
+/// Streams this instance's keys and changes to a follower, from every key or after the
+/// sequence number `after`. Ends when the follower goes away.
+pub fn feed(storage: Arc<dyn Storage>, after: Option<u64>) -> ReceiverStream<Bytes> {
+    let (sender, receiver) = mpsc::channel(FEED_BUFFER);
+    rt::spawn(async move {
+        if let Err(error) = send_feed(&storage, after, &sender).await {
+            log::info!("replication feed ended: {error}");
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+async fn send_feed(
+    storage: &Arc<dyn Storage>,
+    after: Option<u64>,
+    sender: &mpsc::Sender<Bytes>,
+) -> Result<(), String> {
+    // Subscribed before reading anything so no commit falls in between
+    let mut changes = storage.watch();
+    let mut last = match after {
+        Some(after) => after,
+        None => send_snapshot(storage, sender).await?,
+    };
+    last = send_since(storage, last, sender).await?;
+    loop {
+        let received = tokio::select! {
+            received = changes.recv() => received,
+            _ = sender.closed() => return Err("the follower went away".to_string()),
+        };
+        match received {
+            Ok(Changes(events)) => {
+                let events: Vec<Event> = events
+                    .into_iter()
+                    .filter(|event| event.seq > last)
+                    .collect();
+                if let Some(event) = events.last() {
+                    last = event.seq;
+                    send(sender, "changes", Some(last), &events).await?;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                last = send_since(storage, last, sender).await?
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Sends every key a page at a time, returns the sequence number to replay changes from
+async fn send_snapshot(
+    storage: &Arc<dyn Storage>,
+    sender: &mpsc::Sender<Bytes>,
+) -> Result<u64, String> {
+    let seq = read(storage, |storage| storage.last_seq()).await?;
+    let mut after: Option<String> = None;
+    loop {
+        let from = after.take();
+        let page = read(storage, move |storage| {
+            storage.scan_versioned("", from.as_deref(), SNAPSHOT_PAGE)
+        })
+        .await?;
+        let done = page.len() < SNAPSHOT_PAGE;
+        if let Some(entry) = page.last() {
+            after = Some(entry.key.clone());
+            send(sender, "entries", None, &page).await?;
+        }
+        if done {
+            break;
+        }
+    }
+    send(sender, "synced", Some(seq), &LeaderSeq { seq }).await?;
+    Ok(seq)
+}
+
+/// Sends the changes after `after` from the change log a page at a time, returns the last one sent.
+/// Tells the follower to reload if the log no longer goes back that far.
+async fn send_since(
+    storage: &Arc<dyn Storage>,
+    mut after: u64,
+    sender: &mpsc::Sender<Bytes>,
+) -> Result<u64, String> {
+    loop {
+        let from = after;
+        let page = match web::block({
+            let storage = storage.clone();
+            move || storage.changes_since("", from, CATCH_UP_PAGE)
+        })
+        .await
+        {
+            Ok(Ok(page)) => page,
+            Ok(Err(error)) if error.is::<ChangesPruned>() => {
+                send(sender, "resync", None, &serde_json::json!({})).await?;
+                return Err(error.to_string());
+            }
+            Ok(Err(error)) => return Err(error.to_string()),
+            Err(error) => return Err(error.to_string()),
+        };
+        let done = page.len() < CATCH_UP_PAGE;
+        if let Some(event) = page.last() {
+            after = event.seq;
+            send(sender, "changes", Some(after), &page).await?;
+        }
+        if done {
+            return Ok(after);
+        }
+    }
+}
+
+/// Queues one event for the follower, fails once it is gone
+async fn send<T: Serialize>(
+    sender: &mpsc::Sender<Bytes>,
+    event: &str,
+    id: Option<u64>,
+    data: &T,
+) -> Result<(), String> {
+    let data = serde_json::to_string(data).map_err(|error| error.to_string())?;
+    let text = match id {
+        Some(id) => format!("id: {id}\nevent: {event}\ndata: {data}\n\n"),
+        None => format!("event: {event}\ndata: {data}\n\n"),
+    };
+    sender
+        .send(Bytes::from(text))
+        .await
+        .map_err(|_| "the follower went away".to_string())
+}
+
+async fn read<T, F>(storage: &Arc<dyn Storage>, read: F) -> Result<T, String>
+where
+    F: FnOnce(&dyn Storage) -> Result<T, StorageError> + Send + 'static,
+    T: Send + 'static,
+{
+    let storage = storage.clone();
+    match web::block(move || read(storage.as_ref())).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(error)) => Err(error.to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
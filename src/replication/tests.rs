@@ -0,0 +1,174 @@
+//! A follower fed by the leader's own feed, without the HTTP in between
+
+use super::*;
+use crate::config::ChangeLogConfig;
+use crate::storage::{MemoryStorage, WriteOp};
+use actix_web::{App, HttpResponse, HttpServer};
+
+fn follower(leader: &str) -> Follower {
+    Follower {
+        leader: leader.to_string(),
+        secret: "cluster".to_string(),
+        state: Mutex::default(),
+    }
+}
+
+fn applied(follower: &Follower) -> Option<u64> {
+    follower.state.lock().unwrap().applied
+}
+
+/// Applies what the feed sends until the follower is at `seq`
+async fn receive_until(replica: &mut Replica<'_>, feed: &mut ReceiverStream<Bytes>, seq: u64) {
+    while applied(replica.follower) != Some(seq) {
+        let chunk = rt::time::timeout(Duration::from_secs(5), feed.next())
+            .await
+            .expect("timed out")
+            .unwrap();
+        replica.receive(&chunk).await.unwrap();
+    }
+}
+
+fn everything(storage: &Arc<dyn Storage>) -> Vec<VersionedEntry> {
+    storage.scan_versioned("", None, usize::MAX).unwrap()
+}
+
+#[actix_rt::test]
+async fn a_fresh_follower_gets_every_page_then_the_live_changes() {
+    let leader: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let keys = 2 * SNAPSHOT_PAGE + 1;
+    leader
+        .batch(
+            (0..keys)
+                .map(|i| WriteOp::Set {
+                    key: format!("k{i:05}"),
+                    value: i.to_string(),
+                })
+                .collect(),
+        )
+        .unwrap();
+    leader.set("k00001".into(), "again".into()).unwrap();
+
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let follower = follower("http://127.0.0.1:1");
+    let mut replica = Replica::new(&follower, &storage);
+    let mut feed = feed(leader.clone(), None);
+    receive_until(&mut replica, &mut feed, leader.last_seq().unwrap()).await;
+    assert_eq!(everything(&storage).len(), keys);
+    assert_eq!(everything(&storage), everything(&leader));
+
+    leader.set("k00000".into(), "live".into()).unwrap();
+    leader.delete("k00002").unwrap();
+    leader.set("new".into(), "key".into()).unwrap();
+    receive_until(&mut replica, &mut feed, leader.last_seq().unwrap()).await;
+    assert_eq!(everything(&storage), everything(&leader));
+}
+
+#[actix_rt::test]
+async fn a_resync_replaces_the_keys_instead_of_merging_them() {
+    let leader: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    for key in ["a", "b", "c"] {
+        leader.set(key.into(), "1".into()).unwrap();
+    }
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let follower = follower("http://127.0.0.1:1");
+    let mut replica = Replica::new(&follower, &storage);
+    receive_until(&mut replica, &mut feed(leader.clone(), None), 3).await;
+
+    // Missed while disconnected, and gone from the leader's change log
+    leader.delete("b").unwrap();
+    leader.set("c".into(), "2".into()).unwrap();
+    leader.set("d".into(), "1".into()).unwrap();
+    leader
+        .prune_changes(&ChangeLogConfig {
+            retain_secs: 0,
+            max_entries: 1,
+        })
+        .unwrap();
+
+    let mut replica = Replica::new(&follower, &storage);
+    let mut resumed = feed(leader.clone(), Some(3));
+    let chunk = resumed.next().await.unwrap();
+    assert!(replica.receive(&chunk).await.is_err());
+    assert_eq!(applied(&follower), None);
+
+    let mut changes = storage.watch();
+    let mut replica = Replica::new(&follower, &storage);
+    receive_until(&mut replica, &mut feed(leader.clone(), None), 6).await;
+    assert_eq!(everything(&storage), everything(&leader));
+    let Changes(written) = changes.try_recv().unwrap();
+    let mut keys: Vec<&str> = written.iter().map(|event| event.change.key()).collect();
+    keys.sort();
+    // `a` didn't change, so listeners don't hear about it
+    assert_eq!(keys, vec!["b", "c", "d"]);
+    assert!(changes.try_recv().is_err());
+}
+
+#[actix_rt::test]
+async fn events_can_be_split_anywhere() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let follower = follower("http://127.0.0.1:1");
+    let mut replica = Replica::new(&follower, &storage);
+    let stream = concat!(
+        ": keep-alive\n\n",
+        "event: entries\n",
+        "data: [{\"key\":\"a\",\"value\":\"caf\u{e9}\",\"version\":4}]\n\n",
+        "id: 4\nevent: synced\ndata: {\"seq\":4}\n\n",
+        "id: 5\nevent: changes\n",
+        "data: [{\"seq\":5,\"event\":\"set\",\"key\":\"b\",\n",
+        "data: \"value\":\"2\"}]\n\n",
+    );
+    // One byte at a time, so the `é` arrives in two halves
+    for byte in stream.as_bytes() {
+        replica.receive(&[*byte]).await.unwrap();
+    }
+    assert_eq!(applied(&follower), Some(5));
+    assert_eq!(
+        storage.get_versioned("a").unwrap().unwrap().value,
+        "caf\u{e9}"
+    );
+    assert_eq!(storage.get_versioned("b").unwrap().unwrap().version, 5);
+
+    let error = replica
+        .receive(b"event: changes\ndata: []\n\n")
+        .await
+        .unwrap_err();
+    assert!(error.contains("without an id"), "{error}");
+    let error = replica
+        .receive(b"id: 6\nevent: changes\ndata: {\n\n")
+        .await
+        .unwrap_err();
+    assert!(error.contains("bad changes event"), "{error}");
+}
+
+#[actix_rt::test]
+async fn the_lag_is_how_far_behind_the_leader_it_is() {
+    let server = HttpServer::new(|| {
+        App::new().route(
+            "/cluster/cluster/seq",
+            web::get().to(|| async { HttpResponse::Ok().json(LeaderSeq { seq: 10 }) }),
+        )
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let leader = format!("http://{}", server.addrs()[0]);
+    rt::spawn(server.run());
+
+    let follower = follower(&leader);
+    let status = follower.status().await;
+    assert_eq!(status.leader_seq, Some(10));
+    assert_eq!(status.applied_seq, None);
+    assert_eq!(status.lag, None);
+    assert_eq!(status.last_applied_secs, None);
+
+    follower.applied(7);
+    let status = follower.status().await;
+    assert_eq!(status.applied_seq, Some(7));
+    assert_eq!(status.lag, Some(3));
+    assert!(status.last_applied_secs.is_some());
+
+    // Without the leader there's no lag to work out
+    let status = self::follower("http://127.0.0.1:1").status().await;
+    assert_eq!(status.leader_seq, None);
+    assert_eq!(status.lag, None);
+}
@@ -0,0 +1,631 @@
+//! Append-only log backend for write heavy workloads.
+//!
+//! Every write is appended to the log as one JSON line and never changed in
+//! place. An in-memory index maps each live key to the line that holds its
+//! value, so reads are a single positioned read. Writes in a batch are marked
+//! with `more` on every line but the last, on startup the log is replayed and
+//! an unfinished batch or torn line at the end (from a crash mid-write) is cut off.
+//! A damaged line with records after it is not a crash, the log refuses to open.
+//!
+//! Overwritten and deleted lines are dead weight, once there is more of them
+//! than live data the log is compacted by rewriting only the live lines to a
+//! new file and renaming it over the old one.
+//!
+//! The change log is kept in memory. The last line of each batch records the
+//! sequence number of its last change and a compacted log starts with a
+//! checkpoint line, so numbering carries on after a restart, but listeners
+//! resuming from before the restart are told to reload.
+
+use crate::config::{ChangeLogConfig, LogStorageConfig};
+use crate::storage::change_log::ChangeLog;
+use crate::storage::transaction;
+use crate::storage::*;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/// One line of the log
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    op: LogOp,
+    /// Set on every line of a batch except the last one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    more: bool,
+    /// Sequence number of the last change in the batch, only on its last line
+    #[serde(default, skip_serializing_if = "is_zero")]
+    seq: u64,
+}
+
+fn is_zero(seq: &u64) -> bool {
+    *seq == 0
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum LogOp {
+    Set {
+        key: String,
+        value: String,
+        /// Logs written before versions existed don't have it, replay counts them up instead
+        #[serde(default)]
+        version: u64,
+    },
+    Delete {
+        key: String,
+    },
+    /// First line of a compacted log, its `seq` is where the compacted changes left off
+    Checkpoint,
+}
+
+/// Where a live value sits in the log
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    offset: u64,
+    len: u64,
+    version: u64,
+}
+
+struct Inner {
+    file: File,
+    index: BTreeMap<String, Position>,
+    /// Size of the log, the next line goes here
+    len: u64,
+    /// Bytes taken by lines that were overwritten or deleted since
+    stale: u64,
+    history: ChangeLog,
+}
+
+pub struct LogStorage {
+    path: PathBuf,
+    config: LogStorageConfig,
+    inner: RwLock<Inner>,
+    changes: broadcast::Sender<Changes>,
+}
+
+impl LogStorage {
+    /// Opens the log, creating it if needed, and rebuilds the index by replaying it
+    pub fn open(config: &LogStorageConfig) -> Result<LogStorage, StorageError> {
+        let path = config.path.clone();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let inner = replay(file)?;
+        log::debug!(
+            "opened log {} with {} keys ({} of {} bytes stale)",
+            path.display(),
+            inner.index.len(),
+            inner.stale,
+            inner.len
+        );
+
+        let (changes, _) = broadcast::channel(WATCH_CAPACITY);
+        let storage = LogStorage {
+            path,
+            config: config.clone(),
+            inner: RwLock::new(inner),
+            changes,
+        };
+        {
+            let mut inner = storage
+                .inner
+                .write()
+                .map_err(|_| "Storage lock is poisoned")?;
+            if storage.needs_compaction(&inner) {
+                storage.compact(&mut inner)?;
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Rewrites the log with only the live lines
+    fn compact(&self, inner: &mut Inner) -> Result<(), StorageError> {
+        let compact_path = self.path.with_extension("compact");
+        let index = write_live(inner, &compact_path)?;
+        let len = fs::metadata(&compact_path)?.len();
+        fs::rename(&compact_path, &self.path)?;
+
+        // The old file is gone from the directory, appending to it would lose every write.
+        // Panicking poisons the lock so nothing is written until the log is opened again.
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .unwrap_or_else(|error| {
+                panic!(
+                    "could not reopen {} after compaction: {error}",
+                    self.path.display()
+                )
+            });
+        let before = inner.len;
+        inner.len = len;
+        inner.file = file;
+        inner.index = index;
+        inner.stale = 0;
+
+        // The rename is only durable once the directory is synced
+        let dir = self
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()?;
+        log::info!(
+            "compacted log {} from {before} to {} bytes",
+            self.path.display(),
+            inner.len
+        );
+        Ok(())
+    }
+
+    /// Appends `writes` as one batch, updates the index and logs `changes` and tells watchers about them
+    fn append(
+        &self,
+        inner: &mut Inner,
+        writes: Vec<(String, Option<Versioned>)>,
+        changes: Vec<Change>,
+    ) -> Result<(), StorageError> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let mut lines = Vec::new();
+        let mut positions = Vec::with_capacity(writes.len());
+        let count = writes.len();
+        let seq = inner.history.last_seq() + changes.len() as u64;
+        for (number, (key, write)) in writes.iter().enumerate() {
+            let record = Record {
+                op: match write {
+                    Some(entry) => LogOp::Set {
+                        key: key.clone(),
+                        value: entry.value.clone(),
+                        version: entry.version,
+                    },
+                    None => LogOp::Delete { key: key.clone() },
+                },
+                more: number + 1 < count,
+                seq: if number + 1 == count { seq } else { 0 },
+            };
+            let start = lines.len();
+            serde_json::to_writer(&mut lines, &record)?;
+            lines.push(b'\n');
+            positions.push(Position {
+                offset: inner.len + start as u64,
+                len: (lines.len() - start) as u64,
+                version: write.as_ref().map_or(0, |entry| entry.version),
+            });
+        }
+
+        // One write for the whole batch, if it fails part way the tail is cut off
+        let write = inner
+            .file
+            .write_all(&lines)
+            .and_then(|_| match self.config.sync {
+                true => inner.file.sync_data(),
+                false => Ok(()),
+            });
+        if let Err(error) = write {
+            let _ = inner.file.set_len(inner.len);
+            return Err(error.into());
+        }
+        inner.len += lines.len() as u64;
+
+        for ((key, write), position) in writes.into_iter().zip(positions) {
+            match write {
+                Some(_) => {
+                    if let Some(old) = inner.index.insert(key, position) {
+                        inner.stale += old.len;
+                    }
+                }
+                None => {
+                    if let Some(old) = inner.index.remove(&key) {
+                        inner.stale += old.len;
+                    }
+                    inner.stale += position.len;
+                }
+            }
+        }
+
+        // Logged while still holding the lock so changes are numbered in commit order
+        if !changes.is_empty() {
+            let _ = self.changes.send(inner.history.append(changes));
+        }
+
+        if self.needs_compaction(inner) {
+            if let Err(error) = self.compact(inner) {
+                log::error!("could not compact {}: {error}", self.path.display());
+            }
+        }
+        Ok(())
+    }
+
+    fn needs_compaction(&self, inner: &Inner) -> bool {
+        inner.stale >= self.config.compact_min_bytes && inner.stale >= inner.len - inner.stale
+    }
+}
+
+/// Reads the whole log into an index, cutting off a torn batch at the end.
+/// Fails if a damaged line has records after it, those would be lost.
+fn replay(file: File) -> Result<Inner, StorageError> {
+    let mut index: BTreeMap<String, Position> = BTreeMap::new();
+    let mut stale = 0;
+    // Lines of the batch being read, only applied once its last line shows up
+    let mut pending: Vec<(LogOp, Position)> = Vec::new();
+    let mut offset = 0;
+    let mut committed = 0;
+    let mut last_seq = 0;
+
+    let mut reader = BufReader::new(&file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)? as u64;
+        // A line without its newline was cut off in the middle of being written
+        if read == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        let record: Record = match serde_json::from_slice(&line) {
+            Ok(record) => record,
+            // A torn last line is cut off below, anything after it means the log is damaged
+            Err(error) => {
+                if has_records(&mut reader)? {
+                    return Err(format!(
+                        "the log is corrupt at byte {offset} ({error}) and has records after it, \
+                         restore a backup or cut the log at that byte by hand"
+                    )
+                    .into());
+                }
+                log::warn!("log is corrupt at byte {offset}: {error}");
+                break;
+            }
+        };
+        pending.push((
+            record.op,
+            Position {
+                offset,
+                len: read,
+                version: 0,
+            },
+        ));
+        offset += read;
+
+        if !record.more {
+            last_seq = last_seq.max(record.seq);
+            for (op, position) in pending.drain(..) {
+                match op {
+                    LogOp::Set { key, version, .. } => {
+                        let previous = index.get(&key).map_or(0, |old| old.version);
+                        let position = Position {
+                            version: if version > 0 { version } else { previous + 1 },
+                            ..position
+                        };
+                        if let Some(old) = index.insert(key, position) {
+                            stale += old.len;
+                        }
+                    }
+                    LogOp::Delete { key } => {
+                        if let Some(old) = index.remove(&key) {
+                            stale += old.len;
+                        }
+                        stale += position.len;
+                    }
+                    LogOp::Checkpoint => {}
+                }
+            }
+            committed = offset;
+        }
+    }
+
+    // Logs from before versions were sequence numbers can have higher versions,
+    // numbering carries on above them so none is handed out twice
+    let last_seq = index
+        .values()
+        .map(|position| position.version)
+        .fold(last_seq, u64::max);
+
+    let len = file.metadata()?.len();
+    if committed < len {
+        log::warn!(
+            "dropping {} bytes of unfinished writes from the end of the log",
+            len - committed
+        );
+        file.set_len(committed)?;
+        file.sync_all()?;
+    }
+    Ok(Inner {
+        file,
+        index,
+        len: committed,
+        stale,
+        history: ChangeLog::new(last_seq),
+    })
+}
+
+/// Whether any complete line left in `reader` is a record
+fn has_records(reader: &mut impl BufRead) -> Result<bool, StorageError> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 || line.last() != Some(&b'\n') {
+            return Ok(false);
+        }
+        if serde_json::from_slice::<Record>(&line).is_ok() {
+            return Ok(true);
+        }
+    }
+}
+
+/// Writes every live line to a new log at `path` and returns the index for it
+fn write_live(inner: &Inner, path: &Path) -> Result<BTreeMap<String, Position>, StorageError> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(&file);
+    let mut index = BTreeMap::new();
+
+    let checkpoint = Record {
+        op: LogOp::Checkpoint,
+        more: false,
+        seq: inner.history.last_seq(),
+    };
+    let mut line = serde_json::to_vec(&checkpoint)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    let mut offset = line.len() as u64;
+
+    for (key, position) in &inner.index {
+        // Each line is rewritten on its own, so whatever batch it was part of is over
+        let mut record: Record = serde_json::from_slice(&read_line(&inner.file, *position)?)?;
+        record.more = false;
+        record.seq = 0;
+        if let LogOp::Set { version, .. } = &mut record.op {
+            *version = position.version;
+        }
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        writer.write_all(&line)?;
+        let len = line.len() as u64;
+        index.insert(
+            key.clone(),
+            Position {
+                offset,
+                len,
+                version: position.version,
+            },
+        );
+        offset += len;
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()?;
+    Ok(index)
+}
+
+fn read_line(file: &File, position: Position) -> Result<Vec<u8>, StorageError> {
+    let mut line = vec![0; position.len as usize];
+    file.read_exact_at(&mut line, position.offset)?;
+    Ok(line)
+}
+
+fn read_value(file: &File, position: Position) -> Result<String, StorageError> {
+    let record: Record = serde_json::from_slice(&read_line(file, position)?)?;
+    match record.op {
+        LogOp::Set { value, .. } => Ok(value),
+        LogOp::Delete { key } => Err(format!("The index points at a delete of {key}").into()),
+        LogOp::Checkpoint => Err("The index points at a checkpoint".into()),
+    }
+}
+
+impl Storage for LogStorage {
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, StorageError> {
+        let inner = self.inner.read().map_err(|_| "Storage lock is poisoned")?;
+        match inner.index.get(key) {
+            Some(position) => Ok(Some(Versioned {
+                value: read_value(&inner.file, *position)?,
+                version: position.version,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let inner = self.inner.read().map_err(|_| "Storage lock is poisoned")?;
+        Ok(inner
+            .index
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn scan_versioned(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<VersionedEntry>, StorageError> {
+        let inner = self.inner.read().map_err(|_| "Storage lock is poisoned")?;
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        inner
+            .index
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .take(limit)
+            .map(|(key, position)| {
+                Ok(VersionedEntry {
+                    key: key.clone(),
+                    value: read_value(&inner.file, *position)?,
+                    version: position.version,
+                })
+            })
+            .collect()
+    }
+
+    fn snapshot(&self, prefix: &str) -> Result<(Vec<Entry>, u64), StorageError> {
+        let inner = self.inner.read().map_err(|_| "Storage lock is poisoned")?;
+        let entries = inner
+            .index
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, position)| {
+                Ok(Entry {
+                    key: key.clone(),
+                    value: read_value(&inner.file, *position)?,
+                })
+            })
+            .collect::<Result<_, StorageError>>()?;
+        Ok((entries, inner.history.last_seq()))
+    }
+
+    fn batch(&self, ops: Vec<WriteOp>) -> Result<Vec<WriteOutcome>, StorageError> {
+        let mut inner = self.inner.write().map_err(|_| "Storage lock is poisoned")?;
+
+        // Work out every outcome first so a failed batch writes nothing
+        let mut outcomes = Vec::with_capacity(ops.len());
+        let mut writes = Vec::new();
+        // Versions of keys set or deleted earlier in this batch, they aren't in the index yet
+        let mut staged: BTreeMap<&str, Option<u64>> = BTreeMap::new();
+        // Each write's version is the sequence number its change gets
+        let next_seq = inner.history.last_seq() + 1;
+        for op in &ops {
+            let current = staged
+                .get(op.key())
+                .copied()
+                .unwrap_or_else(|| inner.index.get(op.key()).map(|position| position.version));
+            let outcome = match (op, current) {
+                (WriteOp::Set { .. }, Some(_)) => WriteOutcome::Updated,
+                (WriteOp::Set { .. }, None) => WriteOutcome::Created,
+                (WriteOp::Insert { .. }, Some(_)) => WriteOutcome::Skipped,
+                (WriteOp::Insert { .. }, None) => WriteOutcome::Created,
+                (WriteOp::Create { key, .. }, Some(_)) => {
+                    return Err(Box::new(KeyExists(key.clone())))
+                }
+                (WriteOp::Create { .. }, None) => WriteOutcome::Created,
+                (WriteOp::Delete { .. }, Some(_)) => WriteOutcome::Deleted,
+                (WriteOp::Delete { .. }, None) => WriteOutcome::NotFound,
+            };
+            match (op, outcome) {
+                (
+                    WriteOp::Set { key, value }
+                    | WriteOp::Insert { key, value }
+                    | WriteOp::Create { key, value },
+                    WriteOutcome::Created | WriteOutcome::Updated,
+                ) => {
+                    let version = next_seq + writes.len() as u64;
+                    staged.insert(key, Some(version));
+                    writes.push((
+                        key.clone(),
+                        Some(Versioned {
+                            value: value.clone(),
+                            version,
+                        }),
+                    ));
+                }
+                (WriteOp::Delete { key }, WriteOutcome::Deleted) => {
+                    staged.insert(key, None);
+                    writes.push((key.clone(), None));
+                }
+                _ => {}
+            }
+            outcomes.push(outcome);
+        }
+
+        let changes = changes_for(&ops, &outcomes);
+        self.append(&mut inner, writes, changes)?;
+        Ok(outcomes)
+    }
+
+    fn replicate(&self, writes: Vec<Replicated>) -> Result<(), StorageError> {
+        let mut inner = self.inner.write().map_err(|_| "Storage lock is poisoned")?;
+        let mut changes = Vec::with_capacity(writes.len());
+        let mut lines = Vec::with_capacity(writes.len());
+        // Whether keys written earlier in this batch exist, they aren't in the index yet
+        let mut staged: BTreeMap<String, bool> = BTreeMap::new();
+        for write in writes {
+            match write {
+                Replicated::Set {
+                    key,
+                    value,
+                    version,
+                } => {
+                    staged.insert(key.clone(), true);
+                    changes.push(Change::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                    });
+                    lines.push((key, Some(Versioned { value, version })));
+                }
+                Replicated::Delete { key } => {
+                    let exists = staged
+                        .get(&key)
+                        .copied()
+                        .unwrap_or_else(|| inner.index.contains_key(&key));
+                    if exists {
+                        staged.insert(key.clone(), false);
+                        changes.push(Change::Delete { key: key.clone() });
+                        lines.push((key, None));
+                    }
+                }
+            }
+        }
+        self.append(&mut inner, lines, changes)
+    }
+
+    fn transact(&self, ops: Vec<TxOp>) -> Result<Vec<TxResult>, StorageError> {
+        let mut inner = self.inner.write().map_err(|_| "Storage lock is poisoned")?;
+        let next_seq = inner.history.last_seq() + 1;
+        let plan = transaction::plan(&ops, next_seq, |key| match inner.index.get(key) {
+            Some(position) => Ok(Some(Versioned {
+                value: read_value(&inner.file, *position)?,
+                version: position.version,
+            })),
+            None => Ok(None),
+        })?;
+        self.append(&mut inner, plan.writes.into_iter().collect(), plan.changes)?;
+        Ok(plan.results)
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Changes> {
+        self.changes.subscribe()
+    }
+
+    fn changes_since(
+        &self,
+        prefix: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Event>, StorageError> {
+        let inner = self.inner.read().map_err(|_| "Storage lock is poisoned")?;
+        inner.history.since(prefix, after, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64, StorageError> {
+        let inner = self.inner.read().map_err(|_| "Storage lock is poisoned")?;
+        Ok(inner.history.last_seq())
+    }
+
+    fn prune_changes(&self, retention: &ChangeLogConfig) -> Result<u64, StorageError> {
+        let mut inner = self.inner.write().map_err(|_| "Storage lock is poisoned")?;
+        Ok(inner.history.prune(retention))
+    }
+
+    /// Writes a compacted copy of the log
+    fn backup_to(&self, path: &Path) -> Result<(), StorageError> {
+        if path.exists() {
+            return Err(format!("{} already exists", path.display()).into());
+        }
+        let inner = self.inner.read().map_err(|_| "Storage lock is poisoned")?;
+        write_live(&inner, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+//! Read-through LRU cache in front of another backend.
+//!
+//! `get` results (including missing keys) are kept up to a total size in
+//! bytes, the least recently read key is evicted first. Every write goes to
+//! the backend first and then evicts the keys it touched, so the next read
+//! goes back to the backend and sees the committed value.
+
+use crate::config::{CacheConfig, ChangeLogConfig};
+use crate::storage::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Rough bookkeeping cost of an entry on top of its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Counters for `GET /admin/{secret}/cache`
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    entries: AtomicU64,
+    bytes: AtomicU64,
+    max_bytes: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room, writes that evict a key don't count
+    pub evictions: u64,
+    pub entries: u64,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+impl CacheMetrics {
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: self.entries.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            max_bytes: self.max_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct CachedValue {
+    value: Option<Versioned>,
+    size: usize,
+    /// When the entry was last read, also its key in `recency`
+    used: u64,
+}
+
+struct Lru {
+    entries: HashMap<String, CachedValue>,
+    /// Keys ordered from least to most recently used
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    bytes: usize,
+    max_bytes: usize,
+    /// Bumped by every write, a read only fills the cache if no write happened while it was reading
+    generation: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: &str) -> Option<Option<Versioned>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.used);
+        entry.used = self.clock;
+        self.recency.insert(self.clock, key.to_string());
+        Some(entry.value.clone())
+    }
+
+    /// Adds an entry, returns how many others were evicted to make room
+    fn insert(&mut self, key: String, value: Option<Versioned>) -> u64 {
+        let size = key.len() + value.as_ref().map_or(0, |entry| entry.value.len()) + ENTRY_OVERHEAD;
+        if size > self.max_bytes {
+            return 0;
+        }
+        self.remove(&key);
+
+        let mut evicted = 0;
+        while self.bytes + size > self.max_bytes {
+            let oldest = match self.recency.keys().next() {
+                Some(used) => *used,
+                None => break,
+            };
+            if let Some(oldest_key) = self.recency.remove(&oldest) {
+                if let Some(entry) = self.entries.remove(&oldest_key) {
+                    self.bytes -= entry.size;
+                    evicted += 1;
+                }
+            }
+        }
+
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            CachedValue {
+                value,
+                size,
+                used: self.clock,
+            },
+        );
+        self.bytes += size;
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+pub struct CachedStorage {
+    inner: Arc<dyn Storage>,
+    lru: Mutex<Lru>,
+    metrics: Arc<CacheMetrics>,
+}
+
+impl CachedStorage {
+    pub fn new(inner: Arc<dyn Storage>, max_bytes: usize) -> CachedStorage {
+        let metrics = CacheMetrics::default();
+        metrics.max_bytes.store(max_bytes as u64, Ordering::Relaxed);
+        CachedStorage {
+            inner,
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                bytes: 0,
+                max_bytes,
+                generation: 0,
+            }),
+            metrics: Arc::new(metrics),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<CacheMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Drops written keys. Also done when a write failed, it may still have changed something.
+    fn invalidate(&self, keys: &[String]) -> Result<(), StorageError> {
+        let mut lru = self.lru.lock().map_err(|_| "Cache lock is poisoned")?;
+        lru.generation += 1;
+        for key in keys {
+            lru.remove(key);
+        }
+        self.update_size(&lru);
+        Ok(())
+    }
+
+    fn update_size(&self, lru: &Lru) {
+        self.metrics
+            .entries
+            .store(lru.entries.len() as u64, Ordering::Relaxed);
+        self.metrics
+            .bytes
+            .store(lru.bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Puts the cache in front of `storage` when it is turned on in the config
+pub fn wrap(
+    storage: Arc<dyn Storage>,
+    config: &CacheConfig,
+) -> (Arc<dyn Storage>, Option<Arc<CacheMetrics>>) {
+    if config.max_bytes == 0 {
+        return (storage, None);
+    }
+    let cached = CachedStorage::new(storage, config.max_bytes);
+    let metrics = cached.metrics();
+    (Arc::new(cached), Some(metrics))
+}
+
+impl Storage for CachedStorage {
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, StorageError> {
+        let generation = {
+            let mut lru = self.lru.lock().map_err(|_| "Cache lock is poisoned")?;
+            if let Some(value) = lru.get(key) {
+                self.metrics.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(value);
+            }
+            lru.generation
+        };
+        self.metrics.misses.fetch_add(1, Ordering::Relaxed);
+
+        let value = self.inner.get_versioned(key)?;
+
+        let mut lru = self.lru.lock().map_err(|_| "Cache lock is poisoned")?;
+        // A write landed while we were reading, what we read may already be stale
+        if lru.generation == generation {
+            let evicted = lru.insert(key.to_string(), value.clone());
+            self.metrics.evictions.fetch_add(evicted, Ordering::Relaxed);
+            self.update_size(&lru);
+        }
+        Ok(value)
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.inner.scan(prefix)
+    }
+
+    fn scan_versioned(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<VersionedEntry>, StorageError> {
+        self.inner.scan_versioned(prefix, after, limit)
+    }
+
+    fn snapshot(&self, prefix: &str) -> Result<(Vec<Entry>, u64), StorageError> {
+        self.inner.snapshot(prefix)
+    }
+
+    fn batch(&self, ops: Vec<WriteOp>) -> Result<Vec<WriteOutcome>, StorageError> {
+        let keys: Vec<String> = ops.iter().map(|op| op.key().to_string()).collect();
+        let result = self.inner.batch(ops);
+        self.invalidate(&keys)?;
+        result
+    }
+
+    fn replicate(&self, writes: Vec<Replicated>) -> Result<(), StorageError> {
+        let keys: Vec<String> = writes.iter().map(|write| write.key().to_string()).collect();
+        let result = self.inner.replicate(writes);
+        self.invalidate(&keys)?;
+        result
+    }
+
+    fn transact(&self, ops: Vec<TxOp>) -> Result<Vec<TxResult>, StorageError> {
+        let keys: Vec<String> = ops.iter().map(|op| op.key().to_string()).collect();
+        let result = self.inner.transact(ops);
+        self.invalidate(&keys)?;
+        result
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Changes> {
+        self.inner.watch()
+    }
+
+    fn changes_since(
+        &self,
+        prefix: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Event>, StorageError> {
+        self.inner.changes_since(prefix, after, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64, StorageError> {
+        self.inner.last_seq()
+    }
+
+    fn prune_changes(&self, retention: &ChangeLogConfig) -> Result<u64, StorageError> {
+        self.inner.prune_changes(retention)
+    }
+
+    fn backup_to(&self, path: &Path) -> Result<(), StorageError> {
+        self.inner.backup_to(path)
+    }
+}
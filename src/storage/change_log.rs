@@ -0,0 +1,129 @@
+//! Change log for the backends that keep it in memory.
+//!
+//! Every committed change gets the next sequence number and stays in the log
+//! until the retention settings drop it, so listeners that were away can catch
+//! up on what they missed. The SQLite backend keeps its log in the `change_log`
+//! table instead, in the same transaction as the write.
+
+use crate::config::ChangeLogConfig;
+use crate::storage::*;
+use actix_web::{rt, web};
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often old changes are pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct ChangeLog {
+    last_seq: u64,
+    /// Changes with the unix time they were made, oldest first
+    events: VecDeque<(Event, u64)>,
+}
+
+impl ChangeLog {
+    /// Starts an empty log that numbers the next change `last_seq + 1`
+    pub fn new(last_seq: u64) -> ChangeLog {
+        ChangeLog {
+            last_seq,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Numbers `changes` and keeps them
+    pub fn append(&mut self, changes: Vec<Change>) -> Changes {
+        let now = unix_time();
+        let events: Vec<Event> = changes
+            .into_iter()
+            .map(|change| {
+                self.last_seq += 1;
+                Event {
+                    seq: self.last_seq,
+                    change,
+                }
+            })
+            .collect();
+        self.events
+            .extend(events.iter().map(|event| (event.clone(), now)));
+        Changes(events)
+    }
+
+    pub fn since(
+        &self,
+        prefix: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Event>, StorageError> {
+        let oldest = self.events.front().map(|(event, _)| event.seq);
+        check_after(after, oldest, self.last_seq)?;
+        let start = self.events.partition_point(|(event, _)| event.seq <= after);
+        Ok(self
+            .events
+            .range(start..)
+            .map(|(event, _)| event)
+            .filter(|event| event.change.key().starts_with(prefix))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    pub fn prune(&mut self, retention: &ChangeLogConfig) -> u64 {
+        let cutoff = unix_time().saturating_sub(retention.retain_secs);
+        let mut pruned = 0;
+        while self.events.len() > 1 {
+            let too_many =
+                retention.max_entries > 0 && self.events.len() as u64 > retention.max_entries;
+            let too_old = retention.retain_secs > 0
+                && self.events.front().is_some_and(|(_, at)| *at < cutoff);
+            if !too_many && !too_old {
+                break;
+            }
+            self.events.pop_front();
+            pruned += 1;
+        }
+        pruned
+    }
+}
+
+/// Fails unless every change after `after` is still in a log that starts at `oldest` and ends at `last_seq`
+pub fn check_after(after: u64, oldest: Option<u64>, last_seq: u64) -> Result<(), ChangesPruned> {
+    let complete = match oldest {
+        _ if after == last_seq => true,
+        // A number from the future is from another log, ie the data was restored or wiped
+        _ if after > last_seq => false,
+        Some(oldest) => after + 1 >= oldest,
+        None => false,
+    };
+    match complete {
+        true => Ok(()),
+        false => Err(ChangesPruned { after, last_seq }),
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Spawns a loop on the current runtime that prunes the change log every minute
+pub fn start_pruning(storage: Arc<dyn Storage>, retention: ChangeLogConfig) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let storage = storage.clone();
+            let config = retention.clone();
+            match web::block(move || storage.prune_changes(&config)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(pruned)) => log::debug!("pruned {pruned} changes from the change log"),
+                Ok(Err(error)) => log::error!("could not prune the change log: {error}"),
+                Err(error) => log::error!("could not prune the change log: {error}"),
+            }
+        }
+    });
+}
@@ -0,0 +1,272 @@
+//! In-memory backend for tests and ephemeral deployments. Nothing survives a restart.
+
+use crate::config::ChangeLogConfig;
+use crate::storage::change_log::ChangeLog;
+use crate::storage::transaction::{self, TxOp, TxResult};
+use crate::storage::*;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Mutex, RwLock};
+
+pub struct MemoryStorage {
+    entries: RwLock<BTreeMap<String, Versioned>>,
+    history: Mutex<ChangeLog>,
+    changes: broadcast::Sender<Changes>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        let (changes, _) = broadcast::channel(WATCH_CAPACITY);
+        MemoryStorage {
+            entries: RwLock::new(BTreeMap::new()),
+            history: Mutex::new(ChangeLog::new(0)),
+            changes,
+        }
+    }
+
+    /// Sequence number the next change gets, only stable while the entries are locked for writing
+    fn next_seq(&self) -> Result<u64, StorageError> {
+        let history = self
+            .history
+            .lock()
+            .map_err(|_| "Change log lock is poisoned")?;
+        Ok(history.last_seq() + 1)
+    }
+
+    /// Adds committed changes to the change log and sends them to watchers
+    fn publish(&self, changes: Vec<Change>) -> Result<(), StorageError> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let mut history = self
+            .history
+            .lock()
+            .map_err(|_| "Change log lock is poisoned")?;
+        let _ = self.changes.send(history.append(changes));
+        Ok(())
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, StorageError> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| "Storage lock is poisoned")?;
+        Ok(entries.get(key).cloned())
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| "Storage lock is poisoned")?;
+        Ok(entries
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn scan_versioned(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<VersionedEntry>, StorageError> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| "Storage lock is poisoned")?;
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        Ok(entries
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .take(limit)
+            .map(|(key, entry)| VersionedEntry {
+                key: key.clone(),
+                value: entry.value.clone(),
+                version: entry.version,
+            })
+            .collect())
+    }
+
+    fn snapshot(&self, prefix: &str) -> Result<(Vec<Entry>, u64), StorageError> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| "Storage lock is poisoned")?;
+        let history = self
+            .history
+            .lock()
+            .map_err(|_| "Change log lock is poisoned")?;
+        let snapshot = entries
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, entry)| Entry {
+                key: key.clone(),
+                value: entry.value.clone(),
+            })
+            .collect();
+        Ok((snapshot, history.last_seq()))
+    }
+
+    fn batch(&self, ops: Vec<WriteOp>) -> Result<Vec<WriteOutcome>, StorageError> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| "Storage lock is poisoned")?;
+
+        // Check everything before touching the map so a failed batch changes nothing.
+        // Whether keys set or deleted earlier in this batch exist, they aren't in the map yet.
+        let mut staged: BTreeMap<&str, bool> = BTreeMap::new();
+        for op in &ops {
+            let key = op.key();
+            let exists = staged
+                .get(key)
+                .copied()
+                .unwrap_or_else(|| entries.contains_key(key));
+            match op {
+                WriteOp::Create { .. } if exists => {
+                    return Err(Box::new(KeyExists(key.to_string())));
+                }
+                WriteOp::Delete { .. } => {
+                    staged.insert(key, false);
+                }
+                _ => {
+                    staged.insert(key, true);
+                }
+            }
+        }
+
+        let mut outcomes = Vec::with_capacity(ops.len());
+        // Each write's version is the sequence number its change gets
+        let mut seq = self.next_seq()?;
+        for op in &ops {
+            let version = seq;
+            let outcome = match op {
+                WriteOp::Set { key, value } | WriteOp::Create { key, value } => {
+                    match entries.insert(key.clone(), new_entry(value, version)) {
+                        Some(_) => WriteOutcome::Updated,
+                        None => WriteOutcome::Created,
+                    }
+                }
+                WriteOp::Insert { key, value } => {
+                    if entries.contains_key(key) {
+                        WriteOutcome::Skipped
+                    } else {
+                        entries.insert(key.clone(), new_entry(value, version));
+                        WriteOutcome::Created
+                    }
+                }
+                WriteOp::Delete { key } => match entries.remove(key) {
+                    Some(_) => WriteOutcome::Deleted,
+                    None => WriteOutcome::NotFound,
+                },
+            };
+            if !matches!(outcome, WriteOutcome::Skipped | WriteOutcome::NotFound) {
+                seq += 1;
+            }
+            outcomes.push(outcome);
+        }
+
+        // Logged while still holding the lock so changes are numbered in commit order
+        self.publish(changes_for(&ops, &outcomes))?;
+        Ok(outcomes)
+    }
+
+    fn replicate(&self, writes: Vec<Replicated>) -> Result<(), StorageError> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| "Storage lock is poisoned")?;
+        let mut changes = Vec::with_capacity(writes.len());
+        for write in writes {
+            match write {
+                Replicated::Set {
+                    key,
+                    value,
+                    version,
+                } => {
+                    entries.insert(key.clone(), new_entry(&value, version));
+                    changes.push(Change::Set { key, value });
+                }
+                Replicated::Delete { key } => {
+                    if entries.remove(&key).is_some() {
+                        changes.push(Change::Delete { key });
+                    }
+                }
+            }
+        }
+        self.publish(changes)
+    }
+
+    fn transact(&self, ops: Vec<TxOp>) -> Result<Vec<TxResult>, StorageError> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| "Storage lock is poisoned")?;
+        let plan = transaction::plan(&ops, self.next_seq()?, |key| Ok(entries.get(key).cloned()))?;
+        for (key, write) in plan.writes {
+            match write {
+                Some(entry) => entries.insert(key, entry),
+                None => entries.remove(&key),
+            };
+        }
+        self.publish(plan.changes)?;
+        Ok(plan.results)
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Changes> {
+        self.changes.subscribe()
+    }
+
+    fn changes_since(
+        &self,
+        prefix: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Event>, StorageError> {
+        let history = self
+            .history
+            .lock()
+            .map_err(|_| "Change log lock is poisoned")?;
+        history.since(prefix, after, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64, StorageError> {
+        let history = self
+            .history
+            .lock()
+            .map_err(|_| "Change log lock is poisoned")?;
+        Ok(history.last_seq())
+    }
+
+    fn prune_changes(&self, retention: &ChangeLogConfig) -> Result<u64, StorageError> {
+        let mut history = self
+            .history
+            .lock()
+            .map_err(|_| "Change log lock is poisoned")?;
+        Ok(history.prune(retention))
+    }
+
+    fn backup_to(&self, _path: &Path) -> Result<(), StorageError> {
+        Err("The memory backend has nothing on disk to back up, use export instead".into())
+    }
+}
+
+fn new_entry(value: &str, version: u64) -> Versioned {
+    Versioned {
+        value: value.to_string(),
+        version,
+    }
+}
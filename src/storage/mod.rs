@@ -0,0 +1,315 @@
+//! Storage backends. Controllers, the CLI and the WebSocket actor only talk to
+//! the `Storage` trait, so the SQLite database can be swapped for something else.
+//!
+//! Every method blocks, call them from `web::block` inside async code.
+
+pub mod append_log;
+pub mod cache;
+pub mod change_log;
+pub mod memory;
+pub mod read_only;
+pub mod sqlite;
+pub mod transaction;
+pub mod transfer;
+pub mod triggered;
+
+#[cfg(test)]
+mod tests;
+
+use crate::config::{ChangeLogConfig, Config, StorageBackend};
+use crate::data_access::pool::{self, DbPool};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+pub use append_log::LogStorage;
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+pub use transaction::{TxAborted, TxOp, TxResult, Versioned};
+
+pub type StorageError = Box<dyn Error + Send + Sync>;
+
+/// How many change groups can be buffered for each watcher before the slowest ones start missing them
+pub const WATCH_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+}
+
+/// An entry with its version
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionedEntry {
+    pub key: String,
+    pub value: String,
+    pub version: u64,
+}
+
+/// A committed change to a key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Change {
+    Set { key: String, value: String },
+    Delete { key: String },
+}
+
+impl Change {
+    pub fn key(&self) -> &str {
+        match self {
+            Change::Set { key, .. } => key,
+            Change::Delete { key } => key,
+        }
+    }
+}
+
+/// A change with its place in the change log. Sequence numbers go up by one
+/// with every change across all keys and are never reused.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+/// Changes committed together, in the order they were made
+#[derive(Debug, Clone)]
+pub struct Changes(pub Vec<Event>);
+
+/// One write in a batch
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum WriteOp {
+    /// Insert or replace a key
+    Set {
+        key: String,
+        value: String,
+    },
+    /// Insert a key only if it does not exist yet, existing keys are left alone
+    Insert {
+        key: String,
+        value: String,
+    },
+    /// Insert a key, the whole batch fails with `KeyExists` if it is already there
+    Create {
+        key: String,
+        value: String,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+impl WriteOp {
+    pub fn key(&self) -> &str {
+        match self {
+            WriteOp::Set { key, .. }
+            | WriteOp::Insert { key, .. }
+            | WriteOp::Create { key, .. }
+            | WriteOp::Delete { key } => key,
+        }
+    }
+}
+
+/// A write copied from a leader, see `replication`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replicated {
+    /// Insert or replace a key, it gets the version it has on the leader
+    Set {
+        key: String,
+        value: String,
+        version: u64,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+impl Replicated {
+    pub fn key(&self) -> &str {
+        match self {
+            Replicated::Set { key, .. } | Replicated::Delete { key } => key,
+        }
+    }
+}
+
+/// What a write in a batch did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteOutcome {
+    Created,
+    Updated,
+    /// `Insert` of a key that already existed
+    Skipped,
+    Deleted,
+    /// `Delete` of a key that did not exist
+    NotFound,
+}
+
+/// Returned by `batch` when a `Create` hits a key that already exists
+#[derive(Debug)]
+pub struct KeyExists(pub String);
+
+impl fmt::Display for KeyExists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key {} already exists", self.0)
+    }
+}
+
+impl Error for KeyExists {}
+
+/// Returned by `changes_since` when some of the requested changes are no longer
+/// kept, or the sequence number is from a log that was replaced. The caller has
+/// to reload and carry on from `last_seq`.
+#[derive(Debug)]
+pub struct ChangesPruned {
+    pub after: u64,
+    pub last_seq: u64,
+}
+
+impl fmt::Display for ChangesPruned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Changes after {} are no longer kept, the log is at {}",
+            self.after, self.last_seq
+        )
+    }
+}
+
+impl Error for ChangesPruned {}
+
+/// The database stayed locked by another connection past the busy timeout, or no pooled
+/// connection came free in time. Nothing was written, trying again later can work.
+#[derive(Debug)]
+pub struct Busy(pub String);
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The database is busy: {}", self.0)
+    }
+}
+
+impl Error for Busy {}
+
+pub trait Storage: Send + Sync {
+    /// A key's value and version
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, StorageError>;
+
+    fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.get_versioned(key)?.map(|entry| entry.value))
+    }
+
+    fn set(&self, key: String, value: String) -> Result<(), StorageError> {
+        self.batch(vec![WriteOp::Set { key, value }])?;
+        Ok(())
+    }
+
+    /// Returns false if the key did not exist
+    fn delete(&self, key: &str) -> Result<bool, StorageError> {
+        let outcomes = self.batch(vec![WriteOp::Delete {
+            key: key.to_string(),
+        }])?;
+        Ok(outcomes.first() == Some(&WriteOutcome::Deleted))
+    }
+
+    /// Keys starting with `prefix`, in key order
+    fn scan(&self, prefix: &str) -> Result<Vec<String>, StorageError>;
+
+    /// Up to `limit` entries starting with `prefix` that sort after `after`, in key order
+    fn scan_entries(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Entry>, StorageError> {
+        Ok(self
+            .scan_versioned(prefix, after, limit)?
+            .into_iter()
+            .map(|entry| Entry {
+                key: entry.key,
+                value: entry.value,
+            })
+            .collect())
+    }
+
+    /// `scan_entries` along with each entry's version
+    fn scan_versioned(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<VersionedEntry>, StorageError>;
+
+    /// Every entry starting with `prefix`, in key order, along with the sequence number of the
+    /// last change they include. Read in one go so changes after that number are all missing from it.
+    fn snapshot(&self, prefix: &str) -> Result<(Vec<Entry>, u64), StorageError>;
+
+    /// Applies every write or none of them. Listeners get the changes as one group.
+    fn batch(&self, ops: Vec<WriteOp>) -> Result<Vec<WriteOutcome>, StorageError>;
+
+    /// Applies writes copied from a leader, all or none of them, keeping the leader's versions.
+    /// Listeners get the changes as one group, numbered in this instance's change log.
+    fn replicate(&self, writes: Vec<Replicated>) -> Result<(), StorageError>;
+
+    /// Runs a transaction, see `transaction`. A failed precondition returns `TxAborted` and writes nothing.
+    fn transact(&self, ops: Vec<TxOp>) -> Result<Vec<TxResult>, StorageError>;
+
+    /// Subscribes to every committed change
+    fn watch(&self) -> broadcast::Receiver<Changes>;
+
+    /// Up to `limit` changes to keys starting with `prefix` that came after `after`, in log order.
+    /// Fails with `ChangesPruned` if the log no longer goes back that far.
+    fn changes_since(
+        &self,
+        prefix: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Event>, StorageError>;
+
+    /// Sequence number of the last committed change, 0 before the first one
+    fn last_seq(&self) -> Result<u64, StorageError>;
+
+    /// Drops changes the retention settings no longer cover, returns how many.
+    /// The last change is always kept so the log knows where it is.
+    fn prune_changes(&self, retention: &ChangeLogConfig) -> Result<u64, StorageError>;
+
+    /// Writes a consistent copy of the data to `path`
+    fn backup_to(&self, path: &Path) -> Result<(), StorageError>;
+}
+
+/// Opens the backend picked in the config. The SQLite backend reads with `pool`, whose schema is
+/// up to date, or builds its own without it and leaves the schema as it is.
+pub fn open(config: &Config, pool: Option<DbPool>) -> Result<Arc<dyn Storage>, StorageError> {
+    match config.storage.backend {
+        StorageBackend::Sqlite => {
+            let pool = match pool {
+                Some(pool) => pool,
+                None => pool::build(&config.database)?,
+            };
+            Ok(Arc::new(SqliteStorage::open(&config.database, pool)?))
+        }
+        StorageBackend::Memory => Ok(Arc::new(MemoryStorage::new())),
+        StorageBackend::Log => Ok(Arc::new(LogStorage::open(&config.storage.log)?)),
+    }
+}
+
+/// Turns the outcome of each write into the changes listeners should see
+pub fn changes_for(ops: &[WriteOp], outcomes: &[WriteOutcome]) -> Vec<Change> {
+    ops.iter()
+        .zip(outcomes)
+        .filter_map(|(op, outcome)| match (op, outcome) {
+            (_, WriteOutcome::Skipped) | (_, WriteOutcome::NotFound) => None,
+            (WriteOp::Delete { key }, _) => Some(Change::Delete { key: key.clone() }),
+            (WriteOp::Set { key, value }, _)
+            | (WriteOp::Insert { key, value }, _)
+            | (WriteOp::Create { key, value }, _) => Some(Change::Set {
+                key: key.clone(),
+                value: value.clone(),
+            }),
+        })
+        .collect()
+}
@@ -0,0 +1,104 @@
+//! Storage of a follower, see `replication`.
+//!
+//! Reads go to the backend, writes fail with `ReadOnly` naming the leader to
+//! write to instead. Only the replication task holds the backend itself and
+//! writes what it gets from the leader.
+
+use crate::config::ChangeLogConfig;
+use crate::storage::*;
+
+/// Returned for writes to a follower
+#[derive(Debug)]
+pub struct ReadOnly {
+    pub leader: String,
+}
+
+impl fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "This instance is a read-only follower, write to the leader at {}",
+            self.leader
+        )
+    }
+}
+
+impl Error for ReadOnly {}
+
+pub struct ReadOnlyStorage {
+    inner: Arc<dyn Storage>,
+    leader: String,
+}
+
+impl ReadOnlyStorage {
+    pub fn new(inner: Arc<dyn Storage>, leader: String) -> ReadOnlyStorage {
+        ReadOnlyStorage { inner, leader }
+    }
+
+    fn read_only(&self) -> StorageError {
+        Box::new(ReadOnly {
+            leader: self.leader.clone(),
+        })
+    }
+}
+
+impl Storage for ReadOnlyStorage {
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, StorageError> {
+        self.inner.get_versioned(key)
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.inner.scan(prefix)
+    }
+
+    fn scan_versioned(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<VersionedEntry>, StorageError> {
+        self.inner.scan_versioned(prefix, after, limit)
+    }
+
+    fn snapshot(&self, prefix: &str) -> Result<(Vec<Entry>, u64), StorageError> {
+        self.inner.snapshot(prefix)
+    }
+
+    fn batch(&self, _: Vec<WriteOp>) -> Result<Vec<WriteOutcome>, StorageError> {
+        Err(self.read_only())
+    }
+
+    /// Only the replication task writes, through the backend
+    fn replicate(&self, _: Vec<Replicated>) -> Result<(), StorageError> {
+        Err(self.read_only())
+    }
+
+    fn transact(&self, _: Vec<TxOp>) -> Result<Vec<TxResult>, StorageError> {
+        Err(self.read_only())
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Changes> {
+        self.inner.watch()
+    }
+
+    fn changes_since(
+        &self,
+        prefix: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Event>, StorageError> {
+        self.inner.changes_since(prefix, after, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64, StorageError> {
+        self.inner.last_seq()
+    }
+
+    fn prune_changes(&self, retention: &ChangeLogConfig) -> Result<u64, StorageError> {
+        self.inner.prune_changes(retention)
+    }
+
+    fn backup_to(&self, path: &Path) -> Result<(), StorageError> {
+        self.inner.backup_to(path)
+    }
+}
@@ -0,0 +1,266 @@
+//! SQLite backend. Reads use the connection pool, writes go through the single writer.
+
+use crate::config::{ChangeLogConfig, DatabaseConfig};
+use crate::data_access::{
+    actions::*,
+    backup,
+    pool::{ConnectionOptions, DbPool},
+    writer::Writer,
+};
+use crate::storage::change_log::{check_after, unix_time};
+use crate::storage::transaction;
+use crate::storage::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::{Connection, SqliteConnection};
+
+/// Entries read at a time while taking a snapshot
+const SNAPSHOT_PAGE: usize = 1000;
+
+pub struct SqliteStorage {
+    pool: DbPool,
+    writer: Writer,
+    changes: broadcast::Sender<Changes>,
+}
+
+impl SqliteStorage {
+    /// A connection from the read pool
+    fn conn(&self) -> Result<PooledConnection<ConnectionManager<SqliteConnection>>, StorageError> {
+        self.pool.get().map_err(|error| busy_or(error.into()))
+    }
+
+    /// Reads with `pool`, the schema has to be up to date already
+    pub fn open(config: &DatabaseConfig, pool: DbPool) -> Result<SqliteStorage, StorageError> {
+        let (changes, _) = broadcast::channel(WATCH_CAPACITY);
+        // every write goes through one connection, reads use the pool
+        let writer = Writer::start(
+            &config.url,
+            &ConnectionOptions::from_config(config),
+            changes.clone(),
+        )?;
+        Ok(SqliteStorage {
+            pool,
+            writer,
+            changes,
+        })
+    }
+}
+
+/// Applies one write on the writer's connection, `version` is the sequence number its change gets
+fn apply(conn: &mut SqliteConnection, op: &WriteOp, version: i64) -> Result<WriteOutcome, DbError> {
+    let outcome = match op {
+        WriteOp::Set { key, value } => {
+            let existed = key_exists(conn, key)?;
+            put_versioned_entry(conn, key, value, version)?;
+            match existed {
+                true => WriteOutcome::Updated,
+                false => WriteOutcome::Created,
+            }
+        }
+        WriteOp::Insert { key, value } => match key_exists(conn, key)? {
+            true => WriteOutcome::Skipped,
+            false => {
+                put_versioned_entry(conn, key, value, version)?;
+                WriteOutcome::Created
+            }
+        },
+        WriteOp::Create { key, value } => match key_exists(conn, key)? {
+            true => return Err(Box::new(KeyExists(key.clone()))),
+            false => {
+                put_versioned_entry(conn, key, value, version)?;
+                WriteOutcome::Created
+            }
+        },
+        WriteOp::Delete { key } => match delete_by_key(conn, key.clone())? {
+            true => WriteOutcome::Deleted,
+            false => WriteOutcome::NotFound,
+        },
+    };
+    Ok(outcome)
+}
+
+impl Storage for SqliteStorage {
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, StorageError> {
+        let mut conn = self.conn()?;
+        Ok(get_entry(&mut conn, key.to_string())
+            .map_err(busy_or)?
+            .map(|entry| Versioned {
+                value: entry.value,
+                version: entry.version as u64,
+            }))
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let mut conn = self.conn()?;
+        get_keys_by_prefix(&mut conn, prefix.to_string()).map_err(busy_or)
+    }
+
+    fn scan_versioned(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<VersionedEntry>, StorageError> {
+        let mut conn = self.conn()?;
+        Ok(get_entries_by_prefix(&mut conn, prefix, after, limit)
+            .map_err(busy_or)?
+            .into_iter()
+            .map(|entry| VersionedEntry {
+                key: entry.key,
+                value: entry.value,
+                version: entry.version as u64,
+            })
+            .collect())
+    }
+
+    fn snapshot(&self, prefix: &str) -> Result<(Vec<Entry>, u64), StorageError> {
+        let mut conn = self.conn()?;
+        // One read transaction so the entries and the change log are from the same moment
+        conn.transaction::<_, DbError, _>(|conn| {
+            let last_seq = change_log_bounds(conn)?.1.unwrap_or(0) as u64;
+            let mut entries: Vec<Entry> = Vec::new();
+            loop {
+                let after = entries.last().map(|entry| entry.key.as_str());
+                let page = get_entries_by_prefix(conn, prefix, after, SNAPSHOT_PAGE)?;
+                let done = page.len() < SNAPSHOT_PAGE;
+                entries.extend(page.into_iter().map(|entry| Entry {
+                    key: entry.key,
+                    value: entry.value,
+                }));
+                if done {
+                    return Ok((entries, last_seq));
+                }
+            }
+        })
+        .map_err(busy_or)
+    }
+
+    fn batch(&self, ops: Vec<WriteOp>) -> Result<Vec<WriteOutcome>, StorageError> {
+        self.writer.write(move |conn| {
+            let mut outcomes = Vec::with_capacity(ops.len());
+            let mut seq = next_change_seq(conn)?;
+            for op in &ops {
+                let outcome = apply(conn, op, seq)?;
+                if !matches!(outcome, WriteOutcome::Skipped | WriteOutcome::NotFound) {
+                    seq += 1;
+                }
+                outcomes.push(outcome);
+            }
+            let changes = changes_for(&ops, &outcomes);
+            Ok((outcomes, changes))
+        })
+    }
+
+    fn replicate(&self, writes: Vec<Replicated>) -> Result<(), StorageError> {
+        self.writer.write(move |conn| {
+            let mut changes = Vec::with_capacity(writes.len());
+            for write in writes {
+                match write {
+                    Replicated::Set {
+                        key,
+                        value,
+                        version,
+                    } => {
+                        put_versioned_entry(conn, &key, &value, version as i64)?;
+                        changes.push(Change::Set { key, value });
+                    }
+                    Replicated::Delete { key } => {
+                        if delete_by_key(conn, key.clone())? {
+                            changes.push(Change::Delete { key });
+                        }
+                    }
+                }
+            }
+            Ok(((), changes))
+        })
+    }
+
+    fn transact(&self, ops: Vec<TxOp>) -> Result<Vec<TxResult>, StorageError> {
+        self.writer.write(move |conn| {
+            let next_seq = next_change_seq(conn)? as u64;
+            let plan = transaction::plan(&ops, next_seq, |key| {
+                Ok(get_entry(conn, key.to_string())?.map(|entry| Versioned {
+                    value: entry.value,
+                    version: entry.version as u64,
+                }))
+            })?;
+            for (key, write) in &plan.writes {
+                match write {
+                    Some(entry) => {
+                        put_versioned_entry(conn, key, &entry.value, entry.version as i64)?
+                    }
+                    None => {
+                        delete_by_key(conn, key.clone())?;
+                    }
+                }
+            }
+            Ok((plan.results, plan.changes))
+        })
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Changes> {
+        self.changes.subscribe()
+    }
+
+    fn changes_since(
+        &self,
+        prefix: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Event>, StorageError> {
+        let mut conn = self.conn()?;
+        // One read transaction so pruning can't remove changes between the check and the query
+        let entries = conn
+            .transaction::<_, DbError, _>(|conn| {
+                let (oldest, last_seq) = change_log_bounds(conn)?;
+                check_after(
+                    after,
+                    oldest.map(|oldest| oldest as u64),
+                    last_seq.unwrap_or(0) as u64,
+                )?;
+                get_changes_since(conn, prefix, after as i64, limit)
+            })
+            .map_err(busy_or)?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| Event {
+                seq: entry.seq as u64,
+                change: match entry.value {
+                    Some(value) => Change::Set {
+                        key: entry.key,
+                        value,
+                    },
+                    None => Change::Delete { key: entry.key },
+                },
+            })
+            .collect())
+    }
+
+    fn last_seq(&self) -> Result<u64, StorageError> {
+        let mut conn = self.conn()?;
+        Ok(change_log_bounds(&mut conn)
+            .map_err(busy_or)?
+            .1
+            .unwrap_or(0) as u64)
+    }
+
+    fn prune_changes(&self, retention: &ChangeLogConfig) -> Result<u64, StorageError> {
+        let created_before = match retention.retain_secs {
+            0 => 0,
+            secs => unix_time().saturating_sub(secs) as i64,
+        };
+        let max_entries = retention.max_entries as i64;
+        self.writer.write(move |conn| {
+            let below_seq = match (max_entries, change_log_bounds(conn)?.1) {
+                (1.., Some(last_seq)) => last_seq - max_entries + 1,
+                _ => 0,
+            };
+            let pruned = prune_change_log(conn, created_before, below_seq)?;
+            Ok((pruned as u64, Vec::new()))
+        })
+    }
+
+    fn backup_to(&self, path: &Path) -> Result<(), StorageError> {
+        let mut conn = self.conn()?;
+        backup::backup_to(&mut conn, path)
+    }
+}
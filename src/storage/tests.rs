@@ -0,0 +1,616 @@
+//! One suite run against every backend and the cache, so they all behave the same, then
+//! tests of what only one of them does
+
+use super::cache::CachedStorage;
+use super::*;
+use crate::config::{DatabaseConfig, LogStorageConfig};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use tempfile::TempDir;
+
+fn open_sqlite(dir: &TempDir) -> Arc<dyn Storage> {
+    let config = DatabaseConfig::for_test(dir);
+    let pool = pool::open(&config).unwrap();
+    Arc::new(SqliteStorage::open(&config, pool).unwrap())
+}
+
+fn open_memory(_: &TempDir) -> Arc<dyn Storage> {
+    Arc::new(MemoryStorage::new())
+}
+
+fn log_config(dir: &TempDir) -> LogStorageConfig {
+    LogStorageConfig {
+        path: dir.path().join("tinybase.log"),
+        ..LogStorageConfig::default()
+    }
+}
+
+fn open_log(dir: &TempDir) -> Arc<dyn Storage> {
+    Arc::new(LogStorage::open(&log_config(dir)).unwrap())
+}
+
+/// A cache small enough that the suite's keys get evicted now and then
+fn open_cached(_: &TempDir) -> Arc<dyn Storage> {
+    Arc::new(CachedStorage::new(Arc::new(MemoryStorage::new()), 256))
+}
+
+/// A log that compacts as soon as any line is stale, so every test runs across compactions
+fn open_compacting_log(dir: &TempDir) -> Arc<dyn Storage> {
+    let config = LogStorageConfig {
+        compact_min_bytes: 1,
+        ..log_config(dir)
+    };
+    Arc::new(LogStorage::open(&config).unwrap())
+}
+
+fn set(key: &str, value: &str) -> WriteOp {
+    WriteOp::Set {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+fn insert(key: &str, value: &str) -> WriteOp {
+    WriteOp::Insert {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+fn create(key: &str, value: &str) -> WriteOp {
+    WriteOp::Create {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+fn delete(key: &str) -> WriteOp {
+    WriteOp::Delete {
+        key: key.to_string(),
+    }
+}
+
+fn batch_outcomes(storage: &dyn Storage) {
+    storage.set("a".into(), "1".into()).unwrap();
+    let outcomes = storage
+        .batch(vec![
+            set("a", "2"),
+            set("b", "1"),
+            insert("a", "3"),
+            insert("c", "1"),
+            create("d", "1"),
+            delete("b"),
+            delete("missing"),
+        ])
+        .unwrap();
+    assert_eq!(
+        outcomes,
+        vec![
+            WriteOutcome::Updated,
+            WriteOutcome::Created,
+            WriteOutcome::Skipped,
+            WriteOutcome::Created,
+            WriteOutcome::Created,
+            WriteOutcome::Deleted,
+            WriteOutcome::NotFound,
+        ]
+    );
+    assert_eq!(storage.get("a").unwrap().as_deref(), Some("2"));
+    assert_eq!(storage.get("b").unwrap(), None);
+    assert_eq!(storage.scan("").unwrap(), vec!["a", "c", "d"]);
+}
+
+fn create_existing_key_fails_the_batch(storage: &dyn Storage) {
+    storage.set("a".into(), "1".into()).unwrap();
+    let error = storage
+        .batch(vec![set("b", "1"), create("a", "2")])
+        .unwrap_err();
+    assert!(error.is::<KeyExists>());
+    assert_eq!(storage.get("a").unwrap().as_deref(), Some("1"));
+    assert_eq!(storage.get("b").unwrap(), None);
+}
+
+fn create_after_write_in_same_batch(storage: &dyn Storage) {
+    let error = storage
+        .batch(vec![create("a", "1"), create("a", "2")])
+        .unwrap_err();
+    assert!(error.is::<KeyExists>());
+    assert_eq!(storage.get("a").unwrap(), None);
+
+    // A key deleted earlier in the batch can be created again
+    storage.set("b".into(), "1".into()).unwrap();
+    let outcomes = storage.batch(vec![delete("b"), create("b", "2")]).unwrap();
+    assert_eq!(outcomes, vec![WriteOutcome::Deleted, WriteOutcome::Created]);
+    assert_eq!(storage.get("b").unwrap().as_deref(), Some("2"));
+}
+
+fn transact_applies_every_step(storage: &dyn Storage) {
+    storage.set("count".into(), "1".into()).unwrap();
+    let version = storage.get_versioned("count").unwrap().unwrap().version;
+    let results = storage
+        .transact(vec![
+            TxOp::CheckVersion {
+                key: "count".into(),
+                version,
+            },
+            TxOp::Increment {
+                key: "count".into(),
+                by: 2,
+            },
+            TxOp::Set {
+                key: "a".into(),
+                value: "1".into(),
+            },
+            TxOp::Get { key: "a".into() },
+            TxOp::Delete { key: "a".into() },
+        ])
+        .unwrap();
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[1].value.as_deref(), Some("3"));
+    assert_eq!(results[3].value.as_deref(), Some("1"));
+    assert_eq!(results[4].value, None);
+    assert_eq!(storage.get("count").unwrap().as_deref(), Some("3"));
+    assert_eq!(storage.get("a").unwrap(), None);
+}
+
+fn transact_aborts_on_stale_version(storage: &dyn Storage) {
+    storage.set("a".into(), "1".into()).unwrap();
+    let version = storage.get_versioned("a").unwrap().unwrap().version;
+    storage.set("a".into(), "2".into()).unwrap();
+    let error = storage
+        .transact(vec![
+            TxOp::Set {
+                key: "b".into(),
+                value: "1".into(),
+            },
+            TxOp::CheckVersion {
+                key: "a".into(),
+                version,
+            },
+        ])
+        .unwrap_err();
+    let aborted = error.downcast::<TxAborted>().unwrap();
+    assert_eq!(aborted.index, 1);
+    assert_eq!(storage.get("b").unwrap(), None);
+}
+
+fn versions_never_repeat(storage: &dyn Storage) {
+    storage.set("other".into(), "1".into()).unwrap();
+    storage.set("a".into(), "1".into()).unwrap();
+    let first = storage.get_versioned("a").unwrap().unwrap().version;
+    assert_eq!(first, storage.last_seq().unwrap());
+
+    storage.delete("a").unwrap();
+    storage.batch(vec![insert("a", "1")]).unwrap();
+    let recreated = storage.get_versioned("a").unwrap().unwrap().version;
+    assert!(recreated > first);
+
+    // A check against the version from before the delete fails
+    let error = storage
+        .transact(vec![TxOp::CheckVersion {
+            key: "a".into(),
+            version: first,
+        }])
+        .unwrap_err();
+    assert!(error.is::<TxAborted>());
+
+    let results = storage
+        .transact(vec![
+            TxOp::Delete { key: "a".into() },
+            TxOp::Set {
+                key: "a".into(),
+                value: "2".into(),
+            },
+        ])
+        .unwrap();
+    assert!(results[1].version > recreated);
+    assert_eq!(results[1].version, storage.last_seq().unwrap());
+    assert_eq!(
+        storage.get_versioned("a").unwrap().unwrap().version,
+        results[1].version
+    );
+}
+
+fn changes_since_returns_changes_in_order(storage: &dyn Storage) {
+    let start = storage.last_seq().unwrap();
+    storage
+        .batch(vec![set("a", "1"), set("other", "1")])
+        .unwrap();
+    storage.delete("a").unwrap();
+    assert_eq!(storage.last_seq().unwrap(), start + 3);
+
+    let events = storage.changes_since("a", start, 10).unwrap();
+    assert_eq!(
+        events,
+        vec![
+            Event {
+                seq: start + 1,
+                change: Change::Set {
+                    key: "a".into(),
+                    value: "1".into(),
+                },
+            },
+            Event {
+                seq: start + 3,
+                change: Change::Delete { key: "a".into() },
+            },
+        ]
+    );
+    assert_eq!(storage.changes_since("", start + 1, 1).unwrap().len(), 1);
+    assert!(storage.changes_since("", start + 3, 10).unwrap().is_empty());
+}
+
+fn changes_since_pruned_changes_fails(storage: &dyn Storage) {
+    for value in ["1", "2", "3", "4"] {
+        storage.set("a".into(), value.into()).unwrap();
+    }
+    let last_seq = storage.last_seq().unwrap();
+    let retention = ChangeLogConfig {
+        retain_secs: 0,
+        max_entries: 2,
+    };
+    assert_eq!(storage.prune_changes(&retention).unwrap(), 2);
+
+    let error = storage.changes_since("", 0, 10).unwrap_err();
+    let pruned = error.downcast::<ChangesPruned>().unwrap();
+    assert_eq!(pruned.last_seq, last_seq);
+    assert_eq!(
+        storage.changes_since("", last_seq - 2, 10).unwrap().len(),
+        2
+    );
+    // A number from another log can't be resumed from either
+    assert!(storage.changes_since("", last_seq + 5, 10).is_err());
+}
+
+fn watch_gets_each_batch_as_one_group(storage: &dyn Storage) {
+    let mut changes = storage.watch();
+    storage
+        .batch(vec![set("a", "1"), set("b", "1"), insert("a", "2")])
+        .unwrap();
+    let group = changes.try_recv().unwrap();
+    let keys: Vec<&str> = group.0.iter().map(|event| event.change.key()).collect();
+    assert_eq!(keys, vec!["a", "b"]);
+}
+
+fn replicate_keeps_the_leaders_versions(storage: &dyn Storage) {
+    storage.set("gone".into(), "1".into()).unwrap();
+    let mut changes = storage.watch();
+    storage
+        .replicate(vec![
+            Replicated::Set {
+                key: "a".into(),
+                value: "1".into(),
+                version: 40,
+            },
+            Replicated::Set {
+                key: "b".into(),
+                value: "2".into(),
+                version: 42,
+            },
+            Replicated::Delete { key: "gone".into() },
+            Replicated::Delete {
+                key: "missing".into(),
+            },
+        ])
+        .unwrap();
+
+    let entries = storage.scan_versioned("", None, 10).unwrap();
+    let versions: Vec<(&str, u64)> = entries
+        .iter()
+        .map(|entry| (entry.key.as_str(), entry.version))
+        .collect();
+    assert_eq!(versions, vec![("a", 40), ("b", 42)]);
+
+    // One group, numbered in this change log, deletes of missing keys left out
+    let group = changes.try_recv().unwrap();
+    let seqs: Vec<u64> = group.0.iter().map(|event| event.seq).collect();
+    assert_eq!(seqs, vec![2, 3, 4]);
+    assert!(changes.try_recv().is_err());
+}
+
+/// Runs every test in the suite against one backend
+macro_rules! suite {
+    ($backend:ident, $open:ident, $($test:ident),* $(,)?) => {
+        mod $backend {
+            $(
+                #[test]
+                fn $test() {
+                    let dir = tempfile::tempdir().unwrap();
+                    let storage = super::$open(&dir);
+                    super::$test(storage.as_ref());
+                }
+            )*
+        }
+    };
+}
+
+macro_rules! backends {
+    ($($test:ident),* $(,)?) => {
+        suite!(sqlite, open_sqlite, $($test),*);
+        suite!(memory, open_memory, $($test),*);
+        suite!(log, open_log, $($test),*);
+        suite!(compacting_log, open_compacting_log, $($test),*);
+        suite!(cached, open_cached, $($test),*);
+    };
+}
+
+backends!(
+    batch_outcomes,
+    create_existing_key_fails_the_batch,
+    create_after_write_in_same_batch,
+    transact_applies_every_step,
+    transact_aborts_on_stale_version,
+    versions_never_repeat,
+    changes_since_returns_changes_in_order,
+    changes_since_pruned_changes_fails,
+    watch_gets_each_batch_as_one_group,
+    replicate_keeps_the_leaders_versions,
+);
+
+#[test]
+fn log_compaction_keeps_every_key_and_seq() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = LogStorageConfig {
+        compact_min_bytes: 1,
+        ..log_config(&dir)
+    };
+    let storage = LogStorage::open(&config).unwrap();
+    storage.set("a".into(), "1".into()).unwrap();
+    storage.set("a".into(), "2".into()).unwrap();
+    // Half the lines are stale, so the log was rewritten from a checkpoint
+    let log = std::fs::read_to_string(&config.path).unwrap();
+    assert!(log.lines().next().unwrap().contains("\"checkpoint\""));
+    assert_eq!(log.lines().count(), 2);
+
+    // Writes after compaction land in the new file
+    storage.set("c".into(), "1".into()).unwrap();
+    let versions = storage.scan_versioned("", None, 10).unwrap();
+    drop(storage);
+
+    let storage = LogStorage::open(&config).unwrap();
+    assert_eq!(storage.scan_versioned("", None, 10).unwrap(), versions);
+    assert_eq!(storage.get("a").unwrap().as_deref(), Some("2"));
+    assert_eq!(storage.get("c").unwrap().as_deref(), Some("1"));
+    assert_eq!(storage.last_seq().unwrap(), 3);
+    storage.set("d".into(), "1".into()).unwrap();
+    assert_eq!(storage.get_versioned("d").unwrap().unwrap().version, 4);
+}
+
+#[test]
+fn log_replay_cuts_off_a_torn_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = log_config(&dir);
+    {
+        let storage = LogStorage::open(&config).unwrap();
+        storage.batch(vec![set("a", "1"), set("b", "1")]).unwrap();
+        storage.set("a".into(), "2".into()).unwrap();
+    }
+    let len = std::fs::metadata(&config.path).unwrap().len();
+    let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+    // The first line of a batch that never finished, then half of a line
+    file.write_all(
+        b"{\"op\":\"set\",\"key\":\"c\",\"value\":\"1\",\"version\":1,\"more\":true}\n{\"op\":\"se",
+    )
+    .unwrap();
+    drop(file);
+
+    let storage = LogStorage::open(&config).unwrap();
+    assert_eq!(storage.get("a").unwrap().as_deref(), Some("2"));
+    assert_eq!(storage.get("b").unwrap().as_deref(), Some("1"));
+    assert_eq!(storage.get("c").unwrap(), None);
+    assert_eq!(storage.last_seq().unwrap(), 3);
+    assert_eq!(std::fs::metadata(&config.path).unwrap().len(), len);
+
+    // Writes after the cut go where the torn write was
+    storage.set("c".into(), "1".into()).unwrap();
+    drop(storage);
+    let storage = LogStorage::open(&config).unwrap();
+    assert_eq!(storage.get("c").unwrap().as_deref(), Some("1"));
+}
+
+#[test]
+fn log_replay_refuses_damage_before_good_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = log_config(&dir);
+    {
+        let storage = LogStorage::open(&config).unwrap();
+        storage.set("a".into(), "1".into()).unwrap();
+    }
+    let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+    file.write_all(
+        b"not a record\n{\"op\":\"set\",\"key\":\"b\",\"value\":\"1\",\"version\":1,\"seq\":2}\n",
+    )
+    .unwrap();
+    drop(file);
+    let len = std::fs::metadata(&config.path).unwrap().len();
+
+    assert!(LogStorage::open(&config).is_err());
+    // Nothing was cut off
+    assert_eq!(std::fs::metadata(&config.path).unwrap().len(), len);
+}
+
+/// Room for exactly three entries with one-byte keys and values
+const THREE_ENTRIES: usize = 3 * (2 + 64);
+
+fn cached_stats(cached: &CachedStorage) -> (u64, u64, u64, u64) {
+    let stats = cached.metrics().stats();
+    (stats.hits, stats.misses, stats.evictions, stats.entries)
+}
+
+#[test]
+fn cache_evicts_the_least_recently_read() {
+    let inner: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    inner
+        .batch(vec![
+            set("a", "1"),
+            set("b", "1"),
+            set("c", "1"),
+            set("d", "1"),
+        ])
+        .unwrap();
+    let cached = CachedStorage::new(inner, THREE_ENTRIES);
+    for key in ["a", "b", "c"] {
+        cached.get(key).unwrap();
+    }
+    assert_eq!(cached_stats(&cached), (0, 3, 0, 3));
+    assert_eq!(cached.metrics().stats().bytes, THREE_ENTRIES as u64);
+
+    // Reading a makes b the oldest, so d pushes b out
+    cached.get("a").unwrap();
+    cached.get("d").unwrap();
+    assert_eq!(cached_stats(&cached), (1, 4, 1, 3));
+    cached.get("a").unwrap();
+    cached.get("c").unwrap();
+    assert_eq!(cached_stats(&cached), (3, 4, 1, 3));
+    cached.get("b").unwrap();
+    assert_eq!(cached_stats(&cached), (3, 5, 2, 3));
+
+    // Missing keys are cached too, values bigger than the whole cache aren't
+    assert_eq!(cached.get("e").unwrap(), None);
+    assert_eq!(cached.get("e").unwrap(), None);
+    assert_eq!(cached_stats(&cached), (4, 6, 3, 3));
+    cached.set("big".into(), "x".repeat(THREE_ENTRIES)).unwrap();
+    cached.get("big").unwrap();
+    cached.get("big").unwrap();
+    assert_eq!(cached_stats(&cached), (4, 8, 3, 3));
+}
+
+#[test]
+fn cache_never_serves_a_value_written_over() {
+    let cached = CachedStorage::new(Arc::new(MemoryStorage::new()), 4096);
+    cached.set("a".into(), "1".into()).unwrap();
+    assert_eq!(cached.get("a").unwrap().as_deref(), Some("1"));
+
+    cached.batch(vec![set("a", "2")]).unwrap();
+    assert_eq!(cached.get("a").unwrap().as_deref(), Some("2"));
+
+    cached
+        .transact(vec![TxOp::Increment {
+            key: "a".into(),
+            by: 1,
+        }])
+        .unwrap();
+    let read = cached.get_versioned("a").unwrap().unwrap();
+    assert_eq!(read.value, "3");
+    assert_eq!(read.version, cached.last_seq().unwrap());
+
+    cached
+        .replicate(vec![Replicated::Set {
+            key: "a".into(),
+            value: "4".into(),
+            version: 99,
+        }])
+        .unwrap();
+    assert_eq!(
+        cached.get_versioned("a").unwrap(),
+        Some(Versioned {
+            value: "4".into(),
+            version: 99
+        })
+    );
+    cached
+        .replicate(vec![Replicated::Delete { key: "a".into() }])
+        .unwrap();
+    assert_eq!(cached.get("a").unwrap(), None);
+
+    // A failed batch may have written something, so it evicts too
+    cached.set("b".into(), "1".into()).unwrap();
+    assert_eq!(cached.get("a").unwrap(), None);
+    cached
+        .batch(vec![set("a", "5"), create("b", "2")])
+        .unwrap_err();
+    assert_eq!(cached.get("a").unwrap(), None);
+    assert_eq!(cached.metrics().stats().hits, 1);
+}
+
+/// Memory storage that lets a write in between a read and its result
+struct WriteDuringRead {
+    inner: MemoryStorage,
+    write: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+impl Storage for WriteDuringRead {
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, StorageError> {
+        let read = self.inner.get_versioned(key)?;
+        if let Some(write) = self.write.lock().unwrap().take() {
+            write();
+        }
+        Ok(read)
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.inner.scan(prefix)
+    }
+
+    fn scan_versioned(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<VersionedEntry>, StorageError> {
+        self.inner.scan_versioned(prefix, after, limit)
+    }
+
+    fn snapshot(&self, prefix: &str) -> Result<(Vec<Entry>, u64), StorageError> {
+        self.inner.snapshot(prefix)
+    }
+
+    fn batch(&self, ops: Vec<WriteOp>) -> Result<Vec<WriteOutcome>, StorageError> {
+        self.inner.batch(ops)
+    }
+
+    fn replicate(&self, writes: Vec<Replicated>) -> Result<(), StorageError> {
+        self.inner.replicate(writes)
+    }
+
+    fn transact(&self, ops: Vec<TxOp>) -> Result<Vec<TxResult>, StorageError> {
+        self.inner.transact(ops)
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Changes> {
+        self.inner.watch()
+    }
+
+    fn changes_since(
+        &self,
+        prefix: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Event>, StorageError> {
+        self.inner.changes_since(prefix, after, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64, StorageError> {
+        self.inner.last_seq()
+    }
+
+    fn prune_changes(&self, retention: &ChangeLogConfig) -> Result<u64, StorageError> {
+        self.inner.prune_changes(retention)
+    }
+
+    fn backup_to(&self, path: &Path) -> Result<(), StorageError> {
+        self.inner.backup_to(path)
+    }
+}
+
+#[test]
+fn cache_drops_a_read_a_write_overtook() {
+    let inner = Arc::new(WriteDuringRead {
+        inner: MemoryStorage::new(),
+        write: Mutex::new(None),
+    });
+    inner.set("a".into(), "1".into()).unwrap();
+    let cached = Arc::new(CachedStorage::new(inner.clone(), 4096));
+
+    let writer = cached.clone();
+    *inner.write.lock().unwrap() = Some(Box::new(move || {
+        writer.set("a".into(), "2".into()).unwrap();
+    }));
+    // This read started before the write, so it returns the old value but doesn't keep it
+    assert_eq!(cached.get("a").unwrap().as_deref(), Some("1"));
+    assert_eq!(cached.metrics().stats().entries, 0);
+    assert_eq!(cached.get("a").unwrap().as_deref(), Some("2"));
+    assert_eq!(cached.get("a").unwrap().as_deref(), Some("2"));
+    assert_eq!(cached_stats(&cached), (1, 2, 0, 1));
+}
@@ -0,0 +1,192 @@
+//! Multi-key transactions.
+//!
+//! A transaction is an ordered list of operations that either all commit or
+//! none do. A key's version is the sequence number of the change that last wrote
+//! it, so versions only go up and a key that is deleted and set again never gets
+//! an old version back. `check_version` aborts the transaction if a key moved on
+//! since the client last read it. A missing key is version 0.
+//!
+//! Backends only need to look keys up and apply the final result, `plan` does
+//! the rest so every backend behaves the same.
+
+use crate::storage::{Change, StorageError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+/// One step of a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TxOp {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Delete {
+        key: String,
+    },
+    /// Aborts unless the key is at `version`, 0 means the key must not exist
+    CheckVersion {
+        key: String,
+        version: u64,
+    },
+    /// Adds `by` to an integer value, a missing key counts as 0
+    Increment {
+        key: String,
+        #[serde(default = "default_increment")]
+        by: i64,
+    },
+}
+
+fn default_increment() -> i64 {
+    1
+}
+
+impl TxOp {
+    pub fn key(&self) -> &str {
+        match self {
+            TxOp::Get { key }
+            | TxOp::Set { key, .. }
+            | TxOp::Delete { key }
+            | TxOp::CheckVersion { key, .. }
+            | TxOp::Increment { key, .. } => key,
+        }
+    }
+}
+
+/// A value with its version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned {
+    pub value: String,
+    pub version: u64,
+}
+
+/// The state of a key right after an operation ran, `value` is null and `version` 0 when it doesn't exist
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TxResult {
+    pub key: String,
+    pub value: Option<String>,
+    pub version: u64,
+}
+
+/// Returned when an operation's precondition fails, nothing was written
+#[derive(Debug)]
+pub struct TxAborted {
+    /// Index of the failing operation
+    pub index: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for TxAborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Operation {} failed: {}", self.index, self.reason)
+    }
+}
+
+impl Error for TxAborted {}
+
+/// What a transaction does once it commits
+#[derive(Debug, Default)]
+pub struct TxPlan {
+    pub results: Vec<TxResult>,
+    /// Changes for listeners, in the order the operations made them
+    pub changes: Vec<Change>,
+    /// Final state of every key that was written, `None` for deleted keys
+    pub writes: BTreeMap<String, Option<Versioned>>,
+}
+
+/// Runs `ops` against the keys `lookup` returns without writing anything, `next_seq` is
+/// the sequence number the first change will get. Fails with `TxAborted` if a precondition
+/// doesn't hold.
+pub fn plan<F>(ops: &[TxOp], next_seq: u64, mut lookup: F) -> Result<TxPlan, StorageError>
+where
+    F: FnMut(&str) -> Result<Option<Versioned>, StorageError>,
+{
+    let mut plan = TxPlan::default();
+    // Keys as this transaction has left them so far
+    let mut staged: HashMap<String, Option<Versioned>> = HashMap::new();
+
+    for (index, op) in ops.iter().enumerate() {
+        let key = op.key().to_string();
+        let current = match staged.get(&key) {
+            Some(current) => current.clone(),
+            None => lookup(&key)?,
+        };
+        let current_version = current.as_ref().map_or(0, |current| current.version);
+        let existed = current.is_some();
+        // What a write by this operation sets the version to
+        let next_version = next_seq + plan.changes.len() as u64;
+        let abort = |reason: String| TxAborted { index, reason };
+
+        let next = match op {
+            TxOp::Get { .. } => current,
+            TxOp::Set { value, .. } => {
+                plan.changes.push(Change::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+                Some(Versioned {
+                    value: value.clone(),
+                    version: next_version,
+                })
+            }
+            TxOp::Delete { .. } => {
+                if existed {
+                    plan.changes.push(Change::Delete { key: key.clone() });
+                }
+                None
+            }
+            TxOp::CheckVersion { version, .. } => {
+                if current_version != *version {
+                    return Err(abort(format!(
+                        "key {key} is at version {current_version}, expected {version}"
+                    ))
+                    .into());
+                }
+                current
+            }
+            TxOp::Increment { by, .. } => {
+                let number = match &current {
+                    Some(current) => current
+                        .value
+                        .trim()
+                        .parse::<i64>()
+                        .map_err(|_| abort(format!("the value of {key} is not an integer")))?,
+                    None => 0,
+                };
+                let value = number
+                    .checked_add(*by)
+                    .ok_or_else(|| abort(format!("incrementing {key} overflows")))?
+                    .to_string();
+                plan.changes.push(Change::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+                Some(Versioned {
+                    value,
+                    version: next_version,
+                })
+            }
+        };
+
+        plan.results.push(TxResult {
+            key: key.clone(),
+            value: next.as_ref().map(|next| next.value.clone()),
+            version: next.as_ref().map_or(0, |next| next.version),
+        });
+        let wrote = match op {
+            TxOp::Set { .. } | TxOp::Increment { .. } => true,
+            TxOp::Delete { .. } => existed,
+            TxOp::Get { .. } | TxOp::CheckVersion { .. } => false,
+        };
+        if wrote {
+            plan.writes.insert(key.clone(), next.clone());
+        }
+        staged.insert(key, next);
+    }
+    Ok(plan)
+}
@@ -0,0 +1,284 @@
+//! Export and import of the whole key space, used by the admin API and the CLI.
+//!
+//! Exports are done in pages ordered by key so they can be streamed without
+//! holding everything in memory. Imports are written in batches, each batch is one `Storage::batch`.
+
+use crate::storage::{Entry, KeyExists, Storage, StorageError, WriteOp, WriteOutcome};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How many rows are read or written per round trip when nothing else is asked for
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferFormat {
+    /// One `{"key": ..., "value": ...}` object per line
+    #[default]
+    Jsonl,
+    /// A single `{"key": "value"}` object, the same shape Replit DB dumps use
+    Json,
+}
+
+impl TransferFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TransferFormat::Jsonl => "application/x-ndjson",
+            TransferFormat::Json => "application/json",
+        }
+    }
+}
+
+/// What to do when an imported key already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Keep the existing value
+    Skip,
+    /// Replace the existing value
+    #[default]
+    Overwrite,
+    /// Stop the import, batches that were already written stay written
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct ImportSummary {
+    pub inserted: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+}
+
+impl ImportSummary {
+    fn add(&mut self, other: ImportSummary) {
+        self.inserted += other.inserted;
+        self.overwritten += other.overwritten;
+        self.skipped += other.skipped;
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// A line or document could not be parsed
+    Parse { line: usize, reason: String },
+    /// The key already exists and the policy is `fail`
+    Conflict { key: String, summary: ImportSummary },
+    /// Something went wrong talking to the storage backend
+    Db(StorageError),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Parse { line, reason } => write!(f, "Line {line} is not valid: {reason}"),
+            ImportError::Conflict { key, summary } => write!(
+                f,
+                "Key {key} already exists, stopped after importing {} keys",
+                summary.inserted + summary.overwritten
+            ),
+            ImportError::Db(error) => write!(f, "Database error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// Reads the next page of entries under `prefix` that sort after `after`
+pub fn export_page(
+    storage: &dyn Storage,
+    prefix: &str,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<Vec<Entry>, StorageError> {
+    storage.scan_entries(prefix, after, limit)
+}
+
+/// Writes a batch atomically following the conflict policy and returns the totals
+pub fn import_batch(
+    storage: &dyn Storage,
+    records: &[Record],
+    policy: ConflictPolicy,
+) -> Result<ImportSummary, ImportError> {
+    let ops = records
+        .iter()
+        .map(|record| {
+            let (key, value) = (record.key.clone(), record.value.clone());
+            match policy {
+                ConflictPolicy::Skip => WriteOp::Insert { key, value },
+                ConflictPolicy::Overwrite => WriteOp::Set { key, value },
+                ConflictPolicy::Fail => WriteOp::Create { key, value },
+            }
+        })
+        .collect();
+
+    let outcomes = storage
+        .batch(ops)
+        .map_err(|error| match error.downcast::<KeyExists>() {
+            Ok(exists) => ImportError::Conflict {
+                key: exists.0,
+                summary: ImportSummary::default(),
+            },
+            Err(error) => ImportError::Db(error),
+        })?;
+
+    let mut summary = ImportSummary::default();
+    for outcome in outcomes {
+        match outcome {
+            WriteOutcome::Created => summary.inserted += 1,
+            WriteOutcome::Updated => summary.overwritten += 1,
+            WriteOutcome::Skipped => summary.skipped += 1,
+            WriteOutcome::Deleted | WriteOutcome::NotFound => {}
+        }
+    }
+    Ok(summary)
+}
+
+/// Imports batches one after another, keeping a running total for error reporting
+pub struct Importer {
+    policy: ConflictPolicy,
+    batch_size: usize,
+    pending: Vec<Record>,
+    pub summary: ImportSummary,
+}
+
+impl Importer {
+    pub fn new(policy: ConflictPolicy, batch_size: usize) -> Importer {
+        Importer {
+            policy,
+            batch_size: batch_size.max(1),
+            pending: Vec::new(),
+            summary: ImportSummary::default(),
+        }
+    }
+
+    /// Queues a record, returns true once a full batch is waiting to be flushed
+    pub fn push(&mut self, record: Record) -> bool {
+        self.pending.push(record);
+        self.pending.len() >= self.batch_size
+    }
+
+    /// Hands the queued batch off so it can be written on another thread
+    pub fn take_batch(&mut self) -> Vec<Record> {
+        std::mem::take(&mut self.pending)
+    }
+
+    pub fn policy(&self) -> ConflictPolicy {
+        self.policy
+    }
+
+    /// Adds a written batch to the running total, or fills the total into a conflict
+    pub fn record_result(
+        &mut self,
+        result: Result<ImportSummary, ImportError>,
+    ) -> Result<(), ImportError> {
+        match result {
+            Ok(summary) => {
+                self.summary.add(summary);
+                Ok(())
+            }
+            Err(ImportError::Conflict { key: conflict, .. }) => Err(ImportError::Conflict {
+                key: conflict,
+                summary: self.summary,
+            }),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Writes whatever is queued on the current thread
+    pub fn flush(&mut self, storage: &dyn Storage) -> Result<(), ImportError> {
+        let batch = self.take_batch();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let result = import_batch(storage, &batch, self.policy);
+        self.record_result(result)
+    }
+}
+
+/// Parses one line of a JSONL import, blank lines are skipped
+pub fn parse_jsonl_line(line_number: usize, line: &str) -> Result<Option<Record>, ImportError> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str::<Record>(line)
+        .map(Some)
+        .map_err(|error| ImportError::Parse {
+            line: line_number,
+            reason: error.to_string(),
+        })
+}
+
+/// Parses a Replit DB style `{"key": "value"}` document
+pub fn parse_json_object(body: &[u8]) -> Result<Vec<Record>, ImportError> {
+    let object: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(body).map_err(|error| ImportError::Parse {
+            line: error.line(),
+            reason: error.to_string(),
+        })?;
+    Ok(object
+        .into_iter()
+        .map(|(key, value)| Record {
+            key,
+            value: match value {
+                serde_json::Value::String(text) => text,
+                other => other.to_string(),
+            },
+        })
+        .collect())
+}
+
+/// Turns exported entries into the bytes of the chosen format
+pub struct Exporter {
+    format: TransferFormat,
+    first: bool,
+}
+
+impl Exporter {
+    pub fn new(format: TransferFormat) -> Exporter {
+        Exporter {
+            format,
+            first: true,
+        }
+    }
+
+    pub fn start(&self) -> String {
+        match self.format {
+            TransferFormat::Jsonl => String::new(),
+            TransferFormat::Json => "{".to_string(),
+        }
+    }
+
+    pub fn entry(&mut self, entry: &Entry) -> String {
+        let separator = if self.first { "" } else { "," };
+        self.first = false;
+        match self.format {
+            TransferFormat::Jsonl => {
+                let record = Record {
+                    key: entry.key.clone(),
+                    value: entry.value.clone(),
+                };
+                // Serializing two strings can not fail
+                format!("{}\n", serde_json::to_string(&record).unwrap())
+            }
+            TransferFormat::Json => format!(
+                "{separator}{}:{}",
+                serde_json::Value::String(entry.key.clone()),
+                serde_json::Value::String(entry.value.clone())
+            ),
+        }
+    }
+
+    pub fn finish(&self) -> String {
+        match self.format {
+            TransferFormat::Jsonl => String::new(),
+            TransferFormat::Json => "}\n".to_string(),
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! Runs triggers around the writes of the backend it wraps, see `triggers`.
+//!
+//! Before triggers run on every write in a batch or transaction before any of
+//! it is committed, one rejection fails the whole thing. Increments in a
+//! transaction only run after triggers, their value isn't known until then.
+//!
+//! A write is planned against the keys as they are, then committed in one
+//! transaction with what its after triggers asked for and a version check on
+//! every key that was read along the way. If one of those keys changed in the
+//! meantime the write is planned again, triggers and all.
+
+use crate::config::ChangeLogConfig;
+use crate::storage::transaction;
+use crate::storage::*;
+use crate::triggers::{Reader, TriggerRejected, Triggers};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// Times a write is planned before giving up on keys that keep changing under it
+const MAX_PLANS: usize = 16;
+
+pub struct TriggeredStorage {
+    inner: Arc<dyn Storage>,
+    triggers: Arc<Triggers>,
+}
+
+/// The keys as a write being planned sees them
+struct View {
+    inner: Arc<dyn Storage>,
+    /// Version of every key read from the backend when it was first read, 0 if it was missing
+    read: RefCell<BTreeMap<String, u64>>,
+    /// What the write leaves, once it's planned, so after triggers see it
+    planned: RefCell<BTreeMap<String, Option<String>>>,
+}
+
+impl View {
+    fn new(inner: Arc<dyn Storage>) -> Rc<View> {
+        Rc::new(View {
+            inner,
+            read: RefCell::default(),
+            planned: RefCell::default(),
+        })
+    }
+
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, StorageError> {
+        let current = self.inner.get_versioned(key)?;
+        self.read
+            .borrow_mut()
+            .entry(key.to_string())
+            .or_insert(current.as_ref().map_or(0, |current| current.version));
+        Ok(current)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        if let Some(planned) = self.planned.borrow().get(key) {
+            return Ok(planned.clone());
+        }
+        Ok(self.get_versioned(key)?.map(|current| current.value))
+    }
+
+    fn plan(&self, changes: &[Change]) {
+        let mut planned = self.planned.borrow_mut();
+        for change in changes {
+            let value = match change {
+                Change::Set { value, .. } => Some(value.clone()),
+                Change::Delete { .. } => None,
+            };
+            planned.insert(change.key().to_string(), value);
+        }
+    }
+
+    fn reader(self: &Rc<View>) -> Reader {
+        let view = self.clone();
+        Rc::new(move |key: &str| view.get(key))
+    }
+
+    /// Checks that every key read is still where it was
+    fn checks(&self) -> Vec<TxOp> {
+        self.read
+            .borrow()
+            .iter()
+            .map(|(key, &version)| TxOp::CheckVersion {
+                key: key.clone(),
+                version,
+            })
+            .collect()
+    }
+}
+
+impl TriggeredStorage {
+    pub fn new(inner: Arc<dyn Storage>, triggers: Arc<Triggers>) -> TriggeredStorage {
+        TriggeredStorage { inner, triggers }
+    }
+
+    /// Runs the before triggers of a batch write, along with its outcome. Inserts the
+    /// backend would skip don't run any.
+    fn before_write(
+        &self,
+        reader: &Reader,
+        op: WriteOp,
+        exists: bool,
+    ) -> Result<(WriteOp, WriteOutcome), StorageError> {
+        let created = match exists {
+            true => WriteOutcome::Updated,
+            false => WriteOutcome::Created,
+        };
+        let planned = match op {
+            WriteOp::Set { key, value } => {
+                let value = self.triggers.before_set(reader, &key, value)?;
+                (WriteOp::Set { key, value }, created)
+            }
+            WriteOp::Insert { .. } if exists => (op, WriteOutcome::Skipped),
+            WriteOp::Insert { key, value } => {
+                let value = self.triggers.before_set(reader, &key, value)?;
+                (WriteOp::Insert { key, value }, WriteOutcome::Created)
+            }
+            WriteOp::Create { key, .. } if exists => return Err(Box::new(KeyExists(key))),
+            WriteOp::Create { key, value } => {
+                let value = self.triggers.before_set(reader, &key, value)?;
+                (WriteOp::Create { key, value }, WriteOutcome::Created)
+            }
+            WriteOp::Delete { key } => {
+                self.triggers.before_delete(reader, &key)?;
+                let outcome = match exists {
+                    true => WriteOutcome::Deleted,
+                    false => WriteOutcome::NotFound,
+                };
+                (WriteOp::Delete { key }, outcome)
+            }
+        };
+        Ok(planned)
+    }
+
+    fn before_tx(&self, reader: &Reader, op: TxOp) -> Result<TxOp, StorageError> {
+        let op = match op {
+            TxOp::Set { key, value } => {
+                let value = self.triggers.before_set(reader, &key, value)?;
+                TxOp::Set { key, value }
+            }
+            TxOp::Delete { key } => {
+                self.triggers.before_delete(reader, &key)?;
+                TxOp::Delete { key }
+            }
+            op => op,
+        };
+        Ok(op)
+    }
+
+    /// Plans a batch once, returns `None` if a key it read changed before it committed
+    fn plan_batch(&self, ops: &[WriteOp]) -> Result<Option<Vec<WriteOutcome>>, StorageError> {
+        let view = View::new(self.inner.clone());
+        let reader = view.reader();
+        // Whether keys written earlier in the batch exist, the backend hasn't seen them yet
+        let mut staged: BTreeMap<String, bool> = BTreeMap::new();
+        let mut planned = Vec::with_capacity(ops.len());
+        let mut outcomes = Vec::with_capacity(ops.len());
+        for op in ops {
+            let exists = match staged.get(op.key()) {
+                Some(&exists) => exists,
+                None => view.get_versioned(op.key())?.is_some(),
+            };
+            let (op, outcome) = self.before_write(&reader, op.clone(), exists)?;
+            staged.insert(op.key().to_string(), !matches!(op, WriteOp::Delete { .. }));
+            planned.push(op);
+            outcomes.push(outcome);
+        }
+
+        let changes = changes_for(&planned, &outcomes);
+        view.plan(&changes);
+        let writes = self.triggers.after(&reader, &changes)?;
+        let ops = changes
+            .into_iter()
+            .map(|change| match change {
+                Change::Set { key, value } => TxOp::Set { key, value },
+                Change::Delete { key } => TxOp::Delete { key },
+            })
+            .collect();
+        Ok(self.commit(&view, ops, writes)?.map(|_| outcomes))
+    }
+
+    /// Plans a transaction once, returns `None` if a key it read changed before it committed
+    fn plan_tx(&self, ops: &[TxOp]) -> Result<Option<Vec<TxResult>>, StorageError> {
+        let view = View::new(self.inner.clone());
+        let reader = view.reader();
+        let ops = ops
+            .iter()
+            .map(|op| self.before_tx(&reader, op.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Versions aren't known until it commits
+        let plan = transaction::plan(&ops, 0, |key| view.get_versioned(key))?;
+        view.plan(&plan.changes);
+        let writes = self.triggers.after(&reader, &plan.changes)?;
+        self.commit(&view, ops, writes)
+    }
+
+    /// Commits `ops` in one transaction with the writes after triggers asked for, behind a check
+    /// on every key `view` read. Returns the results of `ops`, or `None` if one of those keys changed.
+    fn commit(
+        &self,
+        view: &View,
+        ops: Vec<TxOp>,
+        writes: Vec<(i64, TxOp)>,
+    ) -> Result<Option<Vec<TxResult>>, StorageError> {
+        let mut planned = view.checks();
+        let checks = planned.len();
+        let end = checks + ops.len();
+        planned.extend(ops);
+        let (triggers, writes): (Vec<i64>, Vec<TxOp>) = writes.into_iter().unzip();
+        planned.extend(writes);
+
+        let error = match self.inner.transact(planned) {
+            Ok(mut results) => {
+                results.truncate(end);
+                return Ok(Some(results.split_off(checks)));
+            }
+            Err(error) => error,
+        };
+        match error.downcast::<TxAborted>() {
+            Ok(aborted) if aborted.index < checks => Ok(None),
+            Ok(mut aborted) if aborted.index < end => {
+                aborted.index -= checks;
+                Err(aborted)
+            }
+            Ok(aborted) => Err(Box::new(TriggerRejected {
+                trigger: triggers[aborted.index - end],
+                reason: aborted.reason,
+            })),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Plans a write until it commits without a key it read changing under it
+    fn retry<T>(
+        &self,
+        mut plan: impl FnMut() -> Result<Option<T>, StorageError>,
+    ) -> Result<T, StorageError> {
+        for _ in 0..MAX_PLANS {
+            if let Some(done) = plan()? {
+                return Ok(done);
+            }
+        }
+        Err(Box::new(Busy(format!(
+            "the keys a trigger read changed {MAX_PLANS} times before the write could commit"
+        ))))
+    }
+}
+
+impl Storage for TriggeredStorage {
+    fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, StorageError> {
+        self.inner.get_versioned(key)
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.inner.scan(prefix)
+    }
+
+    fn scan_versioned(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<VersionedEntry>, StorageError> {
+        self.inner.scan_versioned(prefix, after, limit)
+    }
+
+    fn snapshot(&self, prefix: &str) -> Result<(Vec<Entry>, u64), StorageError> {
+        self.inner.snapshot(prefix)
+    }
+
+    fn batch(&self, ops: Vec<WriteOp>) -> Result<Vec<WriteOutcome>, StorageError> {
+        if !ops.iter().any(|op| self.triggers.watches(op.key())) {
+            return self.inner.batch(ops);
+        }
+        self.retry(|| self.plan_batch(&ops))
+    }
+
+    /// The leader already ran its triggers on these writes
+    fn replicate(&self, writes: Vec<Replicated>) -> Result<(), StorageError> {
+        self.inner.replicate(writes)
+    }
+
+    fn transact(&self, ops: Vec<TxOp>) -> Result<Vec<TxResult>, StorageError> {
+        if !ops.iter().any(|op| self.triggers.watches(op.key())) {
+            return self.inner.transact(ops);
+        }
+        self.retry(|| self.plan_tx(&ops))
+    }
+
+    fn watch(&self) -> broadcast::Receiver<Changes> {
+        self.inner.watch()
+    }
+
+    fn changes_since(
+        &self,
+        prefix: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Event>, StorageError> {
+        self.inner.changes_since(prefix, after, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64, StorageError> {
+        self.inner.last_seq()
+    }
+
+    fn prune_changes(&self, retention: &ChangeLogConfig) -> Result<u64, StorageError> {
+        self.inner.prune_changes(retention)
+    }
+
+    fn backup_to(&self, path: &Path) -> Result<(), StorageError> {
+        self.inner.backup_to(path)
+    }
+}
@@ -0,0 +1,588 @@
+//! Triggers, scripts run around writes.
+//!
+//! Admins register a [Rhai](https://rhai.rs) script to run before or after
+//! keys under a prefix are set or deleted. They are kept in the SQLite database
+//! at `database.url`, whichever backend holds the keys, and run in the order
+//! they were registered. `storage::triggered` runs them for every write made
+//! through the server. The CLI and a follower's replication write around them.
+//!
+//! A script sees `key`, `value` (`()` for a delete) and `event`, and can `get`
+//! any key. A before trigger rejects the write by throwing, and changes the
+//! value being set by evaluating to a string or assigning one to `value`. An
+//! after trigger runs on the changes a write makes, `get` sees them, and can
+//! `set`, `delete` and `increment` other keys. Those writes commit along with
+//! the write that set it off and don't run triggers themselves. An after
+//! trigger that fails, or asks for writes that can't be applied, rejects the
+//! write like a before trigger does.
+//!
+//! Scripts can't reach files or the network. A run is stopped after
+//! `timeout_ms` or `max_operations`, and strings, arrays and maps can't grow
+//! past the configured sizes.
+
+use crate::config::TriggersConfig;
+use crate::data_access::{
+    models::{InsertTrigger, Trigger},
+    pool::DbPool,
+    triggers::*,
+};
+use crate::limits::Limits;
+use crate::storage::change_log::unix_time;
+use crate::storage::{Change, StorageError, TxOp};
+use actix_web::{http::StatusCode, HttpResponse};
+use rhai::packages::{Package, StandardPackage};
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests;
+
+/// Longest script accepted, in bytes
+const MAX_SCRIPT_LEN: usize = 64 * 1024;
+
+/// Longest prefix accepted, in bytes
+const MAX_PREFIX_LEN: usize = 2048;
+
+/// Operations between looks at the clock
+const CLOCK_INTERVAL: u64 = 1024;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// Reads a key for a script's `get`
+pub type Reader = Rc<dyn Fn(&str) -> Result<Option<String>, StorageError>>;
+
+/// What the functions scripts call need to know about the run in progress. Rhai only hands a
+/// run's own state to functions called through `call_fn`, not to a whole script, so it's kept
+/// with the thread for as long as the script runs.
+struct Run {
+    deadline: Instant,
+    reader: Reader,
+    /// What an after trigger asked for so far, `None` for before triggers
+    writes: Option<Vec<TxOp>>,
+}
+
+thread_local! {
+    static RUN: RefCell<Option<Run>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Can change or reject the write
+    Before,
+    /// Sees the committed write and can write other keys
+    After,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerEvent {
+    Set,
+    Delete,
+}
+
+impl Phase {
+    fn as_str(self) -> &'static str {
+        match self {
+            Phase::Before => "before",
+            Phase::After => "after",
+        }
+    }
+
+    fn parse(phase: &str) -> Option<Phase> {
+        match phase {
+            "before" => Some(Phase::Before),
+            "after" => Some(Phase::After),
+            _ => None,
+        }
+    }
+}
+
+impl TriggerEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            TriggerEvent::Set => "set",
+            TriggerEvent::Delete => "delete",
+        }
+    }
+
+    fn parse(event: &str) -> Option<TriggerEvent> {
+        match event {
+            "set" => Some(TriggerEvent::Set),
+            "delete" => Some(TriggerEvent::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// What admins send to register a trigger
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewTrigger {
+    pub phase: Phase,
+    pub event: TriggerEvent,
+    #[serde(default)]
+    pub prefix: String,
+    pub script: String,
+}
+
+/// A trigger as the admin API shows it
+#[derive(Debug, Serialize)]
+pub struct TriggerInfo {
+    pub id: i64,
+    pub phase: Phase,
+    pub event: TriggerEvent,
+    pub prefix: String,
+    pub script: String,
+    pub created_at: i64,
+}
+
+/// Why a trigger could not be registered
+#[derive(Debug)]
+pub enum TriggerError {
+    Invalid(String),
+    Db(StorageError),
+}
+
+impl fmt::Display for TriggerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriggerError::Invalid(reason) => write!(f, "Invalid trigger: {reason}"),
+            TriggerError::Db(error) => write!(f, "Database error: {error}"),
+        }
+    }
+}
+
+impl Error for TriggerError {}
+
+impl From<StorageError> for TriggerError {
+    fn from(error: StorageError) -> TriggerError {
+        TriggerError::Db(error)
+    }
+}
+
+impl TriggerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            TriggerError::Invalid(_) => StatusCode::BAD_REQUEST,
+            TriggerError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).body(self.to_string())
+    }
+}
+
+/// Returned when a before trigger throws or fails, nothing was written
+#[derive(Debug)]
+pub struct TriggerRejected {
+    pub trigger: i64,
+    pub reason: String,
+}
+
+impl fmt::Display for TriggerRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rejected by trigger {}: {}", self.trigger, self.reason)
+    }
+}
+
+impl Error for TriggerRejected {}
+
+/// A registered trigger, compiled
+struct Script {
+    trigger: Trigger,
+    phase: Phase,
+    event: TriggerEvent,
+    ast: AST,
+}
+
+impl Script {
+    fn info(&self) -> TriggerInfo {
+        TriggerInfo {
+            id: self.trigger.id,
+            phase: self.phase,
+            event: self.event,
+            prefix: self.trigger.prefix.clone(),
+            script: self.trigger.script.clone(),
+            created_at: self.trigger.created_at,
+        }
+    }
+}
+
+pub struct Triggers {
+    pool: DbPool,
+    /// Every registered trigger in the order they run, read for each write so it's kept in memory
+    scripts: RwLock<Vec<Arc<Script>>>,
+    config: TriggersConfig,
+    /// Writes asked for by after triggers are held to the same limits as clients
+    limits: Limits,
+    /// Built once and shared by every run, see `Run`
+    engine: Engine,
+}
+
+impl Triggers {
+    /// Compiles the triggers registered in `pool`, its schema has to be up to date
+    pub fn open(
+        pool: DbPool,
+        config: TriggersConfig,
+        limits: Limits,
+    ) -> Result<Arc<Triggers>, StorageError> {
+        let mut conn = pool.get()?;
+        let rows = get_triggers(&mut conn)?;
+        drop(conn);
+        let triggers = Triggers {
+            pool,
+            scripts: RwLock::new(Vec::new()),
+            engine: engine(&config, limits),
+            config,
+            limits,
+        };
+        let mut scripts = Vec::with_capacity(rows.len());
+        for trigger in rows {
+            let id = trigger.id;
+            let script = triggers
+                .compile(trigger)
+                .map_err(|error| format!("trigger {id}: {error}"))?;
+            scripts.push(Arc::new(script));
+        }
+        *triggers.scripts.write().unwrap() = scripts;
+        Ok(Arc::new(triggers))
+    }
+
+    pub fn register(&self, new: NewTrigger) -> Result<TriggerInfo, TriggerError> {
+        if new.prefix.len() > MAX_PREFIX_LEN {
+            return Err(TriggerError::Invalid(format!(
+                "prefix can be at most {MAX_PREFIX_LEN} bytes"
+            )));
+        }
+        if new.script.len() > MAX_SCRIPT_LEN {
+            return Err(TriggerError::Invalid(format!(
+                "script can be at most {MAX_SCRIPT_LEN} bytes"
+            )));
+        }
+        let ast = self
+            .engine
+            .compile(&new.script)
+            .map_err(|error| TriggerError::Invalid(error.to_string()))?;
+        let created_at = unix_time() as i64;
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|error| Box::new(error) as StorageError)?;
+        let id = insert_trigger(
+            &mut conn,
+            &InsertTrigger {
+                phase: new.phase.as_str(),
+                event: new.event.as_str(),
+                prefix: &new.prefix,
+                script: &new.script,
+                created_at,
+            },
+        )?;
+        let script = Script {
+            trigger: Trigger {
+                id,
+                phase: new.phase.as_str().to_string(),
+                event: new.event.as_str().to_string(),
+                prefix: new.prefix,
+                script: new.script,
+                created_at,
+            },
+            phase: new.phase,
+            event: new.event,
+            ast,
+        };
+        let info = script.info();
+        self.scripts.write().unwrap().push(Arc::new(script));
+        Ok(info)
+    }
+
+    pub fn list(&self) -> Vec<TriggerInfo> {
+        self.scripts
+            .read()
+            .unwrap()
+            .iter()
+            .map(|script| script.info())
+            .collect()
+    }
+
+    /// Returns false if there was no such trigger. Runs already started finish.
+    pub fn remove(&self, id: i64) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get()?;
+        let deleted = delete_trigger(&mut conn, id)?;
+        self.scripts
+            .write()
+            .unwrap()
+            .retain(|script| script.trigger.id != id);
+        Ok(deleted)
+    }
+
+    /// Whether any trigger runs for this key, whatever the phase or event
+    pub fn watches(&self, key: &str) -> bool {
+        self.scripts
+            .read()
+            .unwrap()
+            .iter()
+            .any(|script| key.starts_with(&script.trigger.prefix))
+    }
+
+    /// Runs the before set triggers for `key` in order, each one sees the value the last one left.
+    /// Returns the value to write, or `TriggerRejected`.
+    pub fn before_set(
+        &self,
+        reader: &Reader,
+        key: &str,
+        mut value: String,
+    ) -> Result<String, StorageError> {
+        for script in self.matching(Phase::Before, TriggerEvent::Set, key) {
+            let mut scope = scope(key, TriggerEvent::Set, Dynamic::from(value.clone()));
+            let (result, _) = self.run(&script, reader, &mut scope, None);
+            let result = result.map_err(|error| self.rejected(&script, &error))?;
+            value = match result.into_string() {
+                Ok(value) => value,
+                Err(_) => scope
+                    .get_value::<String>("value")
+                    .ok_or_else(|| rejected(&script, "value is no longer a string"))?,
+            };
+            if let Err(error) = self.limits.check_value(&value) {
+                return Err(rejected(&script, &error.to_string()));
+            }
+        }
+        Ok(value)
+    }
+
+    /// Runs the before delete triggers for `key` in order, returns `TriggerRejected` if one throws
+    pub fn before_delete(&self, reader: &Reader, key: &str) -> Result<(), StorageError> {
+        for script in self.matching(Phase::Before, TriggerEvent::Delete, key) {
+            let mut scope = scope(key, TriggerEvent::Delete, Dynamic::UNIT);
+            if let (Err(error), _) = self.run(&script, reader, &mut scope, None) {
+                return Err(self.rejected(&script, &error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the after triggers for each change a write is about to commit and returns the writes
+    /// they asked for, each with the trigger that asked. Returns `TriggerRejected` if one fails.
+    pub fn after(
+        &self,
+        reader: &Reader,
+        changes: &[Change],
+    ) -> Result<Vec<(i64, TxOp)>, StorageError> {
+        let mut writes = Vec::new();
+        for change in changes {
+            let (event, value) = match change {
+                Change::Set { value, .. } => (TriggerEvent::Set, Dynamic::from(value.clone())),
+                Change::Delete { .. } => (TriggerEvent::Delete, Dynamic::UNIT),
+            };
+            let key = change.key();
+            for script in self.matching(Phase::After, event, key) {
+                let mut scope = scope(key, event, value.clone());
+                match self.run(&script, reader, &mut scope, Some(Vec::new())) {
+                    (Ok(_), asked) => {
+                        writes.extend(asked.into_iter().map(|op| (script.trigger.id, op)))
+                    }
+                    (Err(error), _) => return Err(self.rejected(&script, &error)),
+                }
+            }
+        }
+        Ok(writes)
+    }
+
+    fn matching(&self, phase: Phase, event: TriggerEvent, key: &str) -> Vec<Arc<Script>> {
+        self.scripts
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|script| {
+                script.phase == phase
+                    && script.event == event
+                    && key.starts_with(&script.trigger.prefix)
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn compile(&self, trigger: Trigger) -> Result<Script, String> {
+        let phase = Phase::parse(&trigger.phase)
+            .ok_or_else(|| format!("unknown phase {}", trigger.phase))?;
+        let event = TriggerEvent::parse(&trigger.event)
+            .ok_or_else(|| format!("unknown event {}", trigger.event))?;
+        let ast = self
+            .engine
+            .compile(&trigger.script)
+            .map_err(|error| error.to_string())?;
+        Ok(Script {
+            trigger,
+            phase,
+            event,
+            ast,
+        })
+    }
+
+    /// Runs a script with the time limit. `writes` collects what an after trigger asks for and
+    /// is handed back along with the result.
+    fn run(
+        &self,
+        script: &Script,
+        reader: &Reader,
+        scope: &mut Scope,
+        writes: Option<Vec<TxOp>>,
+    ) -> (ScriptResult<Dynamic>, Vec<TxOp>) {
+        RUN.with(|run| {
+            run.replace(Some(Run {
+                deadline: Instant::now() + Duration::from_millis(self.config.timeout_ms),
+                reader: reader.clone(),
+                writes,
+            }))
+        });
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(scope, &script.ast);
+        let writes = RUN.with(|run| run.take()).and_then(|run| run.writes);
+        (result, writes.unwrap_or_default())
+    }
+
+    fn rejected(&self, script: &Script, error: &EvalAltResult) -> StorageError {
+        let reason = self.reason(error);
+        if !is_thrown(error) {
+            log::warn!(
+                "{} trigger {} failed: {reason}",
+                script.phase.as_str(),
+                script.trigger.id
+            );
+        }
+        rejected(script, &reason)
+    }
+
+    /// What a script threw, or what went wrong running it
+    fn reason(&self, error: &EvalAltResult) -> String {
+        match error {
+            EvalAltResult::ErrorRuntime(thrown, _) => thrown.to_string(),
+            EvalAltResult::ErrorInFunctionCall(_, _, error, _) => self.reason(error),
+            EvalAltResult::ErrorTerminated(..) => {
+                format!("ran longer than {} ms", self.config.timeout_ms)
+            }
+            error => error.to_string(),
+        }
+    }
+}
+
+fn rejected(script: &Script, reason: &str) -> StorageError {
+    Box::new(TriggerRejected {
+        trigger: script.trigger.id,
+        reason: reason.to_string(),
+    })
+}
+
+/// An engine with the standard library and the configured limits, but nothing that reaches
+/// outside. What it reads and writes goes through the `Run` in progress.
+fn engine(config: &TriggersConfig, limits: Limits) -> Engine {
+    let mut engine = Engine::new_raw();
+    engine
+        .register_global_module(StandardPackage::new().as_shared_module())
+        .disable_symbol("eval")
+        .set_max_operations(config.max_operations)
+        .set_max_string_size(config.max_string_size)
+        .set_max_array_size(config.max_collection_size)
+        .set_max_map_size(config.max_collection_size)
+        .set_max_call_levels(config.max_call_levels)
+        .on_print(|text| log::info!("trigger: {text}"))
+        .on_debug(|text, _, _| log::debug!("trigger: {text}"))
+        .on_progress(|operations| {
+            let late = operations % CLOCK_INTERVAL == 0
+                && with_run(|run| Ok(Instant::now() > run.deadline)).unwrap_or(false);
+            late.then(|| Dynamic::from("timeout"))
+        });
+    engine
+        .register_fn("now_ms", unix_time_ms)
+        .register_fn("is_json", |text: &str| {
+            serde_json::from_str::<serde::de::IgnoredAny>(text).is_ok()
+        });
+
+    engine.register_fn("get", |key: &str| -> ScriptResult<Dynamic> {
+        let reader = with_run(|run| Ok(run.reader.clone()))?;
+        match reader(key) {
+            Ok(Some(value)) => Ok(value.into()),
+            Ok(None) => Ok(Dynamic::UNIT),
+            Err(error) => Err(format!("could not read {key}: {error}").into()),
+        }
+    });
+
+    let write = move |op: TxOp| -> ScriptResult<()> {
+        let checked = match &op {
+            TxOp::Set { key, value } => limits.check(key, value),
+            op => limits.check_key(op.key()),
+        };
+        checked.map_err(|error| error.to_string())?;
+        with_run(|run| {
+            let writes = run
+                .writes
+                .as_mut()
+                .ok_or("only after triggers can write keys")?;
+            writes.push(op);
+            Ok(())
+        })
+    };
+    engine.register_fn("set", move |key: &str, value: &str| {
+        write(TxOp::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    });
+    engine.register_fn("delete", move |key: &str| {
+        write(TxOp::Delete {
+            key: key.to_string(),
+        })
+    });
+    engine.register_fn("increment", move |key: &str, by: i64| {
+        write(TxOp::Increment {
+            key: key.to_string(),
+            by,
+        })
+    });
+    engine.register_fn("increment", move |key: &str| {
+        write(TxOp::Increment {
+            key: key.to_string(),
+            by: 1,
+        })
+    });
+    engine
+}
+
+/// Calls `f` with the run in progress on this thread
+fn with_run<T>(f: impl FnOnce(&mut Run) -> ScriptResult<T>) -> ScriptResult<T> {
+    RUN.with(|run| match run.borrow_mut().as_mut() {
+        Some(run) => f(run),
+        None => Err("no trigger is running".into()),
+    })
+}
+
+fn is_thrown(error: &EvalAltResult) -> bool {
+    match error {
+        EvalAltResult::ErrorRuntime(..) => true,
+        EvalAltResult::ErrorInFunctionCall(_, _, error, _) => is_thrown(error),
+        _ => false,
+    }
+}
+
+/// What a script sees of the write it runs for
+fn scope(key: &str, event: TriggerEvent, value: Dynamic) -> Scope<'static> {
+    let mut scope = Scope::new();
+    scope.push_constant("key", key.to_string());
+    scope.push_constant("event", event.as_str().to_string());
+    scope.push("value", value);
+    scope
+}
+
+fn unix_time_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
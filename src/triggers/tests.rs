@@ -0,0 +1,131 @@
+//! Triggers run through `TriggeredStorage` over the memory backend
+
+use super::*;
+use crate::config::DatabaseConfig;
+use crate::data_access::pool;
+use crate::storage::triggered::TriggeredStorage;
+use crate::storage::{MemoryStorage, Storage, WriteOp, WriteOutcome};
+use tempfile::TempDir;
+
+fn open(dir: &TempDir) -> (Arc<Triggers>, Arc<dyn Storage>, Arc<dyn Storage>) {
+    let pool = pool::open(&DatabaseConfig::for_test(dir)).unwrap();
+    let triggers = Triggers::open(pool, TriggersConfig::default(), Limits::default()).unwrap();
+    let inner: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let storage = Arc::new(TriggeredStorage::new(inner.clone(), triggers.clone()));
+    (triggers, storage, inner)
+}
+
+fn register(triggers: &Triggers, phase: Phase, event: TriggerEvent, script: &str) -> i64 {
+    triggers
+        .register(NewTrigger {
+            phase,
+            event,
+            prefix: "users/".to_string(),
+            script: script.to_string(),
+        })
+        .unwrap()
+        .id
+}
+
+fn rejected_by(error: StorageError) -> i64 {
+    error.downcast::<TriggerRejected>().unwrap().trigger
+}
+
+#[test]
+fn before_triggers_change_the_value() {
+    let dir = tempfile::tempdir().unwrap();
+    let (triggers, storage, _) = open(&dir);
+    register(
+        &triggers,
+        Phase::Before,
+        TriggerEvent::Set,
+        r#"if key == "users/2" { value + "/" + get("users/1") }"#,
+    );
+    storage.set("users/1".into(), "a".into()).unwrap();
+    storage.set("users/2".into(), "b".into()).unwrap();
+    assert_eq!(storage.get("users/2").unwrap().as_deref(), Some("b/a"));
+}
+
+#[test]
+fn after_trigger_writes_commit_with_the_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let (triggers, storage, _) = open(&dir);
+    register(
+        &triggers,
+        Phase::After,
+        TriggerEvent::Set,
+        r#"increment("count"); set("last", get(key))"#,
+    );
+    let mut changes = storage.watch();
+
+    storage.set("users/1".into(), "a".into()).unwrap();
+    let group = changes.try_recv().unwrap();
+    let keys: Vec<&str> = group.0.iter().map(|event| event.change.key()).collect();
+    assert_eq!(keys, vec!["users/1", "count", "last"]);
+    assert_eq!(storage.get("last").unwrap().as_deref(), Some("a"));
+
+    let results = storage
+        .transact(vec![TxOp::Increment {
+            key: "users/n".into(),
+            by: 5,
+        }])
+        .unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].value.as_deref(), Some("5"));
+    assert_eq!(storage.get("count").unwrap().as_deref(), Some("2"));
+    assert_eq!(storage.get("last").unwrap().as_deref(), Some("5"));
+}
+
+#[test]
+fn failing_after_trigger_rejects_the_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let (triggers, storage, _) = open(&dir);
+    let thrown = register(
+        &triggers,
+        Phase::After,
+        TriggerEvent::Delete,
+        r#"throw "keep it""#,
+    );
+    let aborted = register(
+        &triggers,
+        Phase::After,
+        TriggerEvent::Set,
+        r#"increment("not a number")"#,
+    );
+    storage.set("not a number".into(), "x".into()).unwrap();
+
+    let error = storage.set("users/1".into(), "a".into()).unwrap_err();
+    assert_eq!(rejected_by(error), aborted);
+    assert_eq!(storage.get("users/1").unwrap(), None);
+
+    triggers.remove(aborted).unwrap();
+    storage.set("users/1".into(), "a".into()).unwrap();
+    let error = storage.delete("users/1").unwrap_err();
+    assert_eq!(rejected_by(error), thrown);
+    assert_eq!(storage.get("users/1").unwrap().as_deref(), Some("a"));
+}
+
+#[test]
+fn skipped_inserts_run_no_triggers() {
+    let dir = tempfile::tempdir().unwrap();
+    let (triggers, storage, inner) = open(&dir);
+    register(&triggers, Phase::Before, TriggerEvent::Set, r#"throw "no""#);
+    inner.set("users/1".into(), "a".into()).unwrap();
+
+    let outcomes = storage
+        .batch(vec![WriteOp::Insert {
+            key: "users/1".into(),
+            value: "b".into(),
+        }])
+        .unwrap();
+    assert_eq!(outcomes, vec![WriteOutcome::Skipped]);
+    assert_eq!(storage.get("users/1").unwrap().as_deref(), Some("a"));
+
+    let error = storage
+        .batch(vec![WriteOp::Insert {
+            key: "users/2".into(),
+            value: "b".into(),
+        }])
+        .unwrap_err();
+    assert!(error.is::<TriggerRejected>());
+}
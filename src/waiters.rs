@@ -0,0 +1,91 @@
+//! Registry of long-poll requests waiting for a key to change.
+//!
+//! Fed by the storage change stream, so every write wakes its waiters no
+//! matter if it came from REST, a transaction, an import or the WebSocket.
+//! A waiter is only told that something happened to its key, it reads the key
+//! again to find out what.
+
+use crate::storage::Changes;
+use actix_web::rt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::futures::Notified;
+use tokio::sync::{broadcast, Notify};
+
+#[derive(Default)]
+pub struct Waiters {
+    keys: Mutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl Waiters {
+    /// Starts waking waiters for every change sent on `changes`
+    pub fn start(mut changes: broadcast::Receiver<Changes>) -> Arc<Waiters> {
+        let waiters = Arc::new(Waiters::default());
+        let registry = waiters.clone();
+        rt::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(changes) => {
+                        for event in changes.0 {
+                            registry.wake(event.change.key());
+                        }
+                    }
+                    // Don't know which keys were missed, so everyone checks again
+                    Err(broadcast::error::RecvError::Lagged(_)) => registry.wake_all(),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+        waiters
+    }
+
+    /// Registers a waiter for `key`, it stays registered until the returned guard is dropped,
+    /// also when the request is abandoned half way.
+    pub fn register(&self, key: &str) -> Registration<'_> {
+        let mut keys = self.keys.lock().unwrap();
+        let notify = keys.entry(key.to_string()).or_default().clone();
+        Registration {
+            waiters: self,
+            key: key.to_string(),
+            notify,
+        }
+    }
+
+    /// Wakes the waiters of `key`, for changes that don't come from our storage
+    pub fn wake(&self, key: &str) {
+        if let Some(notify) = self.keys.lock().unwrap().get(key) {
+            notify.notify_waiters();
+        }
+    }
+
+    fn wake_all(&self) {
+        for notify in self.keys.lock().unwrap().values() {
+            notify.notify_waiters();
+        }
+    }
+}
+
+/// A waiter on one key, drops the key's entry once nobody else waits on it
+pub struct Registration<'a> {
+    waiters: &'a Waiters,
+    key: String,
+    notify: Arc<Notify>,
+}
+
+impl Registration<'_> {
+    /// Create the future before reading the key, a `Notified` hears every wake-up from
+    /// the moment it exists so nothing slips in between
+    pub fn notified(&self) -> Notified<'_> {
+        self.notify.notified()
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut keys = self.waiters.keys.lock().unwrap();
+        // One reference in the map and this one
+        if Arc::strong_count(&self.notify) <= 2 {
+            keys.remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,674 @@
+//! Webhooks, HTTP endpoints told about changes to keys.
+//!
+//! Admins register a URL with a key prefix, an optional glob pattern and an
+//! optional signing secret. They are kept in the SQLite database at
+//! `database.url`, whichever backend holds the keys. Every committed change to
+//! a matching key is POSTed to the URL as JSON.
+//!
+//! Deliveries are queued in the database, so they survive a restart, and each
+//! webhook sends its queue one delivery at a time in commit order. A delivery
+//! that doesn't get a 2xx is tried again with exponential backoff, holding up
+//! the ones behind it, and after `max_attempts` it goes to the dead letters
+//! where admins can look at it and send it again. A webhook with `max_queued`
+//! deliveries waiting gets new changes as dead letters straight away.
+//!
+//! With a secret, `X-Tinybase-Signature` is `sha256=` and the hex HMAC-SHA256
+//! of the `X-Tinybase-Timestamp` header, a `.` and the body.
+
+use crate::config::WebhooksConfig;
+use crate::data_access::{
+    actions::DbError,
+    models::{DeadLetter, Delivery, NewDeadLetter, NewDelivery, Webhook},
+    pool::DbPool,
+    webhooks::*,
+};
+use crate::storage::change_log::unix_time;
+use crate::storage::{ChangesPruned, Event, Storage, StorageError};
+use actix_web::{http::StatusCode, rt, web, HttpResponse};
+use diesel::SqliteConnection;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use uuid::Uuid;
+
+#[cfg(test)]
+mod tests;
+
+/// Longest URL, prefix or pattern accepted, in bytes
+const MAX_FIELD_LEN: usize = 2048;
+
+/// Changes read at a time when catching up after falling behind
+const CATCH_UP_PAGE: usize = 1000;
+
+/// What admins send to register a webhook
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewWebhook {
+    pub url: String,
+    #[serde(default)]
+    pub prefix: String,
+    /// Glob over the whole key, `*` is any run of characters and `?` any one character
+    pub pattern: Option<String>,
+    pub secret: Option<String>,
+}
+
+/// A webhook as the admin API shows it, without its secret
+#[derive(Debug, Serialize)]
+pub struct WebhookInfo {
+    pub id: String,
+    pub url: String,
+    pub prefix: String,
+    pub pattern: Option<String>,
+    pub signed: bool,
+    pub created_at: i64,
+}
+
+impl From<&Webhook> for WebhookInfo {
+    fn from(webhook: &Webhook) -> WebhookInfo {
+        WebhookInfo {
+            id: webhook.id.clone(),
+            url: webhook.url.clone(),
+            prefix: webhook.prefix.clone(),
+            pattern: webhook.pattern.clone(),
+            signed: webhook.secret.is_some(),
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+/// Why a webhook could not be registered
+#[derive(Debug)]
+pub enum WebhookError {
+    Invalid(String),
+    Db(StorageError),
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebhookError::Invalid(reason) => write!(f, "Invalid webhook: {reason}"),
+            WebhookError::Db(error) => write!(f, "Database error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+impl From<StorageError> for WebhookError {
+    fn from(error: StorageError) -> WebhookError {
+        WebhookError::Db(error)
+    }
+}
+
+impl WebhookError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            WebhookError::Invalid(_) => StatusCode::BAD_REQUEST,
+            WebhookError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).body(self.to_string())
+    }
+}
+
+/// A webhook's queued deliveries
+#[derive(Debug, Default)]
+struct Queue {
+    /// Deliveries in the database waiting to be sent, counting the one being sent
+    queued: usize,
+    /// A task is sending the queue
+    sending: bool,
+    /// Deliveries were queued since the sending task last looked, it has to look again before it stops
+    woken: bool,
+}
+
+pub struct Webhooks {
+    pool: DbPool,
+    /// Every registered webhook, read for each change so it's kept in memory
+    hooks: RwLock<Vec<Webhook>>,
+    config: WebhooksConfig,
+    /// Limits deliveries being sent at once, deliveries waiting to try again don't hold one
+    in_flight: Semaphore,
+    queues: Mutex<HashMap<String, Queue>>,
+    /// Asks the task started by `start` to send a webhook's queue
+    senders: mpsc::UnboundedSender<String>,
+    /// Taken by `start`
+    to_send: Mutex<Option<mpsc::UnboundedReceiver<String>>>,
+}
+
+impl Webhooks {
+    /// Loads the registered webhooks from `pool`, its schema has to be up to date
+    pub fn open(pool: DbPool, config: WebhooksConfig) -> Result<Arc<Webhooks>, StorageError> {
+        let mut conn = pool.get()?;
+        let hooks = get_webhooks(&mut conn)?;
+        let queues = count_deliveries(&mut conn)?
+            .into_iter()
+            .map(|(webhook_id, queued)| {
+                let queue = Queue {
+                    queued: queued as usize,
+                    ..Queue::default()
+                };
+                (webhook_id, queue)
+            })
+            .collect();
+        drop(conn);
+        let (senders, to_send) = mpsc::unbounded_channel();
+        Ok(Arc::new(Webhooks {
+            pool,
+            hooks: RwLock::new(hooks),
+            config,
+            in_flight: Semaphore::new(config.max_in_flight),
+            queues: Mutex::new(queues),
+            senders,
+            to_send: Mutex::new(Some(to_send)),
+        }))
+    }
+
+    pub fn register(&self, new: NewWebhook) -> Result<WebhookInfo, WebhookError> {
+        if !new.url.starts_with("http://") && !new.url.starts_with("https://") {
+            return Err(WebhookError::Invalid(
+                "url must start with http:// or https://".to_string(),
+            ));
+        }
+        if new.url.len() > MAX_FIELD_LEN
+            || new.prefix.len() > MAX_FIELD_LEN
+            || new
+                .pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.len() > MAX_FIELD_LEN)
+        {
+            return Err(WebhookError::Invalid(format!(
+                "url, prefix and pattern can be at most {MAX_FIELD_LEN} bytes"
+            )));
+        }
+        if new.secret.as_ref().is_some_and(|secret| secret.is_empty()) {
+            return Err(WebhookError::Invalid("secret can not be empty".to_string()));
+        }
+        let webhook = Webhook {
+            id: Uuid::new_v4().to_string(),
+            url: new.url,
+            prefix: new.prefix,
+            pattern: new.pattern,
+            secret: new.secret,
+            created_at: unix_time() as i64,
+        };
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|error| Box::new(error) as StorageError)?;
+        insert_webhook(&mut conn, &webhook)?;
+        let info = WebhookInfo::from(&webhook);
+        self.hooks.write().unwrap().push(webhook);
+        Ok(info)
+    }
+
+    pub fn list(&self) -> Vec<WebhookInfo> {
+        self.hooks
+            .read()
+            .unwrap()
+            .iter()
+            .map(WebhookInfo::from)
+            .collect()
+    }
+
+    /// Returns false if there was no such webhook. Its queued deliveries are dropped.
+    pub fn remove(&self, id: &str) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get()?;
+        let deleted = delete_webhook(&mut conn, id)?;
+        self.hooks.write().unwrap().retain(|hook| hook.id != id);
+        // A task sending the queue drops it once it sees the webhook is gone
+        let mut queues = self.queues.lock().unwrap();
+        if queues.get(id).is_some_and(|queue| !queue.sending) {
+            queues.remove(id);
+        }
+        Ok(deleted)
+    }
+
+    pub fn dead_letters(
+        &self,
+        webhook_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<DeadLetter>, StorageError> {
+        let mut conn = self.pool.get()?;
+        get_dead_letters(&mut conn, webhook_id, limit as i64)
+    }
+
+    pub fn take_dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, StorageError> {
+        let mut conn = self.pool.get()?;
+        take_dead_letter(&mut conn, id)
+    }
+
+    /// Queues a dead letter again behind the webhook's other deliveries, it starts over from
+    /// the first try and becomes a dead letter again if they all fail. False if there was no such dead letter.
+    pub fn redeliver(&self, id: i64) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get()?;
+        let dead_letter = match requeue_dead_letter(&mut conn, id)? {
+            Some(dead_letter) => dead_letter,
+            None => return Ok(false),
+        };
+        self.queues
+            .lock()
+            .unwrap()
+            .entry(dead_letter.webhook_id.clone())
+            .or_default()
+            .queued += 1;
+        self.wake(&dead_letter.webhook_id);
+        Ok(true)
+    }
+
+    fn hook(&self, id: &str) -> Option<Webhook> {
+        let hooks = self.hooks.read().unwrap();
+        hooks.iter().find(|hook| hook.id == id).cloned()
+    }
+
+    /// Starts delivering every change committed from now on, and what was queued before a restart
+    pub fn start(self: &Arc<Self>, storage: Arc<dyn Storage>) {
+        // Where to catch up from if the stream gets ahead of us. Read before watching,
+        // so no change in the stream is taken for one from before it.
+        let mut last_seq = match storage.last_seq() {
+            Ok(last_seq) => Some(last_seq),
+            Err(error) => {
+                log::error!("webhooks can't catch up if they fall behind: {error}");
+                None
+            }
+        };
+        let mut changes = storage.watch();
+        let webhooks = self.clone();
+        rt::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(changes) => {
+                        // Already delivered while catching up
+                        let events: Vec<Event> = changes
+                            .0
+                            .into_iter()
+                            .filter(|event| last_seq.is_none_or(|last| event.seq > last))
+                            .collect();
+                        if let Some(event) = events.last() {
+                            last_seq = Some(event.seq);
+                            webhooks.dispatch(events).await;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        log::warn!("webhooks fell behind by {missed} change groups, catching up");
+                        if let Some(after) = last_seq {
+                            last_seq = webhooks.catch_up(&storage, after).await.or(last_seq);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        // Every webhook's queue is sent from this thread, sharing one client
+        let mut to_send = match self.to_send.lock().unwrap().take() {
+            Some(to_send) => to_send,
+            None => return,
+        };
+        let client = awc::Client::builder()
+            .timeout(Duration::from_secs(self.config.timeout_secs))
+            .finish();
+        let webhooks = self.clone();
+        rt::spawn(async move {
+            while let Some(webhook_id) = to_send.recv().await {
+                rt::spawn(webhooks.clone().send_queue(webhook_id, client.clone()));
+            }
+        });
+        let queued: Vec<String> = self.queues.lock().unwrap().keys().cloned().collect();
+        for webhook_id in queued {
+            self.wake(&webhook_id);
+        }
+    }
+
+    /// Delivers the changes after `after` from the change log, returns the last one delivered
+    async fn catch_up(self: &Arc<Self>, storage: &Arc<dyn Storage>, after: u64) -> Option<u64> {
+        let mut after = after;
+        loop {
+            let storage = storage.clone();
+            let page = web::block(move || storage.changes_since("", after, CATCH_UP_PAGE)).await;
+            let events = match page {
+                Ok(Ok(events)) => events,
+                Ok(Err(error)) => {
+                    match error.downcast_ref::<ChangesPruned>() {
+                        Some(_) => log::error!(
+                            "webhooks missed changes after {after}, they are no longer in the change log"
+                        ),
+                        None => log::error!("webhooks could not catch up: {error}"),
+                    }
+                    return None;
+                }
+                Err(error) => {
+                    log::error!("webhooks could not catch up: {error}");
+                    return None;
+                }
+            };
+            let done = events.len() < CATCH_UP_PAGE;
+            if let Some(event) = events.last() {
+                after = event.seq;
+                self.dispatch(events).await;
+            }
+            if done {
+                return Some(after);
+            }
+        }
+    }
+
+    /// Queues a delivery of each event to every webhook it matches, in one transaction so a batch is
+    /// queued whole. Webhooks with a full queue get theirs as dead letters.
+    async fn dispatch(self: &Arc<Self>, events: Vec<Event>) {
+        let mut queued: Vec<(String, String)> = Vec::new();
+        let mut shed: Vec<(String, String)> = Vec::new();
+        {
+            let hooks = self.hooks.read().unwrap();
+            let mut queues = self.queues.lock().unwrap();
+            for event in &events {
+                let key = event.change.key();
+                for hook in hooks.iter() {
+                    let matches = key.starts_with(&hook.prefix)
+                        && hook
+                            .pattern
+                            .as_deref()
+                            .is_none_or(|pattern| glob_matches(pattern, key));
+                    if !matches {
+                        continue;
+                    }
+                    let payload = payload(&hook.id, event);
+                    let queue = queues.entry(hook.id.clone()).or_default();
+                    match queue.queued < self.config.max_queued {
+                        true => {
+                            queue.queued += 1;
+                            queued.push((hook.id.clone(), payload));
+                        }
+                        false => shed.push((hook.id.clone(), payload)),
+                    }
+                }
+            }
+        }
+        if queued.is_empty() && shed.is_empty() {
+            return;
+        }
+        if !shed.is_empty() {
+            log::warn!(
+                "{} webhook deliveries went to the dead letters, their queues are full",
+                shed.len()
+            );
+        }
+
+        let webhooks = self.clone();
+        let to_queue = queued.clone();
+        let stored = web::block(move || {
+            let failed_at = unix_time() as i64;
+            let deliveries: Vec<NewDelivery> = to_queue
+                .iter()
+                .map(|(webhook_id, payload)| NewDelivery {
+                    webhook_id,
+                    payload,
+                })
+                .collect();
+            let dead_letters: Vec<NewDeadLetter> = shed
+                .iter()
+                .map(|(webhook_id, payload)| NewDeadLetter {
+                    webhook_id,
+                    payload,
+                    attempts: 0,
+                    error: "the webhook's queue is full",
+                    failed_at,
+                })
+                .collect();
+            let mut conn = webhooks.pool.get()?;
+            queue_deliveries(&mut conn, &deliveries, &dead_letters)
+        })
+        .await;
+        let error = match stored {
+            Ok(Ok(())) => None,
+            Ok(Err(error)) => Some(error.to_string()),
+            Err(error) => Some(error.to_string()),
+        };
+        if let Some(error) = error {
+            log::error!(
+                "could not queue {} webhook deliveries: {error}",
+                queued.len()
+            );
+            let mut queues = self.queues.lock().unwrap();
+            for (webhook_id, _) in &queued {
+                if let Some(queue) = queues.get_mut(webhook_id) {
+                    queue.queued -= 1;
+                }
+            }
+            return;
+        }
+        let mut woken: Vec<&str> = queued
+            .iter()
+            .map(|(webhook_id, _)| webhook_id.as_str())
+            .collect();
+        woken.sort_unstable();
+        woken.dedup();
+        for webhook_id in woken {
+            self.wake(webhook_id);
+        }
+    }
+
+    /// Makes sure a task is sending the webhook's queue
+    fn wake(&self, webhook_id: &str) {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(webhook_id.to_string()).or_default();
+        match queue.sending {
+            true => queue.woken = true,
+            false => {
+                queue.sending = true;
+                let _ = self.senders.send(webhook_id.to_string());
+            }
+        }
+    }
+
+    /// Sends a webhook's queued deliveries one at a time in order, until none are left
+    async fn send_queue(self: Arc<Self>, webhook_id: String, client: awc::Client) {
+        loop {
+            if let Some(queue) = self.queues.lock().unwrap().get_mut(&webhook_id) {
+                queue.woken = false;
+            }
+            let id = webhook_id.clone();
+            let next = self.db(move |conn| next_delivery(conn, &id)).await;
+            let delivery = match next {
+                Ok(Some(delivery)) => delivery,
+                Ok(None) => {
+                    let mut queues = self.queues.lock().unwrap();
+                    let queue = queues.entry(webhook_id.clone()).or_default();
+                    if queue.woken {
+                        continue;
+                    }
+                    queue.sending = false;
+                    return;
+                }
+                // Tried again when the next delivery is queued
+                Err(error) => {
+                    log::error!("could not read the queue of webhook {webhook_id}: {error}");
+                    if let Some(queue) = self.queues.lock().unwrap().get_mut(&webhook_id) {
+                        queue.sending = false;
+                    }
+                    return;
+                }
+            };
+            if !self.deliver(&client, delivery).await {
+                self.queues.lock().unwrap().remove(&webhook_id);
+                return;
+            }
+            if let Some(queue) = self.queues.lock().unwrap().get_mut(&webhook_id) {
+                queue.queued = queue.queued.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Sends a delivery, again with backoff until it gets a 2xx, then gives up and keeps it as a
+    /// dead letter. Either way it leaves the queue. Returns false if the webhook was removed.
+    async fn deliver(&self, client: &awc::Client, delivery: Delivery) -> bool {
+        let Delivery {
+            id,
+            webhook_id,
+            payload,
+            attempts,
+        } = delivery;
+        let mut attempts = attempts.max(0) as u32;
+        let error = loop {
+            // Tries made before a restart still wait their turn
+            if attempts > 0 {
+                rt::time::sleep(self.backoff(attempts)).await;
+            }
+            // Removed while we were waiting, its queue went with it
+            let hook = match self.hook(&webhook_id) {
+                Some(hook) => hook,
+                None => return false,
+            };
+            attempts += 1;
+            let sent = {
+                let _permit = self.in_flight.acquire().await;
+                send(client, &hook, &payload).await
+            };
+            match sent {
+                Ok(()) => {
+                    if let Err(error) = self.db(move |conn| finish_delivery(conn, id, None)).await {
+                        log::error!("could not take a sent delivery off its queue: {error}");
+                    }
+                    return true;
+                }
+                Err(error) if attempts >= self.config.max_attempts => break error,
+                Err(error) => {
+                    log::debug!("webhook {webhook_id} attempt {attempts} failed: {error}");
+                    let tried = attempts as i32;
+                    if let Err(error) = self
+                        .db(move |conn| set_delivery_attempts(conn, id, tried))
+                        .await
+                    {
+                        log::error!("could not count a webhook delivery attempt: {error}");
+                    }
+                }
+            }
+        };
+
+        log::warn!("webhook {webhook_id} gave up after {attempts} attempts: {error}");
+        let stored = self
+            .db(move |conn| {
+                let dead_letter = NewDeadLetter {
+                    webhook_id: &webhook_id,
+                    payload: &payload,
+                    attempts: attempts as i32,
+                    error: &error,
+                    failed_at: unix_time() as i64,
+                };
+                finish_delivery(conn, id, Some(&dead_letter))
+            })
+            .await;
+        if let Err(error) = stored {
+            log::error!("could not keep a dead letter: {error}");
+        }
+        true
+    }
+
+    /// Wait before the try after `attempts` failed ones
+    fn backoff(&self, attempts: u32) -> Duration {
+        let initial = Duration::from_millis(self.config.initial_backoff_ms);
+        let doubled = initial.saturating_mul(1 << (attempts - 1).min(31));
+        doubled.min(Duration::from_secs(self.config.max_backoff_secs))
+    }
+
+    async fn db<T, F>(&self, query: F) -> Result<T, StorageError>
+    where
+        F: FnOnce(&mut SqliteConnection) -> Result<T, DbError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        web::block(move || {
+            let mut conn = pool.get()?;
+            query(&mut conn)
+        })
+        .await?
+    }
+}
+
+/// What a webhook is sent for an event, retries send the same id
+fn payload(webhook_id: &str, event: &Event) -> String {
+    let mut payload = serde_json::json!({
+        "id": Uuid::new_v4(),
+        "webhook": webhook_id,
+    });
+    // Serializing a change can not fail
+    if let (Some(payload), serde_json::Value::Object(event)) = (
+        payload.as_object_mut(),
+        serde_json::to_value(event).unwrap(),
+    ) {
+        payload.extend(event);
+    }
+    payload.to_string()
+}
+
+/// One try, any answer but a 2xx is a failure
+async fn send(client: &awc::Client, hook: &Webhook, payload: &str) -> Result<(), String> {
+    let mut request = client.post(&hook.url).content_type("application/json");
+    if let Some(secret) = &hook.secret {
+        let timestamp = unix_time().to_string();
+        let signature = sign(secret, &timestamp, payload);
+        request = request
+            .insert_header(("X-Tinybase-Timestamp", timestamp))
+            .insert_header(("X-Tinybase-Signature", format!("sha256={signature}")));
+    }
+    let response = request
+        .send_body(payload.to_string())
+        .await
+        .map_err(|error| error.to_string())?;
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(format!("answered {}", response.status())),
+    }
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{payload}`
+fn sign(secret: &str, timestamp: &str, payload: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut context = hmac::Context::with_key(&key);
+    context.update(timestamp.as_bytes());
+    context.update(b".");
+    context.update(payload.as_bytes());
+    context
+        .sign()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// `*` matches any run of characters and `?` any one character, everything else itself
+fn glob_matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // Where the last `*` was and the key position it currently stands for
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, k));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            // Let the last `*` take one more character and try again
+            _ => match star {
+                Some((star_p, star_k)) => {
+                    p = star_p + 1;
+                    k = star_k + 1;
+                    star = Some((star_p, star_k + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
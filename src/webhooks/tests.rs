@@ -0,0 +1,216 @@
+//! Deliveries to an endpoint served on a local port
+
+use super::*;
+use crate::config::DatabaseConfig;
+use crate::data_access::pool;
+use crate::storage::MemoryStorage;
+use actix_web::{App, HttpRequest, HttpServer};
+use std::time::Instant;
+use tempfile::TempDir;
+
+/// A request the endpoint got
+struct Received {
+    signature: Option<String>,
+    timestamp: Option<String>,
+    body: serde_json::Value,
+    at: Instant,
+}
+
+#[derive(Default)]
+struct EndpointState {
+    /// Requests answered with a 500 before it starts answering 200
+    failures: Mutex<usize>,
+    received: Mutex<Vec<Received>>,
+}
+
+async fn answer(req: HttpRequest, body: String, state: web::Data<EndpointState>) -> HttpResponse {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    state.received.lock().unwrap().push(Received {
+        signature: header("X-Tinybase-Signature"),
+        timestamp: header("X-Tinybase-Timestamp"),
+        body: serde_json::from_str(&body).unwrap(),
+        at: Instant::now(),
+    });
+    let mut failures = state.failures.lock().unwrap();
+    match *failures {
+        0 => HttpResponse::Ok().finish(),
+        _ => {
+            *failures -= 1;
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Serves an endpoint that fails `failures` times, returns its URL
+fn endpoint(failures: usize) -> (String, Arc<EndpointState>) {
+    let state = web::Data::new(EndpointState {
+        failures: Mutex::new(failures),
+        ..EndpointState::default()
+    });
+    let app_state = state.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .default_service(web::to(answer))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}/hook", server.addrs()[0]);
+    rt::spawn(server.run());
+    (url, state.into_inner())
+}
+
+fn config() -> WebhooksConfig {
+    WebhooksConfig {
+        max_attempts: 3,
+        initial_backoff_ms: 50,
+        max_backoff_secs: 1,
+        timeout_secs: 5,
+        max_in_flight: 4,
+        max_queued: 100,
+    }
+}
+
+fn open(dir: &TempDir, config: WebhooksConfig) -> (Arc<Webhooks>, Arc<dyn Storage>) {
+    let pool = pool::open(&DatabaseConfig::for_test(dir)).unwrap();
+    let webhooks = Webhooks::open(pool, config).unwrap();
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    webhooks.start(storage.clone());
+    (webhooks, storage)
+}
+
+fn register(webhooks: &Webhooks, url: &str, secret: Option<&str>) -> String {
+    webhooks
+        .register(NewWebhook {
+            url: url.to_string(),
+            prefix: "users/".to_string(),
+            pattern: None,
+            secret: secret.map(str::to_string),
+        })
+        .unwrap()
+        .id
+}
+
+/// Waits up to five seconds for `done`
+async fn wait_for(mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+        rt::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+fn received_keys(state: &EndpointState) -> Vec<String> {
+    let received = state.received.lock().unwrap();
+    received
+        .iter()
+        .map(|request| request.body["key"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[actix_rt::test]
+async fn signs_deliveries() {
+    let dir = tempfile::tempdir().unwrap();
+    let (webhooks, storage) = open(&dir, config());
+    let (url, state) = endpoint(0);
+    let id = register(&webhooks, &url, Some("shh"));
+
+    storage.set("users/1".into(), "a".into()).unwrap();
+    storage.set("other".into(), "b".into()).unwrap();
+    wait_for(|| !state.received.lock().unwrap().is_empty()).await;
+
+    let received = state.received.lock().unwrap();
+    let request = &received[0];
+    assert_eq!(request.body["webhook"], id.as_str());
+    assert_eq!(request.body["key"], "users/1");
+    assert_eq!(request.body["value"], "a");
+    assert_eq!(request.body["event"], "set");
+
+    let timestamp = request.timestamp.as_deref().unwrap();
+    let signature = request.signature.as_deref().unwrap();
+    let body = request.body.to_string();
+    assert_eq!(
+        signature,
+        format!("sha256={}", sign("shh", timestamp, &body))
+    );
+    assert_ne!(
+        signature,
+        format!("sha256={}", sign("other", timestamp, &body))
+    );
+}
+
+#[actix_rt::test]
+async fn retries_with_backoff_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let (webhooks, storage) = open(&dir, config());
+    let (url, state) = endpoint(2);
+    register(&webhooks, &url, None);
+
+    storage.set("users/1".into(), "a".into()).unwrap();
+    storage.set("users/2".into(), "b".into()).unwrap();
+    wait_for(|| state.received.lock().unwrap().len() == 4).await;
+
+    // The second change waits until the first one got through
+    assert_eq!(
+        received_keys(&state),
+        vec!["users/1", "users/1", "users/1", "users/2"]
+    );
+    let received = state.received.lock().unwrap();
+    assert_eq!(received[0].body["id"], received[2].body["id"]);
+    assert!(received[1].at - received[0].at >= Duration::from_millis(50));
+    assert!(received[2].at - received[1].at >= Duration::from_millis(100));
+}
+
+#[actix_rt::test]
+async fn dead_letters_after_max_attempts_and_redelivers() {
+    let dir = tempfile::tempdir().unwrap();
+    let (webhooks, storage) = open(&dir, config());
+    let (url, state) = endpoint(3);
+    let id = register(&webhooks, &url, None);
+
+    storage.set("users/1".into(), "a".into()).unwrap();
+    let dead_letters = || webhooks.dead_letters(Some(&id), 10).unwrap();
+    wait_for(|| !dead_letters().is_empty()).await;
+    let dead_letter = dead_letters().remove(0);
+    assert_eq!(dead_letter.attempts, 3);
+    assert_eq!(dead_letter.error, "answered 500 Internal Server Error");
+    assert_eq!(state.received.lock().unwrap().len(), 3);
+
+    assert!(webhooks.redeliver(dead_letter.id).unwrap());
+    wait_for(|| state.received.lock().unwrap().len() == 4).await;
+    let received = state.received.lock().unwrap();
+    assert_eq!(received[3].body["id"], received[0].body["id"]);
+    assert!(dead_letters().is_empty());
+    assert!(!webhooks.redeliver(dead_letter.id).unwrap());
+}
+
+#[actix_rt::test]
+async fn full_queue_goes_to_the_dead_letters() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = WebhooksConfig {
+        max_attempts: 100,
+        max_queued: 1,
+        ..config()
+    };
+    let (webhooks, storage) = open(&dir, config);
+    let (url, state) = endpoint(usize::MAX);
+    let id = register(&webhooks, &url, None);
+
+    storage.set("users/1".into(), "a".into()).unwrap();
+    wait_for(|| !state.received.lock().unwrap().is_empty()).await;
+    storage.set("users/2".into(), "b".into()).unwrap();
+    let dead_letters = || webhooks.dead_letters(Some(&id), 10).unwrap();
+    wait_for(|| !dead_letters().is_empty()).await;
+
+    let dead_letter = dead_letters().remove(0);
+    assert_eq!(dead_letter.attempts, 0);
+    assert_eq!(dead_letter.error, "the webhook's queue is full");
+    assert!(dead_letter.payload.contains("users/2"));
+    assert!(received_keys(&state).iter().all(|key| key == "users/1"));
+}